@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+// asciinema v2 file format, see https://docs.asciinema.org/manual/asciicast/v2/
+pub const VERSION: u32 = 2;
+
+// first line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    // unix time of the beginning of the recording
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+}
+
+// seconds since the beginning of the recording, type of the event and its data:
+// "o" for output, "i" for input and "r" for a resize to "<width>x<height>"
+pub type Event = (f64, String, String);
+
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    file: File,
+    started: Instant,
+}
+
+impl Writer {
+    // every event is written at once, so that a crash can only truncate the last one
+    fn event(&mut self, kind: &str, data: &str) -> Result<(), String> {
+        let event: Event = (
+            self.started.elapsed().as_secs_f64(),
+            kind.to_owned(),
+            data.to_owned(),
+        );
+        let mut line = serde_json::to_vec(&event)
+            .map_err(|e| format!("could not convert cast event to json: {}", e))?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .map_err(|e| format!("could not write to {:?}: {}", self.path, e))
+    }
+}
+
+// recording of a terminal session, shared by the tasks talking to the client and
+// doing nothing if recordings are disabled or the file could not be written
+#[derive(Debug, Clone, Default)]
+pub struct Cast {
+    writer: Option<Arc<Mutex<Option<Writer>>>>,
+}
+
+impl Cast {
+    pub fn create(
+        path: &Path,
+        created_at: DateTime<Utc>,
+        title: String,
+        term: &str,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, String> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| format!("could not create {:?}: {}", path, e))?;
+
+        let mut env = HashMap::new();
+        if !term.is_empty() {
+            env.insert("TERM".to_owned(), term.to_owned());
+        }
+
+        let mut header = serde_json::to_vec(&Header {
+            version: VERSION,
+            width,
+            height,
+            timestamp: created_at.timestamp(),
+            title: Some(title),
+            env,
+        })
+        .map_err(|e| format!("could not convert cast header to json: {}", e))?;
+        header.push(b'\n');
+        file.write_all(&header)
+            .map_err(|e| format!("could not write to {:?}: {}", path, e))?;
+
+        Ok(Self {
+            writer: Some(Arc::new(Mutex::new(Some(Writer {
+                path: path.to_owned(),
+                file,
+                started: Instant::now(),
+            })))),
+        })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    fn event(&self, kind: &str, data: &str) {
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().unwrap();
+            if let Some(w) = writer.as_mut() {
+                if let Err(e) = w.event(kind, data) {
+                    // stop recording rather than logging an error for every event
+                    error!("{}", e);
+                    *writer = None;
+                }
+            }
+        }
+    }
+
+    // data sent by the client
+    pub fn input(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.event("i", &String::from_utf8_lossy(data));
+        }
+    }
+
+    // data displayed on the client terminal
+    pub fn output(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.event("o", &String::from_utf8_lossy(data));
+        }
+    }
+
+    pub fn resize(&self, width: u32, height: u32) {
+        self.event("r", &format!("{}x{}", width, height));
+    }
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
 use super::shell::handler::CommandHandler;
+use super::sinks;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Service {
@@ -12,11 +14,22 @@ pub struct Service {
 	#[serde(skip_serializing_if = "Vec::is_empty")]
 	#[serde(default)]
 	pub commands: Vec<CommandHandler>,
+	// file the service has been loaded from
+	#[serde(skip)]
+	pub path: PathBuf,
 	#[serde(flatten)]
 	pub config: HashMap<String, serde_yaml::Value>,
 }
 
 impl Service {
+	// resolve a file name relative to the folder of the service file
+	pub fn relative(&self, file: &str) -> PathBuf {
+		match self.path.parent() {
+			Some(folder) => folder.join(file),
+			None => PathBuf::from(file),
+		}
+	}
+
 	pub fn string(&self, name: &str, default: &str) -> String {
 		self.config
 			.get(name)
@@ -42,6 +55,13 @@ impl Service {
 			.unwrap_or(default)
 	}
 
+	pub fn float(&self, name: &str, default: f64) -> f64 {
+		self.config
+			.get(name)
+			.and_then(|v| v.as_f64())
+			.unwrap_or(default)
+	}
+
 	pub fn strings(&self, name: &str, default: Vec<String>) -> Vec<String> {
 		if let Some(value) = self.config.get(name) {
 			if let Some(sequence) = value.as_sequence() {
@@ -56,15 +76,24 @@ impl Service {
 	}
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, Default)]
 pub struct Records {
 	pub path: String,
+	// seconds between syncs of the record files to disk
+	pub sync: u64,
+	// id of the sensor the records come from, the hostname if empty
+	pub sensor: String,
+	// write asciinema recordings of the terminal sessions next to the records
+	pub casts: bool,
 }
 
 impl Records {
 	pub fn new() -> Self {
 		Self {
 			path: "".to_string(),
+			sync: 0,
+			sensor: "".to_string(),
+			casts: false,
 		}
 	}
 }
@@ -89,3 +118,20 @@ impl Config {
 		self.only.is_empty() || self.only.contains(ip)
 	}
 }
+
+// global configuration file
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct Global {
+	// where session events are sent to, besides the record files
+	#[serde(default)]
+	pub outputs: Vec<sinks::Config>,
+}
+
+impl Global {
+	pub fn from_file(path: &str) -> Result<Self, String> {
+		let data = std::fs::read_to_string(path)
+			.map_err(|e| format!("error reading config file {}: {}", path, e))?;
+		serde_yaml::from_str(&data)
+			.map_err(|e| format!("error parsing config file {}: {}", path, e))
+	}
+}
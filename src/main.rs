@@ -3,21 +3,40 @@ use std::fs;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use clap::{AppSettings, Clap};
 use futures::future;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 #[derive(Clap)]
 #[clap(author, about, version)]
 #[clap(setting = AppSettings::ColoredHelp)]
 pub(crate) struct Options {
+    /// Global configuration file.
+    #[clap(long)]
+    pub config: Option<String>,
     /// Path containing service YAML files.
     #[clap(short, long, default_value = "services.d")]
     pub services: String,
     /// Record files destination path.
     #[clap(short, long, default_value = "records")]
     pub records: String,
+    /// Id of this sensor in the records, the hostname by default.
+    #[clap(long, default_value = "")]
+    pub sensor_id: String,
+    /// SQLite database to store the sessions to, besides the record files.
+    #[clap(long)]
+    pub sqlite: Option<String>,
+    /// Seconds between syncs of the record files to disk.
+    #[clap(long, default_value = "5")]
+    pub records_sync: u64,
+    /// Write asciinema recordings of the SSH and telnet terminal sessions next to the records.
+    #[clap(long)]
+    pub casts: bool,
+    /// Seconds to wait for the active sessions to be saved when shutting down.
+    #[clap(long, default_value = "10")]
+    pub shutdown_timeout: u64,
     /// Packet capture file name.
     #[cfg(feature = "packet_capture")]
     #[clap(short, long, default_value = "capture.pcap")]
@@ -41,11 +60,18 @@ pub(crate) struct Options {
     /// Enable debug verbosity.
     #[clap(long)]
     pub debug: bool,
-    /// Read records from the specified folder and print the activity.
+    /// Read records from the specified folder, or the --sqlite database, and print the activity.
     #[clap(long)]
     pub replay: bool,
+    /// Play back an asciinema recording of a terminal session.
+    #[clap(long)]
+    pub play: Option<String>,
+    /// Speed of the playback, 2 is twice as fast as the original session.
+    #[clap(long, default_value = "1")]
+    pub speed: f64,
 }
 
+mod cast;
 mod config;
 mod protocols;
 mod record;
@@ -53,6 +79,9 @@ mod replay;
 mod services;
 mod shell;
 mod shodan;
+mod shutdown;
+mod sinks;
+mod sqlite;
 
 #[cfg(feature = "packet_capture")]
 mod capture;
@@ -102,11 +131,53 @@ async fn main() {
         return;
     }
 
+    if let Some(path) = &options.play {
+        if let Err(e) = replay::play(path, options.speed).await {
+            error!("{}", e);
+        }
+        return;
+    }
+
     if options.replay {
-        replay::start(&options.records).unwrap();
+        match &options.sqlite {
+            Some(path) => replay::from_sqlite(path).unwrap(),
+            None => replay::start(&options.records).unwrap(),
+        }
         return;
     }
 
+    let recovered = record::recover(&options.records);
+    if recovered > 0 {
+        info!(
+            "closed {} records left open by an unclean shutdown",
+            recovered
+        );
+    }
+
+    // session containers of a previous run that exited before removing them
+    shell::cleanup().await;
+
+    if let Some(path) = &options.config {
+        let global = match config::Global::from_file(path) {
+            Ok(global) => global,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        if let Err(e) = sinks::start(&global.outputs) {
+            error!("{}", e);
+            return;
+        }
+    }
+
+    if let Some(path) = &options.sqlite {
+        if let Err(e) = sqlite::start(path) {
+            error!("{}", e);
+            return;
+        }
+    }
+
     let config = services::load(&options);
 
     let mut services = HashMap::new();
@@ -130,6 +201,13 @@ async fn main() {
         }
     }
 
+    // stop accepting connections and close the active ones on SIGINT or SIGTERM
+    tokio::spawn(async {
+        let signal = shutdown::signal().await;
+        info!("received {}, shutting down ...", signal);
+        shutdown::trigger();
+    });
+
     if services.is_empty() {
         error!("no services found in {}", options.services);
     } else {
@@ -173,5 +251,26 @@ async fn main() {
         }
 
         future::join_all(futures).await;
+
+        // a second signal skips the wait
+        tokio::select! {
+            active = shutdown::drain(Duration::from_secs(options.shutdown_timeout)) => {
+                if active > 0 {
+                    warn!(
+                        "{} sessions still active after {}s, exiting",
+                        active, options.shutdown_timeout
+                    );
+                }
+            }
+            signal = shutdown::signal() => {
+                warn!("received {} while shutting down, exiting", signal);
+            }
+        }
+
+        // the sessions closed during the drain remove their containers in the background
+        shell::cleanup().await;
+
+        sinks::stop(Duration::from_secs(options.shutdown_timeout)).await;
+        sqlite::stop(Duration::from_secs(options.shutdown_timeout)).await;
     }
 }
@@ -1,5 +1,5 @@
 use std::fmt::Write as _;
-use std::net::SocketAddr;
+use std::mem;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
@@ -7,12 +7,18 @@ use log::{error, info};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    time::timeout,
+    time::{sleep, timeout},
 };
 
 use crate::{
     config::{Config as MainConfig, Service},
-    record,
+    protocols::{tls::Stream, Peer},
+    record::{self, Reason},
+    shell::{
+        handler::{self, Context, Output},
+        pacing,
+    },
+    shutdown,
 };
 
 use super::config::Config;
@@ -34,26 +40,47 @@ fn response(code: u32, message: &str, headers: &[String], data: Option<&Vec<u8>>
     resp
 }
 
-pub async fn handle<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
-    mut socket: S,
-    address: SocketAddr,
+pub async fn handle(
+    mut socket: Stream,
+    peer: Peer,
     service_name: String,
     service: Arc<Mutex<Service>>,
     config: Arc<Config>,
     main_config: Arc<MainConfig>,
 ) {
-    let mut log = record::for_address("http", &service_name, address);
+    let address = peer.address;
+    let mut log = record::for_address(
+        "http",
+        &service_name,
+        address,
+        peer.destination,
+        &main_config.records,
+    );
+    log.count(socket.traffic());
 
     let rw_timeout = Duration::from_secs(config.timeout);
 
     log.log("connected".to_owned());
+    if let Some(proxy) = peer.proxy {
+        log.log(format!("proxied by {}", proxy));
+    }
 
     let mut buf = [0; 8192];
 
-    let n = match timeout(rw_timeout, socket.read(&mut buf)).await {
-        Ok(n) => n.unwrap_or(0),
-        Err(e) => {
+    let n = match shutdown::until(timeout(rw_timeout, socket.read(&mut buf))).await {
+        Some(Ok(Ok(n))) => n,
+        Some(Ok(Err(e))) => {
             error!("failed to read request from {}; err = {:?}", address, e);
+            log.end(Reason::Error);
+            0
+        }
+        Some(Err(e)) => {
+            error!("failed to read request from {}; err = {:?}", address, e);
+            log.end(Reason::Timeout);
+            0
+        }
+        None => {
+            log.interrupt();
             0
         }
     };
@@ -63,35 +90,40 @@ pub async fn handle<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
 
         log.request(request.clone());
 
-        let mut output: Option<Vec<u8>> = None;
-        {
-            let mut svc = service.lock().unwrap();
-            for parser in &mut svc.commands {
-                if let Some(out) = parser.parse(&request) {
-                    output = Some(out);
-                    break;
-                }
-            }
-        }
+        let mut context = Context::for_address(&service_name, address);
+        let chunks = handler::dispatch(&service, &request, &mut context, None)
+            .await
+            .map(Output::into_chunks);
 
-        if let Some(output) = output {
+        if let Some(mut chunks) = chunks {
+            // the response is delayed as a whole, the body can then be streamed
+            sleep(mem::take(&mut chunks[0].delay)).await;
+            let output = chunks.iter().flat_map(|chunk| chunk.data.clone()).collect();
             let response = response(200, "OK", &config.headers, Some(&output));
             if let Err(e) = timeout(rw_timeout, socket.write_all(response.as_bytes())).await {
                 error!("failed to send response to {}; err = {:?}", address, e);
-            } else if let Err(e) = timeout(rw_timeout, socket.write_all(&output)).await {
+                log.end(Reason::Error);
+            } else if let Err(e) = pacing::send(&mut socket, &chunks, rw_timeout).await {
                 error!("failed to send response to {}; err = {:?}", address, e);
+                log.end(Reason::Error);
             }
         } else {
             let response = response(404, "Not Found", &config.headers, None);
             if let Err(e) = timeout(rw_timeout, socket.write_all(response.as_bytes())).await {
                 error!("failed to send 404 response to {}; err = {:?}", address, e);
+                log.end(Reason::Error);
             }
         }
+
+        // one request per connection
+        log.end(Reason::Exit);
+    } else {
+        log.end(Reason::ClientClose);
     }
 
     log.log("disconnected".to_string());
 
-    match log.save(&main_config.records.path) {
+    match log.save() {
         Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
         Err(s) => error!("{}", s),
     }
@@ -1,17 +1,176 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use crate::config::{Config, Service};
+use crate::{
+    cast::Cast,
+    config::{Config, Service},
+    record::Traffic,
+};
 
 pub mod http;
+pub mod proxy;
 pub mod ssh;
 pub mod tcp;
 pub mod telnet;
+pub mod tls;
 pub mod udp;
 
 pub type Error = String;
 
+// endpoints of a client connection
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    // address of the client
+    pub address: SocketAddr,
+    // address the client connected to
+    pub destination: SocketAddr,
+    // address of the proxy the connection came through, if any
+    pub proxy: Option<SocketAddr>,
+}
+
+impl Peer {
+    pub fn new(address: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            address,
+            destination,
+            proxy: None,
+        }
+    }
+}
+
+// socket counting the bytes going through it
+pub struct Counted<S> {
+    inner: S,
+    traffic: Traffic,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            traffic: Traffic::default(),
+        }
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.traffic.received(buf.filled().len() - before);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = poll {
+            this.traffic.sent(size);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// client terminal whose input and output are recorded
+pub struct Recorded<S> {
+    inner: S,
+    cast: Cast,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(inner: S, cast: Cast) -> Self {
+        Self { inner, cast }
+    }
+
+    // to talk to the client without recording, for instance protocol negotiations
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub fn cast(&self) -> Cast {
+        self.cast.clone()
+    }
+
+    // start recording, for instance once the client reached a shell
+    pub fn record(&mut self, cast: Cast) {
+        self.cast = cast;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.cast.input(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = poll {
+            this.cast.output(&buf[..size]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
 #[async_trait]
 pub trait Protocol {
     async fn run(&self);
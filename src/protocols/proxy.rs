@@ -0,0 +1,338 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use log::info;
+use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+
+use crate::protocols::{Error, Peer};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_SIZE: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// addresses of the original connection as reported by the proxy
+#[derive(Debug)]
+pub struct Header {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+fn parse_v1(line: &str) -> Result<Option<Header>, Error> {
+    // PROXY TCP4|TCP6|UNKNOWN <source ip> <destination ip> <source port> <destination port>
+    let parts: Vec<&str> = line.trim_end().split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, Error> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|e| format!("invalid proxy protocol address '{}': {}", ip, e))?;
+                let port: u16 = port
+                    .parse()
+                    .map_err(|e| format!("invalid proxy protocol port '{}': {}", port, e))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+
+            Ok(Some(Header {
+                source: parse(parts[2], parts[4])?,
+                destination: parse(parts[3], parts[5])?,
+            }))
+        }
+        _ => Err(format!("invalid proxy protocol v1 header: {:?}", line)),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, data: &[u8]) -> Result<Option<Header>, Error> {
+    if command >> 4 != 2 {
+        return Err(format!(
+            "unsupported proxy protocol version {}",
+            command >> 4
+        ));
+    }
+
+    // LOCAL command, the connection has been opened by the proxy itself (health checks)
+    if command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    let port = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+
+    match family >> 4 {
+        // AF_INET
+        1 if data.len() >= 12 => {
+            let source = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            let destination = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            Ok(Some(Header {
+                source: SocketAddr::new(IpAddr::V4(source), port(8)),
+                destination: SocketAddr::new(IpAddr::V4(destination), port(10)),
+            }))
+        }
+        // AF_INET6
+        2 if data.len() >= 36 => {
+            let mut source = [0u8; 16];
+            let mut destination = [0u8; 16];
+            source.copy_from_slice(&data[0..16]);
+            destination.copy_from_slice(&data[16..32]);
+            Ok(Some(Header {
+                source: SocketAddr::new(IpAddr::V6(Ipv6Addr::from(source)), port(32)),
+                destination: SocketAddr::new(IpAddr::V6(Ipv6Addr::from(destination)), port(34)),
+            }))
+        }
+        // AF_UNSPEC or AF_UNIX, nothing we can use
+        0 | 3 => Ok(None),
+        _ => Err(format!(
+            "invalid proxy protocol v2 address family {:#x} ({} bytes)",
+            family,
+            data.len()
+        )),
+    }
+}
+
+// read a PROXY protocol v1 or v2 header without consuming any of the data that follows it
+async fn read_header(socket: &mut TcpStream) -> Result<Option<Header>, Error> {
+    // both the v2 signature and the shortest v1 header are at least 12 bytes long
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    socket
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| format!("could not read proxy protocol header: {}", e))?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        socket
+            .read_exact(&mut fixed)
+            .await
+            .map_err(|e| format!("could not read proxy protocol v2 header: {}", e))?;
+
+        let mut data = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        socket
+            .read_exact(&mut data)
+            .await
+            .map_err(|e| format!("could not read proxy protocol v2 addresses: {}", e))?;
+
+        return parse_v2(fixed[0], fixed[1], &data);
+    }
+
+    if !header.starts_with(V1_PREFIX) {
+        return Err(format!(
+            "expected proxy protocol header, got {:?}",
+            String::from_utf8_lossy(&header)
+        ));
+    }
+
+    // v1 header is terminated by \r\n, read it one byte at a time
+    let mut byte = [0u8; 1];
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_SIZE {
+            return Err("proxy protocol v1 header too long".to_owned());
+        }
+        socket
+            .read_exact(&mut byte)
+            .await
+            .map_err(|e| format!("could not read proxy protocol v1 header: {}", e))?;
+        header.push(byte[0]);
+    }
+
+    parse_v1(&String::from_utf8_lossy(&header))
+}
+
+// address the client connected to, the one of the client itself if unknown
+pub fn local_address(socket: &TcpStream, address: SocketAddr) -> SocketAddr {
+    socket.local_addr().unwrap_or(address)
+}
+
+// read the real client address from the PROXY protocol header
+pub async fn resolve(
+    socket: &mut TcpStream,
+    address: SocketAddr,
+    rw_timeout: Duration,
+) -> Result<Peer, Error> {
+    let header = timeout(rw_timeout, read_header(socket))
+        .await
+        .map_err(|e| format!("timeout reading proxy protocol header: {}", e))??;
+
+    match header {
+        Some(header) => {
+            info!(
+                "{} proxied by {} (destination {})",
+                header.source, address, header.destination
+            );
+            Ok(Peer {
+                address: header.source,
+                destination: header.destination,
+                proxy: Some(address),
+            })
+        }
+        None => Ok(Peer::new(address, local_address(socket, address))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    // send the data to the server side of a connection and read the header from it,
+    // returning what's left to read
+    async fn read(data: Vec<u8>) -> (Result<Option<Header>, Error>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(&data).await.unwrap();
+        });
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        client.await.unwrap();
+
+        let header = read_header(&mut socket).await;
+        let mut rest = vec![];
+        socket.read_to_end(&mut rest).await.ok();
+        (header, rest)
+    }
+
+    #[test]
+    fn v1_headers() {
+        let header = parse_v1("PROXY TCP4 1.2.3.4 10.0.0.1 51234 22\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.source, "1.2.3.4:51234".parse().unwrap());
+        assert_eq!(header.destination, "10.0.0.1:22".parse().unwrap());
+
+        let header = parse_v1("PROXY TCP6 2001:db8::1 ::1 443 8443\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.source, "[2001:db8::1]:443".parse().unwrap());
+
+        assert!(parse_v1("PROXY UNKNOWN\r\n").unwrap().is_none());
+        assert!(parse_v1("PROXY UNKNOWN 1.2.3.4 10.0.0.1 51234 22\r\n")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn malformed_v1_headers() {
+        for line in &[
+            "PROXY\r\n",
+            "PROXY \r\n",
+            "PROXY TCP4\r\n",
+            "PROXY TCP4 1.2.3.4 10.0.0.1 51234\r\n",
+            "PROXY TCP4 1.2.3.4 10.0.0.1 51234 22 extra\r\n",
+            "PROXY TCP4  1.2.3.4 10.0.0.1 51234 22\r\n",
+            "PROXY UDP4 1.2.3.4 10.0.0.1 51234 22\r\n",
+            "PROXY TCP4 1.2.3.400 10.0.0.1 51234 22\r\n",
+            "PROXY TCP4 1.2.3.4 example.com 51234 22\r\n",
+            "PROXY TCP4 1.2.3.4 10.0.0.1 65536 22\r\n",
+            "PROXY TCP4 1.2.3.4 10.0.0.1 -1 22\r\n",
+        ] {
+            assert!(parse_v1(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn v2_headers() {
+        let addresses = [1, 2, 3, 4, 10, 0, 0, 1, 0xc8, 0x22, 0, 22];
+        let header = parse_v2(0x21, 0x11, &addresses).unwrap().unwrap();
+        assert_eq!(header.source, "1.2.3.4:51234".parse().unwrap());
+        assert_eq!(header.destination, "10.0.0.1:22".parse().unwrap());
+
+        let mut addresses = [0u8; 36];
+        addresses[15] = 1;
+        addresses[31] = 2;
+        addresses[33] = 80;
+        addresses[35] = 81;
+        let header = parse_v2(0x21, 0x21, &addresses).unwrap().unwrap();
+        assert_eq!(header.source, "[::1]:80".parse().unwrap());
+        assert_eq!(header.destination, "[::2]:81".parse().unwrap());
+
+        // TLVs after the addresses are ignored
+        let mut addresses = [1, 2, 3, 4, 10, 0, 0, 1, 0xc8, 0x22, 0, 22].to_vec();
+        addresses.extend_from_slice(&[0x04, 0, 1, 0]);
+        assert!(parse_v2(0x21, 0x11, &addresses).unwrap().is_some());
+
+        // LOCAL connections, unspecified and unix addresses
+        assert!(parse_v2(0x20, 0x11, &[]).unwrap().is_none());
+        assert!(parse_v2(0x21, 0x00, &[]).unwrap().is_none());
+        assert!(parse_v2(0x21, 0x31, &[0; 216]).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_v2_headers() {
+        // bad versions
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x31, 0x11, &[0; 12]).is_err());
+        // addresses shorter than their family requires
+        assert!(parse_v2(0x21, 0x11, &[]).is_err());
+        assert!(parse_v2(0x21, 0x11, &[0; 11]).is_err());
+        assert!(parse_v2(0x21, 0x21, &[0; 12]).is_err());
+        assert!(parse_v2(0x21, 0x21, &[0; 35]).is_err());
+        // unknown family
+        assert!(parse_v2(0x21, 0x41, &[0; 36]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_header() {
+        let mut data = b"PROXY TCP4 1.2.3.4 10.0.0.1 51234 22\r\n".to_vec();
+        data.extend_from_slice(b"SSH-2.0-OpenSSH_8.9\r\n");
+        let (header, rest) = read(data).await;
+        assert_eq!(header.unwrap().unwrap().source.port(), 51234);
+        assert_eq!(rest, b"SSH-2.0-OpenSSH_8.9\r\n");
+
+        let mut data = v2(0x21, 0x11, &[1, 2, 3, 4, 10, 0, 0, 1, 0xc8, 0x22, 0, 22]);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let (header, rest) = read(data).await;
+        assert_eq!(header.unwrap().unwrap().destination.port(), 22);
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn truncated_headers() {
+        // shorter than the v2 signature
+        assert!(read(b"PROXY TCP4".to_vec()).await.0.is_err());
+        assert!(read(V2_SIGNATURE[..8].to_vec()).await.0.is_err());
+
+        // v2 header without its fixed part
+        assert!(read(V2_SIGNATURE.to_vec()).await.0.is_err());
+
+        // v2 length bigger than the addresses sent
+        let mut data = v2(0x21, 0x11, &[1, 2, 3, 4, 10, 0, 0, 1, 0xc8, 0x22, 0, 22]);
+        data[15] = 0xff;
+        assert!(read(data).await.0.is_err());
+
+        // v2 length smaller than the addresses of the family
+        let mut data = v2(0x21, 0x11, &[1, 2, 3, 4, 10, 0, 0, 1, 0xc8, 0x22, 0, 22]);
+        data[15] = 4;
+        let (header, rest) = read(data).await;
+        assert!(header.is_err());
+        assert_eq!(rest.len(), 8);
+
+        // v1 header without its line ending
+        assert!(read(b"PROXY TCP4 1.2.3.4 10.0.0.1 51234 22".to_vec())
+            .await
+            .0
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        assert!(read(b"SSH-2.0-OpenSSH_8.9\r\n".to_vec()).await.0.is_err());
+
+        // v1 header longer than the protocol allows
+        let mut data = b"PROXY TCP4 ".to_vec();
+        data.extend_from_slice(&[b'1'; 200]);
+        data.extend_from_slice(b"\r\n");
+        assert_eq!(
+            read(data).await.0.err().unwrap(),
+            "proxy protocol v1 header too long"
+        );
+    }
+}
@@ -19,6 +19,7 @@ pub fn from_service(svc: &Service) -> Config {
     let banner = svc.string("banner", DEFAULT_BANNER);
     let prompt = svc.string("prompt", DEFAULT_PROMPT);
     let timeout = svc.unsigned("timeout", DEFAULT_TIMEOUT);
+    let proxy_protocol = svc.bool("proxy_protocol", false);
 
     Config {
         address,
@@ -27,6 +28,7 @@ pub fn from_service(svc: &Service) -> Config {
         banner,
         prompt,
         timeout,
+        proxy_protocol,
     }
 }
 
@@ -38,6 +40,7 @@ pub struct Config {
     pub banner: String,
     pub prompt: String,
     pub timeout: u64,
+    pub proxy_protocol: bool,
 }
 
 impl Config {
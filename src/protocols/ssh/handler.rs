@@ -1,90 +1,210 @@
-use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 
-use futures::future;
+use futures::{future, FutureExt};
 use hex_slice::AsHex;
 use log::{error, info, warn};
 use russh::{
-    server::{self, Auth, Session},
-    ChannelId, CryptoVec,
+    server::{self, Auth, Handle, Session},
+    ChannelId, CryptoVec, Pty,
 };
+use tokio::{task::JoinHandle, time::sleep};
 
 use crate::{
-    config::{Config as MainConfig, Service},
-    protocols::ssh::config::Config,
-    record,
-    shell::handler::EXIT_HANDLER_TOKEN,
+    cast::Cast,
+    config::Config as MainConfig,
+    protocols::{ssh::config::Config, Peer},
+    record::{self, Reason, Traffic},
+    shell::{self, session::Response, tty::Tty},
+    shutdown,
 };
 
+// max size of a command line, the same as what telnet reads at once
+const MAX_COMMAND: usize = 1024;
+
 pub struct ClientHandler {
     log: record::Record,
     address: std::net::SocketAddr,
-    service: Arc<Mutex<Service>>,
+    shell: shell::session::Session,
     config: Arc<MainConfig>,
     banner: CryptoVec,
-    prompt: CryptoVec,
+    // prompt of the service, unless the shell personality has its own
+    prompt: String,
     line_break: CryptoVec,
     command: Vec<u8>,
+    // terminal in the container, if attached
+    tty: Option<Tty>,
+    // terminal type and size requested by the client
+    term: String,
+    size: (u32, u32),
+    // recording of what is displayed on the client terminal
+    cast: Cast,
+    // command whose output is being streamed
+    busy: Option<JoinHandle<()>>,
 }
 
 impl ClientHandler {
     pub fn new(
         service_name: String,
-        service: Arc<Mutex<Service>>,
-        address: std::net::SocketAddr,
+        shell: shell::session::Session,
+        peer: Peer,
+        traffic: Traffic,
         config: Arc<Config>,
         main_config: Arc<MainConfig>,
     ) -> Self {
-        let mut log = record::for_address("ssh", &service_name, address);
+        let address = peer.address;
+        let mut log = record::for_address(
+            "ssh",
+            &service_name,
+            address,
+            peer.destination,
+            &main_config.records,
+        );
+        log.count(traffic);
 
         log.log("connected".to_owned());
+        if let Some(proxy) = peer.proxy {
+            log.log(format!("proxied by {}", proxy));
+        }
 
         Self {
             banner: CryptoVec::from_slice(config.banner.as_bytes()),
-            prompt: CryptoVec::from_slice(config.prompt.as_bytes()),
+            prompt: config.prompt.to_owned(),
             line_break: CryptoVec::from_slice(b"\r\n"),
             log,
             address,
             config: main_config,
-            service,
+            shell,
             command: vec![],
+            tty: None,
+            term: String::new(),
+            size: (80, 24),
+            cast: Cast::default(),
+            busy: None,
+        }
+    }
+
+    fn prompt(&mut self) -> CryptoVec {
+        CryptoVec::from_slice(self.shell.prompt(&self.prompt).as_bytes())
+    }
+
+    // send data to the client terminal
+    fn send(&self, channel: ChannelId, session: &mut Session, data: CryptoVec) {
+        self.cast.output(&data);
+        session.data(channel, data);
+    }
+
+    // start recording the terminal, once per session
+    fn record(&mut self) {
+        if !self.cast.is_recording() {
+            self.cast = self.log.cast(&self.term, self.size.0, self.size.1);
         }
     }
 
-    fn on_command(&mut self, command: String, channel: ChannelId, session: &mut Session) -> bool {
+    fn greet(&mut self, channel: ChannelId, session: &mut Session) {
+        self.send(channel, session, self.banner.clone());
+        self.send(channel, session, self.line_break.clone());
+        self.send(channel, session, self.line_break.clone());
+        let prompt = self.prompt();
+        self.send(channel, session, prompt);
+    }
+
+    async fn on_command(
+        &mut self,
+        command: String,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> bool {
         self.log.command(command.clone());
 
-        let mut output: Option<Vec<u8>> = None;
+        let response = self.shell.execute(&command, &mut self.log).await;
+        if response.exit {
+            self.log.end(Reason::Exit);
+        }
 
-        for parser in &mut self.service.lock().unwrap().commands {
-            if let Some(out) = parser.parse(&command) {
-                output = Some(out);
-                break;
-            }
+        if response.is_paced() {
+            let prompt = self.prompt();
+            self.busy = Some(self.stream(response, prompt, channel, session.handle()));
+            return false;
         }
 
-        if let Some(output) = output {
-            if output == EXIT_HANDLER_TOKEN.as_bytes() {
-                return true;
+        self.send(channel, session, self.line_break.clone());
+        if !response.output.is_empty() {
+            self.send(channel, session, CryptoVec::from_slice(&response.output));
+            self.send(channel, session, self.line_break.clone());
+        }
+
+        if response.exit {
+            return true;
+        }
+
+        let prompt = self.prompt();
+        self.send(channel, session, prompt);
+
+        false
+    }
+
+    // send the output of a command as it's produced, followed by the prompt
+    fn stream(
+        &self,
+        response: Response,
+        prompt: CryptoVec,
+        channel: ChannelId,
+        handle: Handle,
+    ) -> JoinHandle<()> {
+        let line_break = self.line_break.clone();
+        let cast = self.cast.clone();
+        tokio::spawn(async move {
+            cast.output(&line_break);
+            let _ = handle.data(channel, line_break.clone()).await;
+            for chunk in response.chunks {
+                sleep(chunk.delay).await;
+                cast.output(&chunk.data);
+                if !chunk.data.is_empty()
+                    && handle
+                        .data(channel, CryptoVec::from_slice(&chunk.data))
+                        .await
+                        .is_err()
+                {
+                    return;
+                }
+            }
+
+            // until interrupted, the task is aborted on ^C
+            if response.running {
+                sleep(shell::pacing::MAX_RUNNING).await;
+            }
+
+            if !response.output.is_empty() {
+                cast.output(&line_break);
+                let _ = handle.data(channel, line_break).await;
+            }
+            if response.exit {
+                let _ = handle.close(channel).await;
             } else {
-                session.data(channel, self.line_break.clone());
-                session.data(channel, CryptoVec::from_slice(&output));
+                cast.output(&prompt);
+                let _ = handle.data(channel, prompt).await;
             }
-        } else {
-            session.data(
-                channel,
-                CryptoVec::from_slice(
-                    format!(
-                        "\r\nsh: command not found: {:?}",
-                        command.split(' ').collect::<Vec<&str>>()[0]
-                    )
-                    .as_bytes(),
-                ),
-            );
-        }
+        })
+    }
 
-        session.data(channel, self.line_break.clone());
-        session.data(channel, self.prompt.clone());
+    // add the data to the command line, what doesn't fit is dropped
+    fn typed<'a>(&mut self, data: &'a [u8]) -> &'a [u8] {
+        let room = MAX_COMMAND.saturating_sub(self.command.len());
+        let data = &data[..data.len().min(room)];
+        self.command.extend(data);
+        data
+    }
 
+    // true if a command is still running
+    fn is_busy(&mut self) -> bool {
+        if let Some(task) = &mut self.busy {
+            if task.now_or_never().is_none() {
+                return true;
+            }
+            self.busy = None;
+        }
         false
     }
 
@@ -101,9 +221,21 @@ impl ClientHandler {
 
 impl Drop for ClientHandler {
     fn drop(&mut self) {
-        self.log.log("disconnected".to_owned());
+        if let Some(task) = self.busy.take() {
+            task.abort();
+        }
+        if let Some(tty) = &mut self.tty {
+            tty.flush(&mut self.log);
+        }
+        if shutdown::triggered() {
+            self.log.interrupt();
+        } else {
+            self.log.log("disconnected".to_owned());
+            // unless the session ended in any other way
+            self.log.end(Reason::ClientClose);
+        }
 
-        match self.log.save(&self.config.records.path) {
+        match self.log.save() {
             Ok(path) => info!("saved {} entries to {:?}", self.log.size(), path),
             Err(s) => error!("{}", s),
         }
@@ -113,7 +245,8 @@ impl Drop for ClientHandler {
 impl server::Handler for ClientHandler {
     type Error = anyhow::Error;
     type FutureAuth = future::Ready<Result<(Self, server::Auth), anyhow::Error>>;
-    type FutureUnit = future::Ready<Result<(Self, Session), anyhow::Error>>;
+    // commands are executed asynchronously
+    type FutureUnit = Pin<Box<dyn Future<Output = Result<(Self, Session), anyhow::Error>> + Send>>;
     type FutureBool = future::Ready<Result<(Self, Session, bool), anyhow::Error>>;
 
     fn finished_auth(self, auth: Auth) -> Self::FutureAuth {
@@ -132,12 +265,13 @@ impl server::Handler for ClientHandler {
     }
 
     fn finished(self, s: Session) -> Self::FutureUnit {
-        future::ready(Ok((self, s)))
+        Box::pin(future::ready(Ok((self, s))))
     }
 
     fn auth_none(mut self, user: &str) -> Self::FutureAuth {
         let policy = self.auth_policy();
 
+        self.shell.login(user);
         self.log.auth(user.to_string(), None, None);
         self.finished_auth(policy)
     }
@@ -145,6 +279,7 @@ impl server::Handler for ClientHandler {
     fn auth_password(mut self, user: &str, password: &str) -> Self::FutureAuth {
         let policy = self.auth_policy();
 
+        self.shell.login(user);
         self.log
             .auth(user.to_string(), Some(password.to_string()), None);
         self.finished_auth(policy)
@@ -157,6 +292,7 @@ impl server::Handler for ClientHandler {
     ) -> Self::FutureAuth {
         let policy = self.auth_policy();
 
+        self.shell.login(user);
         self.log
             .auth(user.to_string(), None, Some(public_key.fingerprint()));
         self.finished_auth(policy)
@@ -170,6 +306,7 @@ impl server::Handler for ClientHandler {
     ) -> Self::FutureAuth {
         let policy = self.auth_policy();
 
+        self.shell.login(user);
         self.log.auth(user.to_string(), None, None);
         self.finished_auth(policy)
     }
@@ -229,15 +366,95 @@ impl server::Handler for ClientHandler {
         let mut session = session;
 
         self.log.log("shell request".to_string());
+        self.record();
+
+        if let Some(config) = self.shell.tty().cloned() {
+            return Box::pin(async move {
+                let (width, height) = self.size;
+                match Tty::open(&config, width, height).await {
+                    Ok((tty, mut reader)) => {
+                        self.log.log("terminal attached".to_string());
+                        self.tty = Some(tty);
+
+                        // stream the container output to the client until the terminal is closed
+                        let handle = session.handle();
+                        let cast = self.cast.clone();
+                        tokio::spawn(async move {
+                            while let Some(data) = reader.read().await {
+                                cast.output(&data);
+                                if handle
+                                    .data(channel, CryptoVec::from_slice(&data))
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                            let _ = handle.close(channel).await;
+                        });
+                    }
+                    Err(e) => {
+                        // fall back to the emulated shell
+                        error!("could not attach terminal for {}: {}", self.address, e);
+                        self.greet(channel, &mut session);
+                    }
+                }
+
+                Ok((self, session))
+            });
+        }
 
-        session.data(channel, self.banner.clone());
-        session.data(channel, self.line_break.clone());
-        session.data(channel, self.line_break.clone());
-        session.data(channel, self.prompt.clone());
+        self.greet(channel, &mut session);
+
+        self.finished(session)
+    }
+
+    fn pty_request(
+        mut self,
+        _channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        session: Session,
+    ) -> Self::FutureUnit {
+        self.log.log(format!(
+            "pty request: {} {}x{}",
+            term, col_width, row_height
+        ));
+        self.term = term.to_owned();
+        if col_width > 0 && row_height > 0 {
+            self.size = (col_width, row_height);
+        }
 
         self.finished(session)
     }
 
+    fn window_change_request(
+        mut self,
+        _channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        session: Session,
+    ) -> Self::FutureUnit {
+        if col_width > 0 && row_height > 0 {
+            self.size = (col_width, row_height);
+            self.cast.resize(col_width, row_height);
+        }
+
+        Box::pin(async move {
+            if let Some(tty) = &self.tty {
+                tty.resize(self.size.0, self.size.1).await;
+            }
+
+            Ok((self, session))
+        })
+    }
+
     fn exec_request(
         mut self,
         channel: ChannelId,
@@ -249,11 +466,13 @@ impl server::Handler for ClientHandler {
             .trim()
             .to_owned();
 
-        if self.on_command(command, channel, &mut session) {
-            session.close(channel);
-        }
+        Box::pin(async move {
+            if self.on_command(command, channel, &mut session).await {
+                session.close(channel);
+            }
 
-        self.finished(session)
+            Ok((self, session))
+        })
     }
 
     fn subsystem_request(
@@ -280,6 +499,39 @@ impl server::Handler for ClientHandler {
     }
 
     fn data(mut self, channel: ChannelId, data: &[u8], mut session: Session) -> Self::FutureUnit {
+        self.cast.input(data);
+
+        // everything goes to the container terminal, which echoes it back
+        if self.tty.is_some() {
+            let data = data.to_vec();
+            return Box::pin(async move {
+                if let Some(tty) = &mut self.tty {
+                    if let Err(e) = tty.write(&data, &mut self.log).await {
+                        warn!("{}: {}", self.address, e);
+                        self.log.end(Reason::Error);
+                        session.close(channel);
+                    }
+                }
+
+                Ok((self, session))
+            });
+        }
+
+        if self.is_busy() {
+            // only ^C is handled while a command is running
+            if data.contains(&0x03) {
+                if let Some(task) = self.busy.take() {
+                    task.abort();
+                }
+                self.log.log("interrupted".to_owned());
+                self.send(channel, &mut session, CryptoVec::from_slice(b"^C"));
+                self.send(channel, &mut session, self.line_break.clone());
+                let prompt = self.prompt();
+                self.send(channel, &mut session, prompt);
+            }
+            return self.finished(session);
+        }
+
         match data {
             // TODO: handle backspace
             b"\r" => {
@@ -289,15 +541,41 @@ impl server::Handler for ClientHandler {
                     .to_owned();
                 self.command.clear();
 
-                if self.on_command(command, channel, &mut session) {
-                    session.close(channel);
-                    return self.finished(session);
+                return Box::pin(async move {
+                    if self.on_command(command, channel, &mut session).await {
+                        session.close(channel);
+                    }
+
+                    Ok((self, session))
+                });
+            }
+            // context help of the network device CLI, the command line is kept
+            b"?" => {
+                let command = String::from_utf8_lossy(&self.command).to_string();
+                match self.shell.help(&command) {
+                    Some(help) => {
+                        self.log.command(format!("{}?", command));
+                        self.send(channel, &mut session, CryptoVec::from_slice(b"?"));
+                        self.send(channel, &mut session, self.line_break.clone());
+                        self.send(channel, &mut session, CryptoVec::from_slice(&help));
+                        self.send(channel, &mut session, self.line_break.clone());
+                        let prompt = self.prompt();
+                        self.send(channel, &mut session, prompt);
+                        self.send(channel, &mut session, CryptoVec::from_slice(&self.command));
+                    }
+                    None => {
+                        let data = self.typed(data);
+                        self.send(channel, &mut session, CryptoVec::from_slice(data));
+                    }
                 }
             }
             _ => {
-                self.command.extend(data);
-                // echo back the data so that it will be displayed on the client terminal
-                session.data(channel, CryptoVec::from_slice(data));
+                let data = self.typed(data);
+                // echo back the data so that it will be displayed on the client terminal,
+                // unless it's a password
+                if !self.shell.is_secret() {
+                    self.send(channel, &mut session, CryptoVec::from_slice(data));
+                }
             }
         }
 
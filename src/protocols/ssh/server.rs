@@ -1,14 +1,16 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{debug, warn};
 use russh::server::{self, Config as SSHConfig};
+use tokio::net::TcpListener;
 
 use crate::{
     config::{Config as MainConfig, Service},
-    protocols::{Error, Protocol},
+    protocols::{proxy, Counted, Error, Peer, Protocol},
+    shell::{handler::Context, session::Session, Shell},
+    shutdown,
 };
 
 use super::{
@@ -20,6 +22,7 @@ use super::{
 pub struct Server {
     service_name: String,
     service: Arc<Mutex<Service>>,
+    shell: Arc<Shell>,
     config: Arc<Config>,
     ssh_config: Arc<SSHConfig>,
     main_config: Arc<MainConfig>,
@@ -36,10 +39,12 @@ impl Server {
         let config = Arc::new(config);
         let ssh_config = Arc::new(ssh_config);
         let main_config = Arc::new(main_config);
+        let shell = Arc::new(Shell::from_service(service.lock().as_ref().unwrap())?);
 
         Ok(Server {
             service_name,
             service,
+            shell,
             config,
             ssh_config,
             main_config,
@@ -52,32 +57,41 @@ impl Protocol for Server {
     async fn run(&self) {
         debug!("starting ssh on {} ...", &self.config.address);
 
-        let address = std::net::SocketAddr::from_str(&self.config.address).unwrap();
+        let listener = TcpListener::bind(&self.config.address).await.unwrap();
+        while let Some(Ok((mut socket, addr))) = shutdown::until(listener.accept()).await {
+            let server = self.clone();
+            tokio::spawn(shutdown::track(async move {
+                let peer = if server.config.proxy_protocol {
+                    let rw_timeout = Duration::from_secs(server.config.timeout);
+                    match proxy::resolve(&mut socket, addr, rw_timeout).await {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            warn!("{}: {}", addr, e);
+                            return;
+                        }
+                    }
+                } else {
+                    Peer::new(addr, proxy::local_address(&socket, addr))
+                };
 
-        russh::server::run(self.ssh_config.clone(), &address, self.clone())
-            .await
-            .unwrap();
-    }
-}
-
-impl server::Server for Server {
-    type Handler = handler::ClientHandler;
-
-    fn new_client(&mut self, address: Option<SocketAddr>) -> handler::ClientHandler {
-        let address = match address {
-            Some(addr) => addr,
-            None => {
-                warn!("ssh address is none");
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
-            }
-        };
+                let socket = Counted::new(socket);
+                let context = Context::for_address(&server.service_name, peer.address);
+                let session = Session::new(server.service, server.shell, context);
+                let handler = handler::ClientHandler::new(
+                    server.service_name,
+                    session,
+                    peer,
+                    socket.traffic(),
+                    server.config,
+                    server.main_config,
+                );
 
-        handler::ClientHandler::new(
-            self.service_name.clone(),
-            self.service.clone(),
-            address,
-            self.config.clone(),
-            self.main_config.clone(),
-        )
+                // on shutdown the session is closed, the handler records why once dropped
+                let connection = server::run_stream(server.ssh_config, socket, handler);
+                if let Some(Err(e)) = shutdown::until(connection).await {
+                    debug!("ssh session with {} terminated: {:?}", peer.address, e);
+                }
+            }));
+        }
     }
 }
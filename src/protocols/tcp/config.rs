@@ -1,18 +1,48 @@
-use crate::config::Service;
+use std::collections::HashMap;
+
+use crate::{
+	config::Service,
+	protocols::{tls, Error},
+};
+
+use super::machine::{self, Machine};
 
 const DEFAULT_BANNER: &str = "hi";
 const DEFAULT_TIMEOUT: u64 = 10;
 
-pub fn from_service(svc: &Service) -> Config {
+pub fn from_service(svc: &Service) -> Result<Config, Error> {
 	let address = svc.address.to_owned();
-	let banner = svc.string("banner", DEFAULT_BANNER);
 	let timeout = svc.unsigned("timeout", DEFAULT_TIMEOUT);
+	let proxy_protocol = svc.bool("proxy_protocol", false);
+
+	let machine = match svc.config.get("states") {
+		Some(states) => {
+			let states: HashMap<String, machine::State> = serde_yaml::from_value(states.clone())
+				.map_err(|e| format!("could not parse states: {}", e))?;
+			let initial = svc.string("initial_state", machine::DEFAULT_INITIAL_STATE);
+
+			Some(Machine::new(initial, states)?)
+		}
+		None => None,
+	};
+
+	// when a state machine is defined, the initial state takes care of the banner
+	let default_banner = if machine.is_some() {
+		""
+	} else {
+		DEFAULT_BANNER
+	};
+	let banner = svc.string("banner", default_banner);
+	let tls = tls::from_service(svc)?;
 
-	Config {
+	Ok(Config {
 		address,
 		banner,
 		timeout,
-	}
+		machine,
+		tls,
+		proxy_protocol,
+	})
 }
 
 #[derive(Clone, Debug)]
@@ -20,4 +50,7 @@ pub struct Config {
 	pub address: String,
 	pub banner: String,
 	pub timeout: u64,
+	pub proxy_protocol: bool,
+	pub machine: Option<Machine>,
+	pub tls: tls::Config,
 }
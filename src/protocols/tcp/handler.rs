@@ -2,33 +2,69 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    time::timeout,
+    time::{sleep, timeout},
 };
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
     config::{Config as MainConfig, Service},
-    record,
+    protocols::{tls::Stream, Peer},
+    record::{self, Reason},
+    shell::{
+        handler::{self, Context, Output},
+        pacing,
+    },
+    shutdown,
 };
 
 use super::config::Config;
 
-pub async fn handle(
-    mut socket: tokio::net::TcpStream,
+async fn enter_state(
+    socket: &mut Stream,
     address: SocketAddr,
+    banner: &str,
+    rw_timeout: Duration,
+) -> bool {
+    if !banner.is_empty() {
+        if let Err(e) = timeout(rw_timeout, socket.write_all(banner.as_bytes())).await {
+            error!("failed to send state banner to {}; err = {:?}", address, e);
+            return false;
+        }
+    }
+    true
+}
+
+pub async fn handle(
+    mut socket: Stream,
+    peer: Peer,
     service_name: String,
     service: Arc<Mutex<Service>>,
     config: Arc<Config>,
     main_config: Arc<MainConfig>,
+    tls_acceptor: Option<TlsAcceptor>,
 ) {
-    let mut log = record::for_address("tcp", &service_name, address);
+    let address = peer.address;
+    let mut log = record::for_address(
+        "tcp",
+        &service_name,
+        address,
+        peer.destination,
+        &main_config.records,
+    );
+    log.count(socket.traffic());
+    let mut context = Context::for_address(&service_name, address);
 
     log.log("connected".to_owned());
+    if let Some(proxy) = peer.proxy {
+        log.log(format!("proxied by {}", proxy));
+    }
 
-    let mut ok = false;
+    // services without a banner only read from the client with a state machine
+    let mut ok = config.banner.is_empty() && config.machine.is_some();
     let rw_timeout = Duration::from_secs(config.timeout);
 
     if !config.banner.is_empty() {
@@ -41,45 +77,119 @@ pub async fn handle(
         }
     }
 
+    let mut state = config.machine.as_ref().map(|m| m.initial().to_owned());
+    if ok {
+        if let (Some(machine), Some(state)) = (&config.machine, &state) {
+            ok = enter_state(&mut socket, address, machine.banner(state), rw_timeout).await;
+        }
+    }
+
     let mut buf = [0; 1024];
 
     if ok {
         loop {
-            let n = match timeout(rw_timeout, socket.read(&mut buf)).await {
-                Ok(n) => n,
-                Err(e) => {
+            let n = match shutdown::until(timeout(rw_timeout, socket.read(&mut buf))).await {
+                Some(Ok(n)) => n,
+                Some(Err(e)) => {
                     error!("failed to read from {}; err = {:?}", address, e);
+                    log.end(Reason::Timeout);
+                    break;
+                }
+                None => {
+                    log.interrupt();
                     break;
                 }
             };
 
             let n = n.unwrap_or(0);
             if n == 0 {
+                log.end(Reason::ClientClose);
                 break;
             }
 
             log.raw(buf[..n].to_vec());
             let command = String::from_utf8_lossy(&buf[..n]);
 
-            let mut output: Option<Vec<u8>> = None;
-            for parser in &mut service.lock().unwrap().commands {
-                if let Some(out) = parser.parse(&command) {
-                    output = Some(out);
-                    break;
+            if let (Some(trigger), Some(acceptor)) = (&config.tls.starttls, &tls_acceptor) {
+                if trigger.is_match(&command) {
+                    if let Err(e) = timeout(
+                        rw_timeout,
+                        socket.write_all(config.tls.starttls_response.as_bytes()),
+                    )
+                    .await
+                    {
+                        error!(
+                            "failed to send starttls response to {}; err = {:?}",
+                            address, e
+                        );
+                        break;
+                    }
+
+                    socket = match socket.upgrade(acceptor, rw_timeout).await {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            warn!("{}: {}", address, e);
+                            log.log(e);
+                            break;
+                        }
+                    };
+
+                    log.log("starttls".to_owned());
+                    continue;
                 }
             }
 
-            if let Some(output) = output {
-                if let Err(e) = timeout(rw_timeout, socket.write_all(&output)).await {
+            if let (Some(machine), Some(current)) = (&config.machine, &state) {
+                if let Some(step) = machine.step(current, &command) {
+                    if !step.delay.is_zero() {
+                        sleep(step.delay).await;
+                    }
+
+                    if !step.response.is_empty() {
+                        if let Err(e) =
+                            timeout(rw_timeout, socket.write_all(step.response.as_bytes())).await
+                        {
+                            error!("failed to send response to {}; err = {:?}", address, e);
+                            break;
+                        }
+                    }
+
+                    if let Some(next) = step.next {
+                        log.log(format!("state {} -> {}", current, next));
+                        if !enter_state(&mut socket, address, machine.banner(next), rw_timeout)
+                            .await
+                        {
+                            break;
+                        }
+                        state = Some(next.to_owned());
+                    }
+
+                    if step.close {
+                        log.end(Reason::Exit);
+                        break;
+                    }
+
+                    continue;
+                }
+            }
+
+            let chunks = handler::dispatch(&service, &command, &mut context, None)
+                .await
+                .map(Output::into_chunks);
+
+            if let Some(chunks) = chunks {
+                if let Err(e) = pacing::send(&mut socket, &chunks, rw_timeout).await {
                     error!("failed to send response to {}; err = {:?}", address, e);
                 }
             }
         }
     }
 
+    // failed to send something to the client
+    log.end(Reason::Error);
     log.log("disconnected".to_string());
 
-    match log.save(&main_config.records.path) {
+    match log.save() {
         Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
         Err(s) => error!("{}", s),
     }
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::protocols::Error;
+
+pub const DEFAULT_INITIAL_STATE: &str = "start";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Transition {
+    // regular expression the client input must match
+    expect: String,
+    // data to send back, {$N} tokens are replaced with the captured groups
+    #[serde(default)]
+    response: String,
+    // state to move to after the response has been sent
+    #[serde(default)]
+    next: Option<String>,
+    // milliseconds to wait before sending the response
+    #[serde(default)]
+    delay: u64,
+    // close the connection once the response has been sent
+    #[serde(default)]
+    close: bool,
+    #[serde(skip)]
+    compiled: Option<Regex>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct State {
+    // sent as is every time the state is entered
+    #[serde(default)]
+    banner: String,
+    #[serde(default)]
+    transitions: Vec<Transition>,
+}
+
+#[derive(Debug)]
+pub struct Step<'a> {
+    pub response: String,
+    pub next: Option<&'a str>,
+    pub delay: Duration,
+    pub close: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Machine {
+    initial: String,
+    states: HashMap<String, State>,
+}
+
+impl Machine {
+    pub fn new(initial: String, mut states: HashMap<String, State>) -> Result<Self, Error> {
+        if !states.contains_key(&initial) {
+            return Err(format!("initial state '{}' is not defined", initial));
+        }
+
+        let names: Vec<String> = states.keys().cloned().collect();
+        for (name, state) in states.iter_mut() {
+            for transition in state.transitions.iter_mut() {
+                if let Some(next) = &transition.next {
+                    if !names.contains(next) {
+                        return Err(format!(
+                            "state '{}' has a transition to undefined state '{}'",
+                            name, next
+                        ));
+                    }
+                }
+
+                transition.compiled = Some(Regex::new(&transition.expect).map_err(|e| {
+                    format!(
+                        "can't compile regex '{}' of state '{}': {}",
+                        &transition.expect, name, e
+                    )
+                })?);
+            }
+        }
+
+        Ok(Self { initial, states })
+    }
+
+    pub fn initial(&self) -> &str {
+        &self.initial
+    }
+
+    pub fn banner(&self, state: &str) -> &str {
+        self.states
+            .get(state)
+            .map(|s| s.banner.as_str())
+            .unwrap_or("")
+    }
+
+    pub fn step(&self, state: &str, input: &str) -> Option<Step<'_>> {
+        let state = self.states.get(state)?;
+        for transition in &state.transitions {
+            if let Some(captures) = transition.compiled.as_ref().unwrap().captures(input) {
+                // substitute {$N} tokens with matches
+                let mut response = transition.response.to_owned();
+                for n in 1..captures.len() {
+                    let token = format!("{{${}}}", n);
+                    if response.contains(&token) {
+                        response = response
+                            .replace(&token, captures.get(n).map(|m| m.as_str()).unwrap_or(""));
+                    }
+                }
+
+                return Some(Step {
+                    response,
+                    next: transition.next.as_deref(),
+                    delay: Duration::from_millis(transition.delay),
+                    close: transition.close,
+                });
+            }
+        }
+        None
+    }
+}
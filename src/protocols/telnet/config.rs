@@ -1,4 +1,7 @@
-use crate::config::Service;
+use crate::{
+	config::Service,
+	protocols::{tls, Error},
+};
 
 const DEFAULT_BANNER: &str = "server v1.0";
 const DEFAULT_LOGIN_PROMPT: &str = "login: ";
@@ -6,22 +9,26 @@ const DEFAULT_PASSWORD_PROMPT: &str = "password: ";
 const DEFAULT_PROMPT: &str = "# ";
 const DEFAULT_TIMEOUT: u64 = 10;
 
-pub fn from_service(svc: &Service) -> Config {
+pub fn from_service(svc: &Service) -> Result<Config, Error> {
 	let address = svc.address.to_owned();
 	let banner = svc.string("banner", DEFAULT_BANNER);
 	let login_prompt = svc.string("login_prompt", DEFAULT_LOGIN_PROMPT);
 	let password_prompt = svc.string("password_prompt", DEFAULT_PASSWORD_PROMPT);
 	let prompt = svc.string("prompt", DEFAULT_PROMPT);
 	let timeout = svc.unsigned("timeout", DEFAULT_TIMEOUT);
+	let proxy_protocol = svc.bool("proxy_protocol", false);
+	let tls = tls::from_service(svc)?;
 
-	Config {
+	Ok(Config {
 		address,
 		banner,
 		login_prompt,
 		password_prompt,
 		prompt,
 		timeout,
-	}
+		tls,
+		proxy_protocol,
+	})
 }
 
 #[derive(Clone, Debug)]
@@ -32,4 +39,6 @@ pub struct Config {
 	pub password_prompt: String,
 	pub prompt: String,
 	pub timeout: u64,
+	pub proxy_protocol: bool,
+	pub tls: tls::Config,
 }
@@ -1,5 +1,5 @@
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Duration;
 
 use log::{debug, error, info, warn};
@@ -8,22 +8,208 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
-    config::{Config as MainConfig, Service},
-    record,
-    shell::handler::EXIT_HANDLER_TOKEN,
+    cast::Cast,
+    config::Config as MainConfig,
+    protocols::{tls::Stream, Peer, Recorded},
+    record::{self, Reason, Record},
+    shell::{
+        pacing,
+        session::Session,
+        tty::{self, Tty},
+    },
+    shutdown,
 };
 
 use super::config::Config;
 
 const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WILL: u8 = 251;
 const WONT: u8 = 252;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const IP: u8 = 244;
 const ECHO: u8 = 1;
+const SGA: u8 = 3;
+const NAWS: u8 = 31;
+
+// terminal size when the client doesn't tell it
+const DEFAULT_SIZE: (u32, u32) = (80, 24);
+
+// client connection, recorded once the telnet options have been negotiated
+type Client = Recorded<Stream>;
+
+// wait for the client to interrupt a running command, false if it disconnected
+async fn interrupted(socket: &mut Client) -> bool {
+    let mut buf = [0; 255];
+    loop {
+        match socket.read(&mut buf).await {
+            Ok(n) if n > 0 => {
+                // ^C or IAC IP
+                let data = &buf[..n];
+                if data.contains(&0x03) || data.windows(2).any(|w| w == [IAC, IP]) {
+                    return true;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+// window size sent by the client with the NAWS option (RFC 1073), if any
+fn window_size(data: &[u8]) -> Option<(u32, u32)> {
+    let start = data.windows(3).position(|w| w == [IAC, SB, NAWS])? + 3;
+
+    // 255 is sent as IAC IAC
+    let mut size = Vec::with_capacity(4);
+    let mut i = start;
+    while size.len() < 4 {
+        match *data.get(i)? {
+            IAC if data.get(i + 1) == Some(&IAC) => i += 2,
+            IAC => return None,
+            _ => i += 1,
+        }
+        size.push(data[i - 1]);
+    }
+
+    let width = u16::from_be_bytes([size[0], size[1]]) as u32;
+    let height = u16::from_be_bytes([size[2], size[3]]) as u32;
+    // 0 means unknown
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
+
+// remove the telnet commands from the client data, and the line endings a terminal
+// doesn't expect
+fn strip_commands(data: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            IAC => match data.get(i + 1) {
+                Some(&IAC) => {
+                    stripped.push(IAC);
+                    i += 2;
+                }
+                Some(&SB) => {
+                    // skip subnegotiation until IAC SE
+                    i += 2;
+                    while i < data.len() && !(data[i] == IAC && data.get(i + 1) == Some(&SE)) {
+                        i += 1;
+                    }
+                    i += 2;
+                }
+                Some(&cmd) if (WILL..=DONT).contains(&cmd) => i += 3,
+                _ => i += 2,
+            },
+            // \r\n and \r\0 are sent as \r
+            b'\r' => {
+                stripped.push(b'\r');
+                i += if matches!(data.get(i + 1), Some(b'\n') | Some(0)) {
+                    2
+                } else {
+                    1
+                };
+            }
+            byte => {
+                stripped.push(byte);
+                i += 1;
+            }
+        }
+    }
+    stripped
+}
+
+// attach the client to a terminal in a container, returns false if it couldn't
+async fn terminal(
+    socket: &mut Client,
+    address: SocketAddr,
+    config: &tty::Config,
+    log: &mut Record,
+    (width, height): (u32, u32),
+    rw_timeout: Duration,
+) -> bool {
+    let (mut tty, mut reader) = match Tty::open(config, width, height).await {
+        Ok(attached) => attached,
+        Err(e) => {
+            error!("could not attach terminal for {}: {}", address, e);
+            return false;
+        }
+    };
+
+    log.log("terminal attached".to_owned());
+
+    // the terminal echoes the input, so the client shouldn't
+    if let Err(e) = socket
+        .get_mut()
+        .write_all(&[IAC, WILL, ECHO, IAC, WILL, SGA])
+        .await
+    {
+        error!("failed to send server IAC to {}; err = {:?}", address, e);
+        log.end(Reason::Error);
+        return true;
+    }
+
+    let mut buf = [0; 1024];
+    loop {
+        tokio::select! {
+            read = timeout(rw_timeout, socket.read(&mut buf)) => {
+                let n = match read {
+                    Ok(Ok(n)) if n > 0 => n,
+                    Ok(Ok(_)) => {
+                        log.end(Reason::ClientClose);
+                        break;
+                    }
+                    Ok(Err(_)) => {
+                        log.end(Reason::Error);
+                        break;
+                    }
+                    Err(_) => {
+                        log.end(Reason::Timeout);
+                        break;
+                    }
+                };
+                if let Err(e) = tty.write(&strip_commands(&buf[..n]), log).await {
+                    warn!("{}: {}", address, e);
+                    log.end(Reason::Error);
+                    break;
+                }
+            }
+            output = reader.read() => {
+                let output = match output {
+                    Some(output) => output,
+                    None => {
+                        // the shell in the container exited
+                        log.end(Reason::Exit);
+                        break;
+                    }
+                };
+                if let Err(e) = timeout(rw_timeout, socket.write_all(&output)).await {
+                    error!("failed to send output to {}; err = {:?}", address, e);
+                    log.end(Reason::Error);
+                    break;
+                }
+            }
+            _ = shutdown::wait() => {
+                log.interrupt();
+                break;
+            }
+        }
+    }
+
+    tty.flush(log);
+    true
+}
 
 async fn login_prompt(
     config: Arc<Config>,
-    socket: &mut tokio::net::TcpStream,
+    socket: &mut Client,
     address: SocketAddr,
     rw_timeout: Duration,
 ) -> Result<Option<String>, String> {
@@ -61,7 +247,7 @@ async fn login_prompt(
 
 async fn password_prompt(
     config: Arc<Config>,
-    socket: &mut tokio::net::TcpStream,
+    socket: &mut Client,
     address: SocketAddr,
     rw_timeout: Duration,
 ) -> Result<Option<String>, String> {
@@ -100,13 +286,24 @@ async fn password_prompt(
     Ok(None)
 }
 
+// read a command line, without the client echoing it if it's a secret
 async fn command_prompt(
-    config: Arc<Config>,
-    socket: &mut tokio::net::TcpStream,
+    prompt: &str,
+    secret: bool,
+    socket: &mut Client,
     address: SocketAddr,
     rw_timeout: Duration,
 ) -> Result<Option<String>, String> {
-    if let Err(e) = timeout(rw_timeout, socket.write_all(config.prompt.as_bytes())).await {
+    if secret {
+        if let Err(e) = timeout(rw_timeout, socket.get_mut().write_all(&[IAC, WILL, ECHO])).await {
+            return Err(format!(
+                "failed to send server IAC to {}; err = {:?}",
+                address, e
+            ));
+        }
+    }
+
+    if let Err(e) = timeout(rw_timeout, socket.write_all(prompt.as_bytes())).await {
         return Err(format!(
             "failed to send prompt to {}; err = {:?}",
             address, e
@@ -114,38 +311,79 @@ async fn command_prompt(
     }
 
     let mut buf = [0; 1024];
-    let n = match timeout(rw_timeout, socket.read(&mut buf)).await {
-        Ok(n) => n,
-        Err(e) => {
+    let data = loop {
+        let n = match timeout(rw_timeout, socket.read(&mut buf)).await {
+            Ok(n) => n,
+            Err(e) => {
+                return Err(format!(
+                    "failed to read command from {}; err = {:?}",
+                    address, e
+                ));
+            }
+        };
+
+        let n = n.unwrap_or(0);
+        if n == 0 {
+            return Ok(None);
+        }
+
+        // the answers to the options negotiated by the server are not part of the command
+        let data = strip_commands(&buf[..n]);
+        if !data.is_empty() {
+            break data;
+        }
+    };
+
+    if secret {
+        // the line break wasn't echoed either
+        if let Err(e) = timeout(rw_timeout, socket.get_mut().write_all(&[IAC, WONT, ECHO])).await {
             return Err(format!(
-                "failed to read command from {}; err = {:?}",
+                "failed to send server IAC to {}; err = {:?}",
                 address, e
             ));
         }
-    };
+        if let Err(e) = timeout(rw_timeout, socket.write_all(b"\r\n")).await {
+            return Err(format!(
+                "failed to send prompt to {}; err = {:?}",
+                address, e
+            ));
+        }
+    }
 
-    let n = n.unwrap_or(0);
-    return if n == 0 {
-        Ok(None)
-    } else {
-        Ok(Some(String::from_utf8_lossy(&buf[0..n]).trim().to_string()))
-    };
+    Ok(Some(
+        String::from_utf8_lossy(&data)
+            .replace('\r', "\n")
+            .trim()
+            .to_string(),
+    ))
 }
 
 pub async fn handle(
-    mut socket: tokio::net::TcpStream,
-    address: SocketAddr,
+    mut socket: Stream,
+    peer: Peer,
     service_name: String,
-    service: Arc<Mutex<Service>>,
+    mut session: Session,
     config: Arc<Config>,
     main_config: Arc<MainConfig>,
+    tls_acceptor: Option<TlsAcceptor>,
 ) {
-    let mut log = record::for_address("telnet", &service_name, address);
+    let address = peer.address;
+    let mut log = record::for_address(
+        "telnet",
+        &service_name,
+        address,
+        peer.destination,
+        &main_config.records,
+    );
+    log.count(socket.traffic());
 
     log.log("connected".to_owned());
+    if let Some(proxy) = peer.proxy {
+        log.log(format!("proxied by {}", proxy));
+    }
 
     // sending initial IAC values
-    let srv_iacs = vec![(ECHO, WONT)];
+    let srv_iacs = vec![(ECHO, WONT), (NAWS, DO)];
     for (opt, cmd) in srv_iacs {
         let buf = vec![IAC, cmd, opt];
         if let Err(e) = socket.write_all(&buf).await {
@@ -160,10 +398,19 @@ pub async fn handle(
     // something or not.
     let rw_timeout = Duration::from_millis(300);
     let mut buf = [0; 255];
-    if let Err(e) = timeout(rw_timeout, socket.read(&mut buf)).await {
-        // not fatal
-        debug!("could not consume telnet first bytes from client: {}", e);
-    }
+    let size = match timeout(rw_timeout, socket.read(&mut buf)).await {
+        Ok(Ok(n)) => window_size(&buf[..n]),
+        Ok(Err(_)) => None,
+        Err(e) => {
+            // not fatal
+            debug!("could not consume telnet first bytes from client: {}", e);
+            None
+        }
+    };
+    let size = size.unwrap_or(DEFAULT_SIZE);
+
+    // only recorded once the client reaches a shell
+    let mut socket = Recorded::new(socket, Cast::default());
 
     // now use the configured timeout, we should be able to send data
     let rw_timeout = Duration::from_secs(config.timeout);
@@ -177,32 +424,92 @@ pub async fn handle(
         }
     }
 
-    let username = match login_prompt(config.clone(), &mut socket, address, rw_timeout).await {
-        Ok(username) => username,
-        Err(e) => {
-            // not fatal
-            warn!("{}", e);
-            None
-        }
-    };
+    let credentials = shutdown::until(async {
+        let username = match login_prompt(config.clone(), &mut socket, address, rw_timeout).await {
+            Ok(username) => username,
+            Err(e) => {
+                // not fatal
+                warn!("{}", e);
+                None
+            }
+        };
 
-    let password = match password_prompt(config.clone(), &mut socket, address, rw_timeout).await {
-        Ok(password) => password,
-        Err(e) => {
-            // not fatal
-            warn!("{}", e);
-            None
+        let password = match password_prompt(config.clone(), &mut socket, address, rw_timeout).await
+        {
+            Ok(password) => password,
+            Err(e) => {
+                // not fatal
+                warn!("{}", e);
+                None
+            }
+        };
+
+        (username, password)
+    })
+    .await;
+
+    let (username, password) = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            log.interrupt();
+            log.log("disconnected".to_string());
+
+            match log.save() {
+                Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
+                Err(s) => error!("{}", s),
+            }
+            return;
         }
     };
 
     if let Some(user) = username {
+        session.login(&user);
         log.auth(user, password, None);
     }
 
+    // what the client sees from now on is recorded
+    socket.record(log.cast("", size.0, size.1));
+
+    if let Some(config) = session.tty().cloned() {
+        if terminal(&mut socket, address, &config, &mut log, size, rw_timeout).await {
+            log.log("disconnected".to_string());
+
+            match log.save() {
+                Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
+                Err(s) => error!("{}", s),
+            }
+            return;
+        }
+    }
+
     let mut keep_going = true;
-    while let Ok(Some(command)) =
-        command_prompt(config.clone(), &mut socket, address, rw_timeout).await
-    {
+    let mut starttls = false;
+    loop {
+        let command = match shutdown::until(command_prompt(
+            &session.prompt(&config.prompt),
+            session.is_secret(),
+            &mut socket,
+            address,
+            rw_timeout,
+        ))
+        .await
+        {
+            Some(Ok(Some(command))) => command,
+            Some(Ok(None)) => {
+                log.end(Reason::ClientClose);
+                break;
+            }
+            Some(Err(e)) => {
+                debug!("{}", e);
+                log.end(Reason::Timeout);
+                break;
+            }
+            None => {
+                log.interrupt();
+                break;
+            }
+        };
+
         for command in command.split('\n') {
             let command = command.trim().to_string();
             if command.is_empty() {
@@ -211,46 +518,70 @@ pub async fn handle(
 
             log.command(command.clone());
 
-            let mut output: Option<Vec<u8>> = None;
-            for parser in &mut service.lock().unwrap().commands {
-                if let Some(out) = parser.parse(&command) {
-                    output = Some(out);
+            if let Some(trigger) = &config.tls.starttls {
+                if trigger.is_match(&command) {
+                    if let Err(e) = timeout(
+                        rw_timeout,
+                        socket.write_all(config.tls.starttls_response.as_bytes()),
+                    )
+                    .await
+                    {
+                        error!(
+                            "failed to send starttls response to {}; err = {:?}",
+                            address, e
+                        );
+                        log.end(Reason::Timeout);
+                        keep_going = false;
+                        break;
+                    }
+
+                    // upgrade once out of the commands loop
+                    starttls = true;
                     break;
                 }
             }
 
-            if let Some(output) = output {
-                if output == EXIT_HANDLER_TOKEN.as_bytes() {
-                    keep_going = false;
-                    break;
-                } else if let Err(e) = timeout(rw_timeout, socket.write_all(&output)).await {
-                    error!("failed to send output to {}; err = {:?}", address, e);
-                    keep_going = false;
-                    break;
-                }
-            } else {
-                debug!("'{}' command not found", command);
-
-                if let Err(e) = timeout(
-                    rw_timeout,
-                    socket.write_all(
-                        format!(
-                            "\r\nsh: command not found: {:?}",
-                            command.split(' ').collect::<Vec<&str>>()[0]
-                        )
-                        .as_bytes(),
-                    ),
-                )
-                .await
-                {
-                    error!("failed to send output to {}; err = {:?}", address, e);
-                    keep_going = false;
-                    break;
+            let response = session.execute(&command, &mut log).await;
+            if let Err(e) = pacing::send(&mut socket, &response.chunks, rw_timeout).await {
+                error!("failed to send output to {}; err = {:?}", address, e);
+                log.end(Reason::Error);
+                keep_going = false;
+                break;
+            }
+
+            let mut tail = vec![];
+            if response.running {
+                // the client may stay silent while the command runs
+                match shutdown::until(timeout(pacing::MAX_RUNNING, interrupted(&mut socket))).await {
+                    Some(Ok(true)) => {
+                        log.log("interrupted".to_owned());
+                        tail.extend_from_slice(b"^C");
+                    }
+                    Some(Ok(false)) => {
+                        log.end(Reason::ClientClose);
+                        keep_going = false;
+                        break;
+                    }
+                    Some(Err(_)) => log.log("command stopped running".to_owned()),
+                    None => {
+                        log.interrupt();
+                        keep_going = false;
+                        break;
+                    }
                 }
             }
+            if !response.output.is_empty() || response.running {
+                tail.extend_from_slice(b"\r\n");
+            }
+            if let Err(e) = timeout(rw_timeout, socket.write_all(&tail)).await {
+                error!("failed to send output to {}; err = {:?}", address, e);
+                log.end(Reason::Timeout);
+                keep_going = false;
+                break;
+            }
 
-            if let Err(e) = timeout(rw_timeout, socket.write_all("\r\n".as_bytes())).await {
-                error!("failed to send banner to {}; err = {:?}", address, e);
+            if response.exit {
+                log.end(Reason::Exit);
                 keep_going = false;
                 break;
             }
@@ -259,11 +590,30 @@ pub async fn handle(
         if !keep_going {
             break;
         }
+
+        if starttls {
+            starttls = false;
+            let cast = socket.cast();
+            socket = match socket
+                .into_inner()
+                .upgrade(tls_acceptor.as_ref().unwrap(), rw_timeout)
+                .await
+            {
+                Ok(socket) => Recorded::new(socket, cast),
+                Err(e) => {
+                    warn!("{}: {}", address, e);
+                    log.log(e);
+                    log.end(Reason::Error);
+                    break;
+                }
+            };
+            log.log("starttls".to_owned());
+        }
     }
 
     log.log("disconnected".to_string());
 
-    match log.save(&main_config.records.path) {
+    match log.save() {
         Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
         Err(s) => error!("{}", s),
     }
@@ -1,13 +1,17 @@
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{debug, warn};
 
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
 	config::{Config as MainConfig, Service},
-	protocols::{Error, Protocol},
+	protocols::{proxy, tls::Stream, Error, Peer, Protocol},
+	shell::{handler::Context, session::Session, Shell},
+	shutdown,
 };
 
 use super::{
@@ -19,8 +23,10 @@ use super::{
 pub struct Server {
 	service_name: String,
 	service: Arc<Mutex<Service>>,
+	shell: Arc<Shell>,
 	config: Arc<Config>,
 	main_config: Arc<MainConfig>,
+	tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl Server {
@@ -29,15 +35,19 @@ impl Server {
 		service: Arc<Mutex<Service>>,
 		main_config: MainConfig,
 	) -> Result<Self, Error> {
-		let config = config::from_service(service.lock().as_ref().unwrap());
+		let config = config::from_service(service.lock().as_ref().unwrap())?;
 		let config = Arc::new(config);
 		let main_config = Arc::new(main_config);
+		let shell = Arc::new(Shell::from_service(service.lock().as_ref().unwrap())?);
+		let tls_acceptor = config.tls.acceptor()?;
 
 		Ok(Server {
 			service_name,
 			service,
+			shell,
 			config,
 			main_config,
+			tls_acceptor,
 		})
 	}
 }
@@ -49,21 +59,46 @@ impl Protocol for Server {
 
 		let listener = TcpListener::bind(&self.config.address).await.unwrap();
 
-		while let Ok((socket, addr)) = listener.accept().await {
-			if !self.main_config.is_allowed_ip(&addr.ip()) {
-				warn!("{} not allowed", addr);
-				drop(socket);
-				continue;
-			}
+		while let Some(Ok((mut socket, addr))) = shutdown::until(listener.accept()).await {
+			let server = self.clone();
+			tokio::spawn(shutdown::track(async move {
+				let rw_timeout = Duration::from_secs(server.config.timeout);
+				let peer = if server.config.proxy_protocol {
+					match proxy::resolve(&mut socket, addr, rw_timeout).await {
+						Ok(peer) => peer,
+						Err(e) => {
+							warn!("{}: {}", addr, e);
+							return;
+						}
+					}
+				} else {
+					Peer::new(addr, proxy::local_address(&socket, addr))
+				};
 
-			tokio::spawn(handler::handle(
-				socket,
-				addr,
-				self.service_name.clone(),
-				self.service.clone(),
-				self.config.clone(),
-				self.main_config.clone(),
-			));
+				if !server.main_config.is_allowed_ip(&peer.address.ip()) {
+					warn!("{} not allowed", peer.address);
+					return;
+				}
+
+				// perform the tls handshake, if needed, without blocking the listener
+				match Stream::new(socket, &server.config.tls, server.tls_acceptor.as_ref(), rw_timeout).await {
+					Ok(socket) => {
+						let context = Context::for_address(&server.service_name, peer.address);
+						let session = Session::new(server.service, server.shell, context);
+						handler::handle(
+							socket,
+							peer,
+							server.service_name,
+							session,
+							server.config,
+							server.main_config,
+							server.tls_acceptor,
+						)
+						.await
+					}
+					Err(e) => warn!("{}: {}", peer.address, e),
+				}
+			}));
 		}
 	}
 }
@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use regex::Regex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        NoClientAuth, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::{
+    config::Service,
+    protocols::{Counted, Error},
+    record::Traffic,
+};
+
+pub const DEFAULT_CERT_FILE: &str = "/tmp/medusa-https.crt";
+pub const DEFAULT_KEY_FILE: &str = "/tmp/medusa-https.key";
+
+pub fn from_service(svc: &Service) -> Result<Config, Error> {
+    let enabled = svc.bool("tls", false);
+    let key_file = svc.string("key", DEFAULT_KEY_FILE);
+    let cert_file = svc.string("certificate", DEFAULT_CERT_FILE);
+    let starttls = match svc.string("starttls", "").as_str() {
+        "" => None,
+        expr => {
+            Some(Regex::new(expr).map_err(|e| format!("can't compile regex '{}': {}", expr, e))?)
+        }
+    };
+    let starttls_response = svc.string("starttls_response", "");
+
+    Ok(Config {
+        enabled,
+        key_file,
+        cert_file,
+        starttls,
+        starttls_response,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // wrap the whole connection in tls
+    pub enabled: bool,
+    pub key_file: String,
+    pub cert_file: String,
+    // client input that upgrades a plain connection to tls when matched
+    pub starttls: Option<Regex>,
+    // sent to the client before upgrading the connection
+    pub starttls_response: String,
+}
+
+impl Config {
+    pub fn is_needed(&self) -> bool {
+        self.enabled || self.starttls.is_some()
+    }
+
+    pub fn acceptor(&self) -> Result<Option<TlsAcceptor>, Error> {
+        if !self.is_needed() {
+            return Ok(None);
+        }
+
+        let certs = certs(&mut BufReader::new(
+            File::open(&self.cert_file)
+                .map_err(|e| format!("could not open {}: {}", self.cert_file, e))?,
+        ))
+        .map_err(|_| "invalid certificate")?;
+
+        let mut keys = pkcs8_private_keys(&mut BufReader::new(
+            File::open(&self.key_file)
+                .map_err(|e| format!("could not open {}: {}", self.key_file, e))?,
+        ))
+        .map_err(|_| "invalid key")?;
+
+        if keys.is_empty() {
+            // try PKCS#1 before returning an error
+            keys = rsa_private_keys(&mut BufReader::new(
+                File::open(&self.key_file)
+                    .map_err(|e| format!("could not open {}: {}", self.key_file, e))?,
+            ))
+            .map_err(|_| "invalid key")?;
+
+            if keys.is_empty() {
+                return Err(format!(
+                    "no valid PKCS#8 or PKCS#1 encoded keys found in {}",
+                    &self.key_file
+                ));
+            }
+        }
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(certs, keys.remove(0))
+            .map_err(|e| format!("could not set tls certificate and key: {}", e))?;
+
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+// a client connection that is either plain text or wrapped in tls, the bytes on the
+// wire are counted
+pub enum Stream {
+    Plain(Counted<TcpStream>),
+    Tls(Box<TlsStream<Counted<TcpStream>>>),
+}
+
+impl Stream {
+    // wrap the socket in tls right away if configured to do so
+    pub async fn new(
+        socket: TcpStream,
+        config: &Config,
+        acceptor: Option<&TlsAcceptor>,
+        handshake_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let socket = Counted::new(socket);
+        match acceptor {
+            Some(acceptor) if config.enabled => {
+                Self::accept(socket, acceptor, handshake_timeout).await
+            }
+            _ => Ok(Self::Plain(socket)),
+        }
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        match self {
+            Self::Plain(socket) => socket.traffic(),
+            Self::Tls(stream) => stream.get_ref().0.traffic(),
+        }
+    }
+
+    // clients that never complete the handshake are dropped after the timeout
+    async fn accept(
+        socket: Counted<TcpStream>,
+        acceptor: &TlsAcceptor,
+        handshake_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let stream = timeout(handshake_timeout, acceptor.accept(socket))
+            .await
+            .map_err(|_| "tls handshake timed out".to_owned())?
+            .map_err(|e| format!("tls handshake failed: {}", e))?;
+
+        Ok(Self::Tls(Box::new(stream)))
+    }
+
+    pub async fn upgrade(
+        self,
+        acceptor: &TlsAcceptor,
+        handshake_timeout: Duration,
+    ) -> Result<Self, Error> {
+        match self {
+            Self::Plain(socket) => Self::accept(socket, acceptor, handshake_timeout).await,
+            Self::Tls(_) => Err("connection is already using tls".to_owned()),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => Pin::new(socket).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(socket) => Pin::new(socket).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => Pin::new(socket).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => Pin::new(socket).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
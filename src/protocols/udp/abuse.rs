@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// fixed window counter of the responses sent in the last second
+#[derive(Clone, Copy)]
+struct Window {
+    started_at: Instant,
+    count: u64,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            count: 0,
+        }
+    }
+
+    // true if another response fits in the current window
+    fn has_room(&mut self, limit: u64) -> bool {
+        if self.started_at.elapsed() >= WINDOW {
+            self.started_at = Instant::now();
+            self.count = 0;
+        }
+
+        limit == 0 || self.count < limit
+    }
+}
+
+pub struct Guard {
+    // max responses per second to a single source address, 0 for unlimited
+    rate_limit: u64,
+    // max responses per second for the whole service, 0 for unlimited
+    global_rate_limit: u64,
+    // max ratio between response and request sizes, 0 for unlimited
+    max_amplification: f64,
+    sources: HashMap<IpAddr, Window>,
+    global: Window,
+    probes: HashMap<&'static str, u64>,
+}
+
+impl Guard {
+    pub fn new(rate_limit: u64, global_rate_limit: u64, max_amplification: f64) -> Self {
+        Self {
+            rate_limit,
+            global_rate_limit,
+            max_amplification,
+            sources: HashMap::new(),
+            global: Window::new(),
+            probes: HashMap::new(),
+        }
+    }
+
+    // returns true if a response can be sent to the source without exceeding the rate limits
+    pub fn allow(&mut self, source: IpAddr) -> bool {
+        let rate_limit = self.rate_limit;
+        let source = self.sources.entry(source).or_insert_with(Window::new);
+        // only count the responses actually sent, so that a source that is rate
+        // limited globally isn't penalized and the other way around
+        if !source.has_room(rate_limit) || !self.global.has_room(self.global_rate_limit) {
+            return false;
+        }
+
+        source.count += 1;
+        self.global.count += 1;
+        true
+    }
+
+    pub fn is_amplified(&self, request_size: usize, response_size: usize) -> bool {
+        self.max_amplification > 0.0
+            && response_size as f64 > request_size as f64 * self.max_amplification
+    }
+
+    // count a detected probe and return how many of its kind have been seen so far
+    pub fn probe(&mut self, kind: &'static str) -> u64 {
+        let count = self.probes.entry(kind).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    // forget sources that didn't send anything in the last window
+    pub fn cleanup(&mut self) {
+        self.sources
+            .retain(|_, window| window.started_at.elapsed() < WINDOW);
+    }
+}
+
+fn detect_dns_any(data: &[u8]) -> bool {
+    // 12 bytes header, QR bit must be 0 (query) and at least one question
+    if data.len() < 17 || data[2] & 0x80 != 0 || data[4..6] == [0, 0] {
+        return false;
+    }
+
+    // skip the qname labels of the first question
+    let mut offset = 12;
+    while offset < data.len() && data[offset] != 0 {
+        offset += data[offset] as usize + 1;
+    }
+    offset += 1;
+
+    // QTYPE 255 = ANY
+    offset + 2 <= data.len() && data[offset..offset + 2] == [0x00, 0xff]
+}
+
+fn detect_ntp_monlist(data: &[u8]) -> bool {
+    // mode 7 (private), xntpd implementation, request code MON_GETLIST or MON_GETLIST_1
+    data.len() >= 4
+        && data[0] & 0x07 == 7
+        && (1..=4).contains(&((data[0] >> 3) & 0x07))
+        && (data[2] == 2 || data[2] == 3)
+        && (data[3] == 20 || data[3] == 42)
+}
+
+fn detect_memcached(data: &[u8]) -> bool {
+    // 8 bytes udp frame header followed by a text command
+    data.len() > 8
+        && [&b"stats"[..], b"get ", b"gets "]
+            .iter()
+            .any(|cmd| data[8..].starts_with(cmd))
+}
+
+fn detect_snmp_getbulk(data: &[u8]) -> bool {
+    // SEQUENCE { INTEGER version, OCTET STRING community, GetBulkRequest-PDU }
+    if data.len() < 7 || data[0] != 0x30 || data[2] != 0x02 || data[3] != 0x01 || data[5] != 0x04 {
+        return false;
+    }
+    let pdu = 7 + data[6] as usize;
+    pdu < data.len() && data[pdu] == 0xa5
+}
+
+fn detect_portmap_dump(data: &[u8]) -> bool {
+    // RPC call (0) version 2 to program 100000 (portmapper) procedure 4 (DUMP)
+    data.len() >= 24
+        && data[4..8] == [0, 0, 0, 0]
+        && data[8..12] == [0, 0, 0, 2]
+        && data[12..16] == [0x00, 0x01, 0x86, 0xa0]
+        && data[20..24] == [0, 0, 0, 4]
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|window| window == pattern)
+}
+
+// returns the kind of amplification probe the datagram looks like, if any
+pub fn detect(data: &[u8]) -> Option<&'static str> {
+    if detect_dns_any(data) {
+        Some("dns-any")
+    } else if detect_ntp_monlist(data) {
+        Some("ntp-monlist")
+    } else if detect_memcached(data) {
+        Some("memcached")
+    } else if data.starts_with(b"M-SEARCH") {
+        Some("ssdp")
+    } else if data.first() == Some(&0x30) && contains(&data.to_ascii_lowercase(), b"objectclass") {
+        Some("cldap")
+    } else if detect_snmp_getbulk(data) {
+        Some("snmp-getbulk")
+    } else if detect_portmap_dump(data) {
+        Some("portmap-dump")
+    } else if contains(data, b"schemas.xmlsoap.org/ws/2005/04/discovery") {
+        Some("ws-discovery")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dns_query(qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&[0, 1]);
+        query
+    }
+
+    fn snmp(pdu: u8) -> Vec<u8> {
+        let mut request = vec![0x30, 0x26, 0x02, 0x01, 0x01, 0x04, 0x06];
+        request.extend_from_slice(b"public");
+        request.extend_from_slice(&[pdu, 0x19, 0x02, 0x04, 0x12, 0x34, 0x56, 0x78]);
+        request
+    }
+
+    fn probes() -> Vec<(&'static str, Vec<u8>)> {
+        let mut memcached = vec![0, 0, 0, 0, 0, 1, 0, 0];
+        memcached.extend_from_slice(b"stats\r\n");
+
+        let mut cldap = vec![0x30, 0x84, 0, 0, 0, 0x2d, 0x02, 0x01, 0x01, 0x63];
+        cldap.extend_from_slice(b"\x04\x00\x0a\x01\x00\x0a\x01\x00\x02\x01\x00\x02\x01\x00\x01\x01\x00\x87\x0bobjectClass");
+
+        let mut portmap = vec![0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0, 0, 0, 0, 2];
+        portmap.extend_from_slice(&[0x00, 0x01, 0x86, 0xa0, 0, 0, 0, 2, 0, 0, 0, 4]);
+        portmap.extend_from_slice(&[0; 16]);
+
+        let mut ntp = vec![0x17, 0x00, 0x03, 0x2a];
+        ntp.extend_from_slice(&[0; 4]);
+
+        vec![
+            ("dns-any", dns_query(255)),
+            ("ntp-monlist", ntp),
+            ("memcached", memcached),
+            ("ssdp", b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n".to_vec()),
+            ("cldap", cldap),
+            ("snmp-getbulk", snmp(0xa5)),
+            ("portmap-dump", portmap),
+            ("ws-discovery", b"<soap:Envelope xmlns:wsd=\"http://schemas.xmlsoap.org/ws/2005/04/discovery\"><soap:Body><wsd:Probe/></soap:Body></soap:Envelope>".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn probes_are_detected() {
+        for (kind, probe) in probes() {
+            assert_eq!(detect(&probe), Some(kind));
+        }
+    }
+
+    #[test]
+    fn legit_requests_are_not_probes() {
+        let mut ntp = vec![0x1b];
+        ntp.extend_from_slice(&[0; 47]);
+
+        for request in &[
+            vec![],
+            b"\n".to_vec(),
+            b"hello".to_vec(),
+            b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+            dns_query(1),
+            dns_query(28),
+            ntp,
+            snmp(0xa0),
+            vec![0, 0, 0, 0, 0, 1, 0, 0],
+        ] {
+            assert_eq!(detect(request), None, "{:?}", request);
+        }
+
+        // answers are not queries
+        let mut answer = dns_query(255);
+        answer[2] |= 0x80;
+        assert_eq!(detect(&answer), None);
+    }
+
+    #[test]
+    fn truncated_probes() {
+        // no prefix of a probe can make the detection panic
+        for (_, probe) in probes() {
+            for size in 0..probe.len() {
+                detect(&probe[..size]);
+            }
+        }
+
+        // labels or community strings longer than the datagram
+        let mut query = dns_query(255);
+        query[12] = 0xff;
+        assert_eq!(detect(&query), None);
+        let mut query = dns_query(255);
+        query.truncate(query.len() - 3);
+        assert_eq!(detect(&query), None);
+
+        let mut request = snmp(0xa5);
+        request[6] = 0xff;
+        assert_eq!(detect(&request), None);
+        request.truncate(13);
+        request[6] = 6;
+        assert_eq!(detect(&request), None);
+    }
+
+    #[test]
+    fn rate_limits() {
+        let a: IpAddr = "1.2.3.4".parse().unwrap();
+        let b: IpAddr = "5.6.7.8".parse().unwrap();
+
+        let mut guard = Guard::new(2, 3, 0.0);
+        assert!(guard.allow(a));
+        assert!(guard.allow(a));
+        assert!(!guard.allow(a));
+        assert!(guard.allow(b));
+        assert!(!guard.allow(b));
+
+        // unlimited
+        let mut guard = Guard::new(0, 0, 0.0);
+        assert!((0..1000).all(|_| guard.allow(a)));
+    }
+
+    #[test]
+    fn rejected_responses_are_not_counted() {
+        let a: IpAddr = "1.2.3.4".parse().unwrap();
+        let b: IpAddr = "5.6.7.8".parse().unwrap();
+
+        let mut guard = Guard::new(1, 1, 0.0);
+        assert!(guard.allow(a));
+        // over the global limit, not the one of b
+        assert!(!guard.allow(b));
+
+        guard.global = Window::new();
+        assert!(guard.allow(b));
+
+        // over the limit of a, the global one is untouched
+        guard.global = Window::new();
+        assert!(!guard.allow(a));
+        assert!(guard.allow("9.9.9.9".parse().unwrap()));
+    }
+
+    #[test]
+    fn amplification() {
+        let guard = Guard::new(0, 0, 2.0);
+        assert!(!guard.is_amplified(10, 20));
+        assert!(guard.is_amplified(10, 21));
+        assert!(guard.is_amplified(0, 1));
+        assert!(!Guard::new(0, 0, 0.0).is_amplified(1, 1000));
+    }
+}
@@ -1,23 +1,81 @@
-use crate::config::Service;
+use crate::{config::Service, protocols::Error};
 
 const DEFAULT_BANNER: &str = "hi";
+const DEFAULT_BANNER_MODE: &str = "first";
 const DEFAULT_TIMEOUT: u64 = 10;
+const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_RESPONSES: u64 = 10;
+const DEFAULT_MAX_SESSIONS: u64 = 1000;
+const DEFAULT_RATE_LIMIT: u64 = 5;
+const DEFAULT_GLOBAL_RATE_LIMIT: u64 = 100;
+const DEFAULT_MAX_AMPLIFICATION: f64 = 2.0;
 
-pub fn from_service(svc: &Service) -> Config {
+#[derive(Clone, Debug, PartialEq)]
+pub enum BannerMode {
+	// send the banner only before the first response of a session
+	First,
+	// never send the banner
+	Never,
+	// send the banner before every response
+	Always,
+}
+
+impl BannerMode {
+	fn parse(mode: &str) -> Result<Self, Error> {
+		match mode {
+			"first" => Ok(Self::First),
+			"never" => Ok(Self::Never),
+			"always" => Ok(Self::Always),
+			_ => Err(format!(
+				"banner_mode '{}' is not valid, use one of: first, never, always",
+				mode
+			)),
+		}
+	}
+}
+
+pub fn from_service(svc: &Service) -> Result<Config, Error> {
 	let address = svc.address.to_owned();
 	let banner = svc.string("banner", DEFAULT_BANNER);
+	let banner_mode = BannerMode::parse(&svc.string("banner_mode", DEFAULT_BANNER_MODE))?;
 	let timeout = svc.unsigned("timeout", DEFAULT_TIMEOUT);
+	let idle_timeout = svc.unsigned("idle_timeout", DEFAULT_IDLE_TIMEOUT);
+	let max_responses = svc.unsigned("max_responses", DEFAULT_MAX_RESPONSES);
+	let max_sessions = svc.unsigned("max_sessions", DEFAULT_MAX_SESSIONS);
+	let rate_limit = svc.unsigned("rate_limit", DEFAULT_RATE_LIMIT);
+	let global_rate_limit = svc.unsigned("global_rate_limit", DEFAULT_GLOBAL_RATE_LIMIT);
+	let max_amplification = svc.float("max_amplification", DEFAULT_MAX_AMPLIFICATION);
 
-	Config {
+	Ok(Config {
 		address,
 		banner,
+		banner_mode,
 		timeout,
-	}
+		idle_timeout,
+		max_responses,
+		max_sessions,
+		rate_limit,
+		global_rate_limit,
+		max_amplification,
+	})
 }
 
 #[derive(Clone, Debug)]
 pub struct Config {
 	pub address: String,
 	pub banner: String,
+	pub banner_mode: BannerMode,
 	pub timeout: u64,
+	// seconds of inactivity after which a peer session is saved and closed
+	pub idle_timeout: u64,
+	// maximum number of responses sent to a peer per session, 0 for unlimited
+	pub max_responses: u64,
+	// maximum number of peers tracked at once, the least recently seen is closed to make room
+	pub max_sessions: u64,
+	// maximum number of responses per second to a single source ip, 0 for unlimited
+	pub rate_limit: u64,
+	// maximum number of responses per second of the service, 0 for unlimited
+	pub global_rate_limit: u64,
+	// maximum ratio between the size of a response and the size of its request, 0 for unlimited
+	pub max_amplification: f64,
 }
@@ -1,2 +1,4 @@
+mod abuse;
 mod config;
 pub mod server;
+mod session;
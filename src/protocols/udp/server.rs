@@ -1,18 +1,30 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use log::{debug, error, info, warn};
 
-use tokio::{net::UdpSocket, time::timeout};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    time::{interval, timeout},
+};
 
 use crate::{
     config::{Config as MainConfig, Service},
     protocols::{Error, Protocol},
-    record,
+    record::Reason,
+    shell::handler::{self, Context, Output},
+    shutdown,
 };
 
-use super::config::{self, Config};
+use super::{
+    abuse::{self, Guard},
+    config::{self, BannerMode, Config},
+    session::Session,
+};
 
 #[derive(Clone)]
 pub struct Server {
@@ -28,7 +40,7 @@ impl Server {
         service: Arc<Mutex<Service>>,
         main_config: MainConfig,
     ) -> Result<Self, Error> {
-        let config = config::from_service(service.lock().as_ref().unwrap());
+        let config = config::from_service(service.lock().as_ref().unwrap())?;
         let config = Arc::new(config);
         let main_config = Arc::new(main_config);
 
@@ -39,6 +51,212 @@ impl Server {
             main_config,
         })
     }
+
+    fn save(&self, mut session: Session, reason: Reason) {
+        session.log.end(reason);
+        session.log.log(format!(
+            "session closed ({}): {} datagrams received ({} not recorded), {} responses sent, {} dropped",
+            reason, session.datagrams, session.unrecorded, session.responses, session.dropped
+        ));
+
+        match session.log.save() {
+            Ok(path) => info!("saved {} entries to {:?}", session.log.size(), path),
+            Err(s) => error!("{}", s),
+        }
+    }
+
+    fn expire(&self, sessions: &mut HashMap<SocketAddr, Session>) {
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        let expired: Vec<SocketAddr> = sessions
+            .iter()
+            .filter(|(_, session)| session.is_idle(idle_timeout))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in expired {
+            if let Some(session) = sessions.remove(&peer) {
+                self.save(session, Reason::Timeout);
+            }
+        }
+    }
+
+    // close the least recently seen session to make room for a new peer
+    fn evict(&self, sessions: &mut HashMap<SocketAddr, Session>) {
+        let oldest = sessions
+            .iter()
+            .min_by_key(|(_, session)| session.last_seen)
+            .map(|(peer, _)| *peer);
+
+        if let Some(mut session) = oldest.and_then(|peer| sessions.remove(&peer)) {
+            session.log.log(format!(
+                "too many sessions (max_sessions: {}), closing the least recently seen",
+                self.config.max_sessions
+            ));
+            self.save(session, Reason::Exit);
+        }
+    }
+
+    fn on_datagram(
+        &self,
+        guard: &mut Guard,
+        session: &mut Session,
+        peer: SocketAddr,
+        data: &[u8],
+        responses: &UnboundedSender<Response>,
+    ) {
+        session.last_seen = Instant::now();
+        session.datagrams += 1;
+        session.log.traffic().received(data.len());
+        if session.should_record(self.config.max_responses) {
+            session.log.raw(data.to_vec());
+        } else {
+            session.unrecorded += 1;
+        }
+
+        // never answer to what looks like an amplification attempt
+        if let Some(kind) = abuse::detect(data) {
+            let seen = guard.probe(kind);
+            warn!(
+                "[{}] <{}> {} amplification probe ({} seen so far)",
+                &self.service_name, peer, kind, seen
+            );
+            session.log.tag("amplification");
+            session
+                .log
+                .log(format!("{} amplification probe detected", kind));
+            session.dropped += 1;
+            return;
+        }
+
+        if !session.has_budget(self.config.max_responses) {
+            debug!("response budget for {} exhausted", peer);
+            session.dropped += 1;
+            return;
+        }
+
+        // the handlers of a peer run one at a time since they share its context
+        let mut context = match session.context.take() {
+            Some(context) => context,
+            None => {
+                debug!("still answering {}, dropping datagram", peer);
+                session.dropped += 1;
+                return;
+            }
+        };
+
+        let service = self.service.clone();
+        let command = String::from_utf8_lossy(data).into_owned();
+        let request_size = data.len();
+        let first = session.datagrams == 1;
+        let responses = responses.clone();
+
+        // run the handlers in their own task so a slow one never stalls the service
+        tokio::spawn(async move {
+            let output = handler::dispatch(&service, &command, &mut context, None)
+                .await
+                .map(Output::into_bytes);
+
+            responses
+                .send(Response {
+                    peer,
+                    context,
+                    output,
+                    request_size,
+                    first,
+                })
+                .ok();
+        });
+    }
+
+    async fn on_response(
+        &self,
+        listener: &UdpSocket,
+        guard: &mut Guard,
+        session: &mut Session,
+        response: Response,
+    ) {
+        let rw_timeout = Duration::from_secs(self.config.timeout);
+        let Response {
+            peer,
+            context,
+            output,
+            request_size,
+            first,
+        } = response;
+
+        session.context = Some(context);
+
+        let send_banner = !self.config.banner.is_empty()
+            && match self.config.banner_mode {
+                BannerMode::First => first,
+                BannerMode::Never => false,
+                BannerMode::Always => true,
+            };
+
+        let mut size = output.as_ref().map(|o| o.len()).unwrap_or(0);
+        if send_banner {
+            size += self.config.banner.len();
+        }
+
+        if size == 0 {
+            return;
+        }
+
+        if guard.is_amplified(request_size, size) {
+            debug!(
+                "dropping {} bytes response to a {} bytes request from {}",
+                size, request_size, peer
+            );
+            session.dropped += 1;
+            return;
+        }
+
+        if !guard.allow(peer.ip()) {
+            debug!("rate limiting {}", peer);
+            session.dropped += 1;
+            return;
+        }
+
+        let traffic = session.log.traffic();
+        if send_banner {
+            match timeout(
+                rw_timeout,
+                listener.send_to(self.config.banner.as_bytes(), &peer),
+            )
+            .await
+            {
+                Ok(Ok(n)) => traffic.sent(n),
+                Ok(Err(e)) => error!("error sending udp banner to {:?}: {}", peer, e),
+                Err(e) => error!("error sending udp banner to {:?}: {}", peer, e),
+            }
+        }
+
+        if let Some(output) = &output {
+            match timeout(rw_timeout, listener.send_to(output, &peer)).await {
+                Ok(Ok(n)) => traffic.sent(n),
+                Ok(Err(e)) => error!("error sending udp response to {:?}: {}", peer, e),
+                Err(e) => error!("error sending udp response to {:?}: {}", peer, e),
+            }
+        }
+
+        session.responses += 1;
+        if !session.has_budget(self.config.max_responses) {
+            session.log.log(format!(
+                "response budget of {} exhausted, ignoring further datagrams",
+                self.config.max_responses
+            ));
+        }
+    }
+}
+
+// output of the handlers for a datagram, along with the context of its peer
+struct Response {
+    peer: SocketAddr,
+    context: Context,
+    output: Option<Vec<u8>>,
+    request_size: usize,
+    // whether the datagram was the first of the session
+    first: bool,
 }
 
 #[async_trait]
@@ -46,50 +264,54 @@ impl Protocol for Server {
     async fn run(&self) {
         debug!("starting udp on {} ...", &self.config.address);
 
-        let rw_timeout = Duration::from_secs(self.config.timeout);
         let mut buf = [0; 1024];
+        let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+        let mut guard = Guard::new(
+            self.config.rate_limit,
+            self.config.global_rate_limit,
+            self.config.max_amplification,
+        );
+        let mut expiration = interval(Duration::from_secs(1));
+        let (responses, mut pending) = unbounded_channel();
         let listener = UdpSocket::bind(&self.config.address).await.unwrap();
+        let destination = listener.local_addr().unwrap();
         loop {
-            if let Ok((size, peer)) = listener.recv_from(&mut buf).await {
-                if !self.main_config.is_allowed_ip(&peer.ip()) {
-                    warn!("{} not allowed", peer);
-                    continue;
-                }
+            tokio::select! {
+                received = listener.recv_from(&mut buf) => {
+                    if let Ok((size, peer)) = received {
+                        if !self.main_config.is_allowed_ip(&peer.ip()) {
+                            warn!("{} not allowed", peer);
+                            continue;
+                        }
 
-                let mut log = record::for_address("udp", &self.service_name, peer);
+                        let max_sessions = self.config.max_sessions as usize;
+                        if max_sessions > 0 && sessions.len() >= max_sessions && !sessions.contains_key(&peer) {
+                            self.evict(&mut sessions);
+                        }
 
-                if !self.config.banner.is_empty() {
-                    if let Err(e) = timeout(
-                        rw_timeout,
-                        listener.send_to(self.config.banner.as_bytes(), &peer),
-                    )
-                    .await
-                    {
-                        error!("error sending udp banner to {:?}: {}", peer, e);
-                    }
-                }
-
-                log.raw(buf[..size].to_vec());
+                        let session = sessions
+                            .entry(peer)
+                            .or_insert_with(|| Session::new(&self.service_name, peer, destination, &self.main_config.records));
 
-                let command = String::from_utf8_lossy(&buf[..size]);
-
-                let mut output: Option<Vec<u8>> = None;
-                for parser in &mut self.service.lock().unwrap().commands {
-                    if let Some(out) = parser.parse(&command) {
-                        output = Some(out);
-                        break;
+                        self.on_datagram(&mut guard, session, peer, &buf[..size], &responses);
                     }
                 }
-
-                if let Some(output) = output {
-                    if let Err(e) = timeout(rw_timeout, listener.send_to(&output, &peer)).await {
-                        error!("error sending udp response to {:?}: {}", peer, e);
+                Some(response) = pending.recv() => {
+                    // the session may have expired (and been replaced) while the handlers were running
+                    if let Some(session) = sessions.get_mut(&response.peer).filter(|session| session.context.is_none()) {
+                        self.on_response(&listener, &mut guard, session, response).await;
                     }
                 }
-
-                match log.save(&self.main_config.records.path) {
-                    Ok(path) => info!("saved {} entries to {:?}", log.size(), path),
-                    Err(s) => error!("{}", s),
+                _ = expiration.tick() => {
+                    self.expire(&mut sessions);
+                    guard.cleanup();
+                }
+                _ = shutdown::wait() => {
+                    for (_, mut session) in sessions.drain() {
+                        session.log.log(shutdown::ENTRY.to_owned());
+                        self.save(session, Reason::Shutdown);
+                    }
+                    break;
                 }
             }
         }
@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::Records;
+use crate::record::{self, Record};
+use crate::shell::handler::Context;
+
+pub struct Session {
+    pub log: Record,
+    pub last_seen: Instant,
+    pub datagrams: u64,
+    pub responses: u64,
+    pub dropped: u64,
+    // datagrams whose payload was not recorded
+    pub unrecorded: u64,
+    // taken by the task running the handlers while a response is being prepared
+    pub context: Option<Context>,
+}
+
+impl Session {
+    pub fn new(
+        service_name: &str,
+        peer: SocketAddr,
+        destination: SocketAddr,
+        records: &Records,
+    ) -> Self {
+        let mut log = record::for_address("udp", service_name, peer, destination, records);
+
+        log.log("session started".to_owned());
+
+        Self {
+            log,
+            last_seen: Instant::now(),
+            datagrams: 0,
+            responses: 0,
+            dropped: 0,
+            unrecorded: 0,
+            context: Some(Context::for_address(service_name, peer)),
+        }
+    }
+
+    pub fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.last_seen.elapsed() >= idle_timeout
+    }
+
+    pub fn has_budget(&self, max_responses: u64) -> bool {
+        max_responses == 0 || self.responses < max_responses
+    }
+
+    // payloads are only recorded for as many datagrams as the responses allowed
+    pub fn should_record(&self, max_responses: u64) -> bool {
+        max_responses == 0 || self.datagrams <= max_responses
+    }
+}
@@ -1,8 +1,24 @@
-use std::{fmt, fs, net::SocketAddr, path::PathBuf, str};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use chrono::{DateTime, Utc};
-use log::info;
+use glob::glob;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{cast::Cast, config::Records, shutdown, sinks, sqlite};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
@@ -14,8 +30,34 @@ pub enum Data {
     },
     Log(String),
     Command(String),
+    // single command of a command line
+    Exec(String),
     Request(String),
     Raw(Vec<u8>),
+    // terminal output sent to the client
+    Output(String),
+    Download {
+        tool: String,
+        url: String,
+        host: String,
+        filename: String,
+    },
+}
+
+impl Data {
+    // short name of the entry type
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Authentication { .. } => "auth",
+            Self::Log(_) => "log",
+            Self::Command(_) => "command",
+            Self::Exec(_) => "exec",
+            Self::Request(_) => "request",
+            Self::Raw(_) => "raw",
+            Self::Output(_) => "output",
+            Self::Download { .. } => "download",
+        }
+    }
 }
 
 impl fmt::Display for Data {
@@ -34,7 +76,19 @@ impl fmt::Display for Data {
             }
             Self::Log(s) => write!(f, "{}", s),
             Self::Command(s) => write!(f, "command: {}", s),
+            Self::Exec(s) => write!(f, "exec: {}", s),
             Self::Request(s) => write!(f, "request: {}", s),
+            Self::Output(s) => write!(f, "output: {:?}", s),
+            Self::Download {
+                tool,
+                url,
+                host,
+                filename,
+            } => write!(
+                f,
+                "download: {} url={} host={} file={}",
+                tool, url, host, filename
+            ),
             Self::Raw(data) => {
                 if let Ok(s) = str::from_utf8(data) {
                     write!(f, "raw: '{}'", s)
@@ -67,38 +121,313 @@ impl fmt::Display for Entry {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// version of the record format, the records without one are version 1
+pub const VERSION: u32 = 2;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+// why a session ended
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    // the client closed the connection
+    ClientClose,
+    Timeout,
+    // closed by the honeypot, for instance by an @exit handler
+    Exit,
+    Error,
+    Shutdown,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Self::ClientClose => "client close",
+            Self::Timeout => "timeout",
+            Self::Exit => "exit",
+            Self::Error => "error",
+            Self::Shutdown => "shutdown",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+// bytes received from and sent to the client, shared with the socket counting them
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+}
+
+impl Traffic {
+    pub fn received(&self, size: usize) {
+        self.received.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, size: usize) {
+        self.sent.fetch_add(size as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Record {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    // unique id of the session, a ULID
+    #[serde(default)]
+    pub id: String,
     pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_at: Option<DateTime<Utc>>,
+    // seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
     // server info
+    #[serde(default)]
+    pub sensor: String,
     pub hostname: String,
     pub protocol: String,
     pub service: String,
+    // address and port the client connected to
+    #[serde(default)]
+    pub destination_address: String,
+    #[serde(default)]
+    pub destination_port: u16,
     // client info
     pub address: String,
     pub port: u16,
+    // bytes received from the client
+    #[serde(default)]
+    pub bytes_in: u64,
+    // bytes sent to the client
+    #[serde(default)]
+    pub bytes_out: u64,
     // events
     pub entries: Vec<Entry>,
+    // flags set by the protocols for notable sessions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // where and how the record is written
+    #[serde(skip)]
+    records: Records,
+    // file the record is streamed to, entries are kept in memory until it's open
+    #[serde(skip)]
+    writer: Option<Writer>,
+    // entries written to the file
+    #[serde(skip)]
+    written: usize,
+    // session record not saved yet, saved when dropped otherwise
+    #[serde(skip)]
+    pending: bool,
+    // key of the session in the sqlite database, if enabled
+    #[serde(skip)]
+    stored: Option<u64>,
+    #[serde(skip)]
+    traffic: Traffic,
+}
+
+// first line of a record file
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    #[serde(default = "legacy_version")]
+    version: u32,
+    #[serde(default)]
+    id: String,
+    created_at: DateTime<Utc>,
+    #[serde(default)]
+    sensor: String,
+    hostname: String,
+    protocol: String,
+    service: String,
+    #[serde(default)]
+    destination_address: String,
+    #[serde(default)]
+    destination_port: u16,
+    address: String,
+    port: u16,
+}
+
+// last line of a record file, missing if the session is still open or medusa crashed
+#[derive(Debug, Serialize, Deserialize)]
+struct Footer {
+    closed_at: DateTime<Utc>,
+    #[serde(default)]
+    duration: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+    entries: usize,
+    // unknown for recovered records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes_in: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes_out: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    // closed at startup after an unclean shutdown
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    recovered: bool,
+}
+
+// line of a record file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Line {
+    Header(Header),
+    Entry(Entry),
+    Tag { tag: String },
+    Footer(Footer),
+}
+
+// append only JSON lines file a record is streamed to
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    file: File,
+    // how often the file is synced to disk
+    interval: Duration,
+    synced: Instant,
+}
+
+impl Writer {
+    fn create(path: PathBuf, interval: Duration) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| format!("could not create {:?}: {}", path, e))?;
+        Ok(Self {
+            path,
+            file,
+            interval,
+            synced: Instant::now(),
+        })
+    }
+
+    // every line is written at once, so that a crash can only truncate the last one
+    fn write(&mut self, line: &Line) -> Result<(), String> {
+        let mut data = serde_json::to_vec(line)
+            .map_err(|e| format!("could not convert record line to json: {}", e))?;
+        data.push(b'\n');
+        self.file
+            .write_all(&data)
+            .map_err(|e| format!("could not write to {:?}: {}", self.path, e))?;
+        if self.synced.elapsed() >= self.interval {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), String> {
+        self.synced = Instant::now();
+        self.file
+            .sync_data()
+            .map_err(|e| format!("could not sync {:?}: {}", self.path, e))
+    }
 }
 
 impl fmt::Display for Record {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(
+        write!(
             f,
             "[{}] {} -> {} ({})",
             self.created_at, self.address, self.service, self.protocol
         )?;
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(", "))?;
+        }
+        writeln!(f)?;
         for entry in &self.entries {
             writeln!(f, "  {}", entry)?;
         }
+        if let (Some(duration), Some(reason)) = (self.duration, self.reason) {
+            writeln!(
+                f,
+                "  closed ({}) after {:.1}s, {} bytes in, {} bytes out",
+                reason, duration, self.bytes_in, self.bytes_out
+            )?;
+        }
         Ok(())
     }
 }
 
 impl Record {
+    // record that is not written anywhere, for instance to be replayed
+    pub fn new(
+        created_at: DateTime<Utc>,
+        hostname: String,
+        protocol: String,
+        service: String,
+        address: String,
+        port: u16,
+    ) -> Self {
+        Self {
+            version: VERSION,
+            id: String::new(),
+            created_at,
+            closed_at: None,
+            duration: None,
+            reason: None,
+            sensor: String::new(),
+            hostname,
+            protocol,
+            service,
+            destination_address: String::new(),
+            destination_port: 0,
+            address,
+            port,
+            bytes_in: 0,
+            bytes_out: 0,
+            entries: vec![],
+            tags: vec![],
+            records: Records::default(),
+            writer: None,
+            written: 0,
+            pending: false,
+            stored: None,
+            traffic: Traffic::default(),
+        }
+    }
+
+    // write the entry to the file, or keep it in memory if it's not open
+    fn push(&mut self, entry: Entry) {
+        if let Some(key) = self.stored {
+            sqlite::entry(key, &entry);
+        }
+        if sinks::enabled() {
+            sinks::publish(sinks::Event {
+                time: entry.time,
+                session: self.id.to_owned(),
+                sensor: self.sensor.to_owned(),
+                hostname: self.hostname.to_owned(),
+                protocol: self.protocol.to_owned(),
+                service: self.service.to_owned(),
+                address: self.address.to_owned(),
+                port: self.port,
+                data: entry.data.clone(),
+            });
+        }
+
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                self.entries.push(entry);
+                return;
+            }
+        };
+
+        match writer.write(&Line::Entry(entry)) {
+            Ok(_) => self.written += 1,
+            Err(e) => error!("{}", e),
+        }
+    }
+
     pub fn log(&mut self, text: String) {
         info!("[{}] <{}> {}", &self.service, self.address, &text);
-        self.entries.push(Entry::new(Data::Log(text)));
+        self.push(Entry::new(Data::Log(text)));
     }
 
     pub fn auth(&mut self, username: String, password: Option<String>, key: Option<String>) {
@@ -118,7 +447,7 @@ impl Record {
                 "".to_owned()
             }
         );
-        self.entries.push(Entry::new(Data::Authentication {
+        self.push(Entry::new(Data::Authentication {
             username,
             password,
             key,
@@ -127,22 +456,103 @@ impl Record {
 
     pub fn request(&mut self, request: String) {
         info!("[{}] <{}> {}", &self.service, self.address, &request);
-        self.entries.push(Entry::new(Data::Request(request)));
+        self.push(Entry::new(Data::Request(request)));
     }
 
     pub fn command(&mut self, command: String) {
         info!("[{}] <{}> {}", &self.service, self.address, &command);
-        self.entries.push(Entry::new(Data::Command(command)));
+        self.push(Entry::new(Data::Command(command)));
+    }
+
+    pub fn exec(&mut self, command: String) {
+        info!("[{}] <{}> exec: {}", &self.service, self.address, &command);
+        self.push(Entry::new(Data::Exec(command)));
     }
 
     pub fn raw(&mut self, data: Vec<u8>) {
         let entry = Entry::new(Data::Raw(data));
         info!("[{}] <{}> {}", &self.service, self.address, &entry.data);
-        self.entries.push(entry);
+        self.push(entry);
+    }
+
+    pub fn output(&mut self, output: String) {
+        let entry = Entry::new(Data::Output(output));
+        debug!("[{}] <{}> {}", &self.service, self.address, &entry.data);
+        self.push(entry);
+    }
+
+    pub fn download(&mut self, tool: String, url: String, host: String, filename: String) {
+        let entry = Entry::new(Data::Download {
+            tool,
+            url,
+            host,
+            filename,
+        });
+        info!("[{}] <{}> {}", &self.service, self.address, &entry.data);
+        self.push(entry);
+        self.tag("download");
+    }
+
+    pub fn tag(&mut self, tag: &str) {
+        if self.tags.iter().any(|t| t == tag) {
+            return;
+        }
+        self.tags.push(tag.to_owned());
+        if let Some(key) = self.stored {
+            sqlite::tag(key, tag);
+        }
+        if let Some(writer) = &mut self.writer {
+            let line = Line::Tag {
+                tag: tag.to_owned(),
+            };
+            if let Err(e) = writer.write(&line) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    // set why the session ended, the first reason wins
+    pub fn end(&mut self, reason: Reason) {
+        if self.reason.is_none() {
+            self.reason = Some(reason);
+        }
+    }
+
+    // the session was closed because of a shutdown of the sensor
+    pub fn interrupt(&mut self) {
+        self.log(shutdown::ENTRY.to_owned());
+        self.end(Reason::Shutdown);
+    }
+
+    // counters of the bytes going through the client connection
+    pub fn traffic(&self) -> Traffic {
+        self.traffic.clone()
+    }
+
+    // count the bytes of a connection created before the record
+    pub fn count(&mut self, traffic: Traffic) {
+        self.traffic = traffic;
     }
 
     pub fn size(&self) -> usize {
-        self.entries.len()
+        self.written + self.entries.len()
+    }
+
+    // start the asciinema recording of the session terminal, next to the record file
+    pub fn cast(&self, term: &str, width: u32, height: u32) -> Cast {
+        if !self.records.casts {
+            return Cast::default();
+        }
+
+        let path = self.path(&self.records.path).with_extension("cast");
+        let title = format!("{} -> {} ({})", self.address, self.service, self.protocol);
+        match Cast::create(&path, self.created_at, title, term, width, height) {
+            Ok(cast) => cast,
+            Err(e) => {
+                error!("{}", e);
+                Cast::default()
+            }
+        }
     }
 
     fn path(&self, folder: &str) -> PathBuf {
@@ -151,16 +561,35 @@ impl Record {
         path.push(&self.address);
         path.push(&self.service);
 
+        // the id avoids collisions between sessions started at the same time
         path.push(format!(
-            "{}.json",
-            self.created_at.format("%+") // ISO 8601 / RFC 3339
+            "{}_{}.jsonl",
+            self.created_at.format("%+"), // ISO 8601 / RFC 3339
+            self.id
         ));
 
         path
     }
 
-    pub fn save(&mut self, folder: &str) -> Result<PathBuf, String> {
-        let path = self.path(folder);
+    fn header(&self) -> Header {
+        Header {
+            version: self.version,
+            id: self.id.to_owned(),
+            created_at: self.created_at,
+            sensor: self.sensor.to_owned(),
+            hostname: self.hostname.to_owned(),
+            protocol: self.protocol.to_owned(),
+            service: self.service.to_owned(),
+            destination_address: self.destination_address.to_owned(),
+            destination_port: self.destination_port,
+            address: self.address.to_owned(),
+            port: self.port,
+        }
+    }
+
+    // create the record file and write what has been recorded so far
+    fn open(&mut self) -> Result<(), String> {
+        let path = self.path(&self.records.path);
         let parent = match path.parent() {
             Some(parent) => parent,
             None => return Err(format!("could not get parent folder of {:?}", path)),
@@ -168,29 +597,241 @@ impl Record {
 
         fs::create_dir_all(parent).map_err(|e| format!("could not create {:?}: {}", parent, e))?;
 
-        let data = serde_json::to_string_pretty(self)
-            .map_err(|e| format!("could not convert record to json: {}", e))?;
+        let mut writer = Writer::create(path, Duration::from_secs(self.records.sync))?;
+        writer.write(&Line::Header(self.header()))?;
+        for tag in &self.tags {
+            writer.write(&Line::Tag {
+                tag: tag.to_owned(),
+            })?;
+        }
+        for entry in &self.entries {
+            writer.write(&Line::Entry(entry.clone()))?;
+        }
+
+        self.written += self.entries.len();
+        self.entries.clear();
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    // close the record file
+    pub fn save(&mut self) -> Result<PathBuf, String> {
+        self.pending = false;
+        let closed_at = Utc::now();
+        let duration = (closed_at - self.created_at).num_milliseconds() as f64 / 1000.0;
+        self.closed_at = Some(closed_at);
+        self.duration = Some(duration);
+        self.bytes_in = self.traffic.received.load(Ordering::Relaxed);
+        self.bytes_out = self.traffic.sent.load(Ordering::Relaxed);
 
-        if let Err(e) = fs::write(&path, data) {
-            return Err(format!("could not write record: {}", e));
+        if let Some(key) = self.stored.take() {
+            sqlite::close(key, self);
         }
 
-        Ok(path)
+        if self.writer.is_none() {
+            self.open()?;
+        }
+
+        let mut writer = self.writer.take().unwrap();
+        writer.write(&Line::Footer(Footer {
+            closed_at,
+            duration,
+            reason: self.reason,
+            entries: self.written,
+            bytes_in: Some(self.bytes_in),
+            bytes_out: Some(self.bytes_out),
+            tags: self.tags.clone(),
+            recovered: false,
+        }))?;
+        writer.sync()?;
+
+        Ok(writer.path)
     }
 }
 
-pub fn for_address(protocol: &str, service: &str, address: SocketAddr) -> Record {
+impl Drop for Record {
+    fn drop(&mut self) {
+        // not saved by the protocol, because its task was interrupted by a shutdown
+        // or panicked
+        if self.pending {
+            let interrupted = shutdown::triggered();
+            if interrupted {
+                self.interrupt();
+            }
+            match self.save() {
+                Ok(path) if interrupted => info!("saved {} entries to {:?}", self.size(), path),
+                Ok(path) => debug!("closed {:?}", path),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+}
+
+// parse a record file, either a JSON lines one or a legacy JSON one, returning the
+// record, whether it has been closed and the length of its complete lines
+fn parse(path: &Path) -> Result<(Record, bool, usize), String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("could not open {:?}: {}", path, e))?;
+
+    if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+        let record = serde_json::from_str(&data)
+            .map_err(|e| format!("could not deserialize {:?}: {}", path, e))?;
+        return Ok((record, true, data.len()));
+    }
+
+    let mut record: Option<Record> = None;
+    let mut closed = false;
+    let mut valid = 0;
+
+    // a line without a line break has been truncated by a crash
+    for line in data
+        .split_inclusive('\n')
+        .filter(|line| line.ends_with('\n'))
+    {
+        let parsed: Line = serde_json::from_str(line)
+            .map_err(|e| format!("could not deserialize {:?}: {}", path, e))?;
+        valid += line.len();
+
+        match (parsed, &mut record) {
+            (Line::Header(header), None) => {
+                let mut loaded = Record::new(
+                    header.created_at,
+                    header.hostname,
+                    header.protocol,
+                    header.service,
+                    header.address,
+                    header.port,
+                );
+                loaded.version = header.version;
+                loaded.id = header.id;
+                loaded.sensor = header.sensor;
+                loaded.destination_address = header.destination_address;
+                loaded.destination_port = header.destination_port;
+                record = Some(loaded);
+            }
+            (Line::Entry(entry), Some(record)) => record.entries.push(entry),
+            (Line::Tag { tag }, Some(record)) => {
+                if !record.tags.contains(&tag) {
+                    record.tags.push(tag);
+                }
+            }
+            (Line::Footer(footer), Some(record)) => {
+                record.closed_at = Some(footer.closed_at);
+                record.duration = Some(footer.duration);
+                record.reason = footer.reason;
+                record.bytes_in = footer.bytes_in.unwrap_or(0);
+                record.bytes_out = footer.bytes_out.unwrap_or(0);
+                closed = true;
+            }
+            _ => return Err(format!("{:?} is not a valid record file", path)),
+        }
+    }
+
+    match record {
+        Some(record) => Ok((record, closed, valid)),
+        None => Err(format!("{:?} has no header", path)),
+    }
+}
+
+pub fn load(path: &Path) -> Result<Record, String> {
+    parse(path).map(|(record, _, _)| record)
+}
+
+// close the record files left open by an unclean shutdown, returns how many
+pub fn recover(folder: &str) -> usize {
+    let mut recovered = 0;
+
+    for path in glob(&format!("{}/**/*.jsonl", folder)).unwrap().flatten() {
+        let (record, closed, valid) = match parse(&path) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        if closed {
+            continue;
+        }
+
+        let closed_at = record
+            .entries
+            .last()
+            .map(|entry| entry.time)
+            .unwrap_or(record.created_at);
+        let footer = Line::Footer(Footer {
+            closed_at,
+            duration: (closed_at - record.created_at).num_milliseconds() as f64 / 1000.0,
+            reason: None,
+            entries: record.entries.len(),
+            bytes_in: None,
+            bytes_out: None,
+            tags: record.tags.clone(),
+            recovered: true,
+        });
+
+        // drop the truncated line, if any, and close the record
+        let result = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_len(valid as u64).map(|_| file))
+            .map_err(|e| format!("could not open {:?}: {}", path, e))
+            .and_then(|file| {
+                Writer {
+                    path: path.clone(),
+                    file,
+                    interval: Duration::default(),
+                    synced: Instant::now(),
+                }
+                .write(&footer)
+            });
+
+        match result {
+            Ok(_) => {
+                info!("recovered {:?} ({} entries)", path, record.entries.len());
+                recovered += 1;
+            }
+            Err(e) => error!("could not recover {:?}: {}", path, e),
+        }
+    }
+
+    recovered
+}
+
+// start the record of a session, streamed to a file in the records folder
+pub fn for_address(
+    protocol: &str,
+    service: &str,
+    address: SocketAddr,
+    destination: SocketAddr,
+    records: &Records,
+) -> Record {
     let hostname = gethostname::gethostname()
         .to_str()
         .unwrap_or("could not detect hostname")
         .to_owned();
-    Record {
-        created_at: Utc::now(),
-        protocol: protocol.to_owned(),
-        service: service.to_owned(),
-        entries: Vec::new(),
-        address: address.ip().to_string(),
-        port: address.port(),
+    let mut record = Record::new(
+        Utc::now(),
         hostname,
+        protocol.to_owned(),
+        service.to_owned(),
+        address.ip().to_string(),
+        address.port(),
+    );
+    record.id = Ulid::new().to_string();
+    record.sensor = if records.sensor.is_empty() {
+        record.hostname.to_owned()
+    } else {
+        records.sensor.to_owned()
+    };
+    record.destination_address = destination.ip().to_string();
+    record.destination_port = destination.port();
+    record.records = records.clone();
+    record.stored = sqlite::session(&record);
+    record.pending = true;
+
+    // entries are kept in memory and written when saving if the file can't be created
+    if let Err(e) = record.open() {
+        error!("{}", e);
     }
+
+    record
 }
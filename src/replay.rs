@@ -1,31 +1,106 @@
 use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
 
 use glob::glob;
-use log::info;
+use log::{debug, info};
+use tokio::time::sleep;
 
-use crate::record::Record;
+use crate::{
+    cast::{self, Event, Header},
+    record::{self, Record},
+    sqlite,
+};
 
 pub(crate) fn start(path: &str) -> Result<(), String> {
     info!("starting replay mode from {} ...", path);
 
     let mut records = vec![];
 
-    for entry in glob(&format!("{}/**/*.json", path)).unwrap() {
-        let entry = entry.unwrap();
-        let raw = fs::read_to_string(&entry)
-            .map_err(|e| format!("could not open {} for reading: {:?}", entry.display(), e))?;
+    // streamed records and the ones saved by older versions
+    for pattern in &["jsonl", "json"] {
+        for entry in glob(&format!("{}/**/*.{}", path, pattern)).unwrap() {
+            let entry = entry.unwrap();
+            records.push(record::load(&entry)?);
+        }
+    }
 
-        let record: Record = serde_json::from_str(&raw)
-            .map_err(|e| format!("could not deserialize {}: {:?}", entry.display(), e))?;
+    print(records);
 
-        records.push(record);
-    }
+    Ok(())
+}
+
+pub(crate) fn from_sqlite(path: &str) -> Result<(), String> {
+    info!("starting replay mode from {} ...", path);
 
+    print(sqlite::load(path)?);
+
+    Ok(())
+}
+
+fn print(mut records: Vec<Record>) {
     records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
 
     for record in records {
         println!("{}", record);
     }
+}
+
+// write the output of a terminal recording to stdout with its original timing, divided
+// by the speed
+pub(crate) async fn play(path: &str, speed: f64) -> Result<(), String> {
+    if speed <= 0.0 || !speed.is_finite() {
+        return Err(format!("invalid playback speed {}", speed));
+    }
+
+    let data = fs::read_to_string(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+
+    let header: Header = serde_json::from_str(lines.next().unwrap_or_default())
+        .map_err(|e| format!("could not parse header of {}: {}", path, e))?;
+    if header.version != cast::VERSION {
+        return Err(format!(
+            "unsupported asciicast version {} in {}",
+            header.version, path
+        ));
+    }
+
+    info!(
+        "playing {} ({}x{}) at {}x speed ...",
+        header.title.as_deref().unwrap_or(path),
+        header.width,
+        header.height,
+        speed
+    );
+
+    let mut stdout = io::stdout();
+    let mut elapsed = 0.0;
+    for line in lines {
+        // the last line of a recording interrupted by a crash can be truncated
+        let (time, kind, data): Event = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(e) => {
+                debug!("skipping invalid event '{}': {}", line, e);
+                continue;
+            }
+        };
+
+        if time > elapsed {
+            sleep(Duration::from_secs_f64((time - elapsed) / speed)).await;
+            elapsed = time;
+        }
+
+        // the input is echoed back by the terminal when it's meant to be seen
+        if kind == "o" {
+            stdout
+                .write_all(data.as_bytes())
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("could not write to stdout: {}", e))?;
+        }
+    }
+
+    println!();
+    info!("end of the recording after {:.1}s", elapsed);
 
     Ok(())
 }
@@ -12,6 +12,9 @@ pub(crate) fn load(options: &Options) -> config::Config {
     let mut config = config::Config::new();
 
     config.records.path = options.records.to_string();
+    config.records.sync = options.records_sync;
+    config.records.sensor = options.sensor_id.to_string();
+    config.records.casts = options.casts;
 
     if !options.only.is_empty() {
         config.only = options
@@ -41,10 +44,19 @@ pub(crate) fn load(options: &Options) -> config::Config {
                     .map_err(|e| format!("error reading service file {:?}: {}", &path, e))
                     .unwrap();
 
-                let service: config::Service = serde_yaml::from_str(&data)
+                let mut service: config::Service = serde_yaml::from_str(&data)
                     .map_err(|e| format!("error parsing service file {:?}: {}", &path, e))
                     .unwrap();
 
+                for command in &service.commands {
+                    command
+                        .validate()
+                        .map_err(|e| format!("error in service file {:?}: {}", &path, e))
+                        .unwrap();
+                }
+
+                service.path = path;
+
                 config.services.insert(service_name, service);
             }
             Err(e) => error!("{:?}", e),
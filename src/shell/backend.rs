@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{docker::Engine, handler::Output, sandbox::Sandbox};
+
+fn default_network() -> String {
+    "none".to_owned()
+}
+
+fn default_command() -> Vec<String> {
+    vec!["tail".to_owned(), "-f".to_owned(), "/dev/null".to_owned()]
+}
+
+// jail created for every client session
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct Container {
+    // container image, or root filesystem folder for the sandbox backend
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    // memory limit in megabytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    // number of cpus, can be fractional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+    // max number of processes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<i64>,
+    // network mode, no network by default
+    #[serde(default = "default_network")]
+    pub network: String,
+    // main process keeping the container alive
+    #[serde(default = "default_command")]
+    pub command: Vec<String>,
+    // compiled seccomp filter, sandbox backend only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<String>,
+    // megabytes of the /tmp tmpfs, sandbox backend only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmp_size: Option<u64>,
+}
+
+impl Container {
+    // check the limits are supported by the backend
+    pub fn validate(&self, backend: &str) -> Result<(), String> {
+        if backend == "sandbox" && (self.cpus.is_some() || self.pids.is_some()) {
+            return Err(
+                "the cpus and pids limits are not supported by the sandbox backend".to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+// something that can execute commands in isolation
+#[async_trait]
+pub trait Backend: Send + Sync {
+    // run a command in a jail, either a shared one by name or one created for the session
+    async fn exec(&self, jail: &str, command: &str) -> Result<Output, String>;
+    // create a new jail, returning its id
+    async fn create(&self, container: &Container) -> Result<String, String>;
+    // destroy a jail created with create
+    async fn remove(&self, id: &str) -> Result<(), String>;
+}
+
+lazy_static! {
+    pub static ref DOCKER: Engine = Engine::docker();
+    pub static ref PODMAN: Engine = Engine::podman();
+    static ref SANDBOX: Sandbox = Sandbox::default();
+}
+
+// get a backend by the name used in handlers
+pub fn get(name: &str) -> Option<&'static dyn Backend> {
+    match name {
+        "docker" => Some(&*DOCKER),
+        "podman" => Some(&*PODMAN),
+        "sandbox" => Some(&*SANDBOX),
+        _ => None,
+    }
+}
+
+// remove the session containers left behind by a previous run or by sessions still
+// closing on shutdown
+pub async fn cleanup() {
+    for (name, engine) in [("docker", &*DOCKER), ("podman", &*PODMAN)] {
+        match engine.cleanup().await {
+            Ok(0) => {}
+            Ok(removed) => info!("removed {} {} containers left behind", removed, name),
+            Err(e) => warn!("could not remove the {} containers left behind: {}", name, e),
+        }
+    }
+}
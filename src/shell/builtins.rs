@@ -0,0 +1,463 @@
+use super::{
+    handler::Output,
+    session::{Session, User},
+    vfs::{self, Kind, Node},
+};
+
+// split arguments in single letter flags and operands
+fn options(args: &[String]) -> (String, Vec<&str>) {
+    let mut flags = String::new();
+    let mut operands = vec![];
+    let mut only_operands = false;
+
+    for arg in args {
+        if only_operands || arg.len() < 2 || !arg.starts_with('-') {
+            operands.push(arg.as_str());
+        } else if arg == "--" {
+            only_operands = true;
+        } else {
+            flags.push_str(&arg[1..]);
+        }
+    }
+
+    (flags, operands)
+}
+
+fn cd(session: &mut Session, args: &[String], out: &mut Output) {
+    let target = match args.first() {
+        Some(target) => target.to_owned(),
+        None => session.user.home.to_owned(),
+    };
+
+    let path = vfs::join(&session.cwd, &target);
+    match session.fs.get(&path) {
+        // keep the logical path like shells do
+        Ok((_, node)) if node.is_dir() => session.cwd = path,
+        Ok(_) => out.error(format!(
+            "{}: cd: can't cd to {}: {}",
+            session.name(),
+            target,
+            vfs::NOT_A_DIRECTORY
+        )),
+        Err(e) => out.error(format!(
+            "{}: cd: can't cd to {}: {}",
+            session.name(),
+            target,
+            e
+        )),
+    }
+}
+
+// switch user without asking for a password, like root would
+fn su(session: &mut Session, args: &[String], out: &mut Output) {
+    let (flags, operands) = options(args);
+    let login = flags.contains('l') || operands.first() == Some(&"-");
+    let name = operands
+        .iter()
+        .find(|operand| **operand != "-")
+        .copied()
+        .unwrap_or("root");
+
+    if name != "root" && session.passwd(name).is_none() {
+        out.error(format!("su: unknown user {}", name));
+        return;
+    }
+
+    session.user = User::new(&session.fs, name);
+    if login && session.fs.exists(&session.user.home) {
+        session.cwd = session.user.home.to_owned();
+    }
+}
+
+fn long_format(session: &Session, name: &str, node: &Node) -> String {
+    let mut line = format!(
+        "{} {:>4} {:<8} {:<8} {:>9} {} {}",
+        node.permissions(),
+        if node.is_dir() { 2 } else { 1 },
+        session.user_name(node.uid),
+        session.group_name(node.gid),
+        node.size(),
+        node.modified.format("%b %e %H:%M"),
+        name
+    );
+    if let Kind::Symlink(target) = &node.kind {
+        line.push_str(" -> ");
+        line.push_str(target);
+    }
+    line
+}
+
+fn ls(session: &mut Session, args: &[String], out: &mut Output) {
+    let (flags, mut operands) = options(args);
+    let all = flags.contains('a');
+    let long = flags.contains('l');
+    let one_per_line = long || flags.contains('1');
+    if operands.is_empty() {
+        operands.push(".");
+    }
+
+    let print = |out: &mut Output, names: Vec<String>| {
+        if one_per_line {
+            for name in names {
+                out.print(&name);
+            }
+        } else if !names.is_empty() {
+            out.print(&names.join("  "));
+        }
+    };
+
+    let show_headers = operands.len() > 1;
+    for (i, operand) in operands.iter().enumerate() {
+        let path = vfs::join(&session.cwd, operand);
+        let node = match session.fs.get(&path) {
+            Ok((_, node)) => node,
+            Err(e) => {
+                out.error(format!("ls: {}: {}", operand, e));
+                continue;
+            }
+        };
+
+        if !node.is_dir() || flags.contains('d') {
+            let node = session.fs.get_link(&path).map(|(_, n)| n).unwrap_or(node);
+            let line = if long {
+                long_format(session, operand, node)
+            } else {
+                operand.to_string()
+            };
+            print(out, vec![line]);
+            continue;
+        }
+
+        if show_headers {
+            if i > 0 {
+                out.print("");
+            }
+            out.print(&format!("{}:", operand));
+        }
+
+        let mut entries: Vec<(&str, &Node)> = vec![];
+        if all {
+            entries.push((".", node));
+            if let Ok((_, parent)) = session.fs.get(&vfs::parent(&path)) {
+                entries.push(("..", parent));
+            }
+        }
+        if let Ok(children) = session.fs.list(&path) {
+            entries.extend(
+                children
+                    .into_iter()
+                    .filter(|(name, _)| all || !name.starts_with('.')),
+            );
+        }
+
+        let lines = entries
+            .iter()
+            .map(|(name, node)| {
+                if long {
+                    long_format(session, name, node)
+                } else {
+                    name.to_string()
+                }
+            })
+            .collect();
+        print(out, lines);
+    }
+}
+
+fn cat(session: &mut Session, args: &[String], stdin: &[u8], out: &mut Output) {
+    let (_, operands) = options(args);
+    if operands.is_empty() {
+        out.stdout.extend_from_slice(stdin);
+    }
+
+    for operand in operands {
+        if operand == "-" {
+            out.stdout.extend_from_slice(stdin);
+            continue;
+        }
+
+        match session.fs.read(&vfs::join(&session.cwd, operand)) {
+            Ok(data) => out.stdout.extend_from_slice(&data),
+            Err(e) => out.error(format!("cat: {}: {}", operand, e)),
+        }
+    }
+}
+
+// interpret echo -e escape sequences
+fn unescape(arg: &str) -> Vec<u8> {
+    let mut data = vec![];
+    let mut chars = arg.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => data.push(b'\n'),
+            Some('r') => data.push(b'\r'),
+            Some('t') => data.push(b'\t'),
+            Some('a') => data.push(0x07),
+            Some('b') => data.push(0x08),
+            Some('e') => data.push(0x1b),
+            Some('\\') => data.push(b'\\'),
+            Some(base @ 'x') | Some(base @ '0') => {
+                let (radix, max) = if base == 'x' { (16, 2) } else { (8, 3) };
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while digits < max {
+                    match chars.peek().and_then(|c| c.to_digit(radix)) {
+                        Some(digit) => {
+                            value = value * radix + digit;
+                            digits += 1;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                data.push(value as u8);
+            }
+            Some(c) => {
+                data.push(b'\\');
+                let mut buf = [0; 4];
+                data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            None => data.push(b'\\'),
+        }
+    }
+
+    data
+}
+
+fn echo(args: &[String], out: &mut Output) {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut args = args;
+
+    // only leading arguments made of valid flags are options
+    while let Some(arg) = args.first() {
+        if arg.len() < 2 || !arg.starts_with('-') || !arg[1..].chars().all(|c| "neE".contains(c)) {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        args = &args[1..];
+    }
+
+    let text = args.join(" ");
+    if escapes {
+        out.stdout.extend(unescape(&text));
+    } else {
+        out.stdout.extend_from_slice(text.as_bytes());
+    }
+    if newline {
+        out.stdout.push(b'\n');
+    }
+}
+
+fn rm(session: &mut Session, args: &[String], out: &mut Output) {
+    let (flags, operands) = options(args);
+    let recursive = flags.contains('r') || flags.contains('R');
+    let force = flags.contains('f');
+
+    if operands.is_empty() && !force {
+        out.error("rm: missing operand".to_owned());
+    }
+
+    for operand in operands {
+        match session
+            .fs
+            .remove(&vfs::join(&session.cwd, operand), recursive)
+        {
+            Ok(_) => {}
+            Err(vfs::NOT_FOUND) if force => {}
+            Err(vfs::IS_A_DIRECTORY) => out.error(format!("rm: '{}' is a directory", operand)),
+            Err(e) => out.error(format!("rm: can't remove '{}': {}", operand, e)),
+        }
+    }
+}
+
+fn mkdir(session: &mut Session, args: &[String], out: &mut Output) {
+    let (flags, operands) = options(args);
+    let parents = flags.contains('p');
+
+    if operands.is_empty() {
+        out.error("mkdir: missing operand".to_owned());
+    }
+
+    for operand in operands {
+        let path = vfs::join(&session.cwd, operand);
+        if let Err(e) = session
+            .fs
+            .mkdir(&path, parents, session.user.uid, session.user.gid)
+        {
+            out.error(format!(
+                "mkdir: can't create directory '{}': {}",
+                operand, e
+            ));
+        }
+    }
+}
+
+// parse an octal or symbolic (u+x,go-w, ...) mode
+fn parse_mode(spec: &str, current: u32) -> Option<u32> {
+    if !spec.is_empty() && spec.chars().all(|c| c.is_digit(8)) {
+        return u32::from_str_radix(spec, 8).ok().filter(|m| *m <= 0o7777);
+    }
+
+    let mut mode = current;
+    for clause in spec.split(',') {
+        let (who, rest) = clause.split_at(clause.find(|c| "+-=".contains(c))?);
+
+        let mut mask = 0;
+        for c in who.chars() {
+            mask |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return None,
+            };
+        }
+        if mask == 0 {
+            mask = 0o777;
+        }
+
+        let mut bits = 0;
+        for c in rest[1..].chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                // accepted but not emulated
+                's' | 't' | 'X' => 0,
+                _ => return None,
+            };
+        }
+        bits &= mask;
+
+        mode = match &rest[..1] {
+            "+" => mode | bits,
+            "-" => mode & !bits,
+            _ => (mode & !mask) | bits,
+        };
+    }
+
+    Some(mode)
+}
+
+fn chmod(session: &mut Session, args: &[String], out: &mut Output) {
+    // modes like -x would be parsed as flags
+    let args: Vec<String> = args
+        .iter()
+        .filter(|arg| arg.as_str() != "-R")
+        .cloned()
+        .collect();
+    if args.len() < 2 {
+        out.error("chmod: missing operand".to_owned());
+        return;
+    }
+
+    let spec = &args[0];
+    for operand in &args[1..] {
+        let path = vfs::join(&session.cwd, operand);
+        let current = match session.fs.get(&path) {
+            Ok((_, node)) => node.mode,
+            Err(e) => {
+                out.error(format!("chmod: {}: {}", operand, e));
+                continue;
+            }
+        };
+
+        match parse_mode(spec, current) {
+            Some(mode) => {
+                if let Err(e) = session.fs.chmod(&path, mode) {
+                    out.error(format!("chmod: {}: {}", operand, e));
+                }
+            }
+            None => {
+                out.error(format!("chmod: invalid mode '{}'", spec));
+                return;
+            }
+        }
+    }
+}
+
+fn uname(session: &mut Session, args: &[String], out: &mut Output) {
+    let (flags, _) = options(args);
+    let uname = &session.uname;
+    let all = flags.contains('a');
+
+    if let Some(invalid) = flags.chars().find(|c| !"asnrvmo".contains(*c)) {
+        out.error(format!("uname: invalid option -- '{}'", invalid));
+        return;
+    }
+
+    let mut fields = vec![];
+    for (flag, value) in &[
+        ('s', &uname.sysname),
+        ('n', &uname.nodename),
+        ('r', &uname.release),
+        ('v', &uname.version),
+        ('m', &uname.machine),
+    ] {
+        if all || flags.contains(*flag) {
+            fields.push(value.as_str());
+        }
+    }
+    if all || flags.contains('o') {
+        fields.push("GNU/Linux");
+    }
+    if fields.is_empty() {
+        fields.push(&uname.sysname);
+    }
+
+    out.print(&fields.join(" "));
+}
+
+fn id(session: &mut Session, out: &mut Output) {
+    let user = &session.user;
+    let group = session.group_name(user.gid);
+    out.print(&format!(
+        "uid={}({}) gid={}({}) groups={}({})",
+        user.uid, user.name, user.gid, group, user.gid, group
+    ));
+}
+
+// run the command if it's a builtin, returns None otherwise
+pub fn run(session: &mut Session, argv: &[String], stdin: &[u8]) -> Option<Output> {
+    let (mut name, mut args) = argv.split_first()?;
+    let mut out = Output::default();
+
+    // commands can be invoked by path or as busybox applets
+    while vfs::basename(name) == "busybox" {
+        let (applet, rest) = args.split_first()?;
+        name = applet;
+        args = rest;
+    }
+
+    match vfs::basename(name) {
+        "cd" => cd(session, args, &mut out),
+        "pwd" => out.print(&session.cwd),
+        "ls" => ls(session, args, &mut out),
+        "cat" => cat(session, args, stdin, &mut out),
+        "echo" => echo(args, &mut out),
+        "rm" => rm(session, args, &mut out),
+        "mkdir" => mkdir(session, args, &mut out),
+        "chmod" => chmod(session, args, &mut out),
+        "uname" => uname(session, args, &mut out),
+        "id" => id(session, &mut out),
+        "whoami" => out.print(&session.user.name),
+        "su" => su(session, args, &mut out),
+        _ => return None,
+    }
+
+    Some(out)
+}
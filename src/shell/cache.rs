@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::handler::Output;
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+fn default_max_entries() -> usize {
+    DEFAULT_MAX_ENTRIES
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // never cache
+    None,
+    // outputs are only reused within the same client session
+    Session,
+    // outputs are shared by every session of the service
+    Global,
+}
+
+// how the output of a handler is cached
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Policy {
+    pub scope: Scope,
+    // seconds after which an output expires, never if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    // number of outputs after which the oldest ones are evicted
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            scope: Scope::Session,
+            ttl: None,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+impl Policy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// bounded map of handler outputs
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    entries: HashMap<String, (Instant, Output)>,
+    // insertion order, oldest first
+    order: VecDeque<String>,
+}
+
+impl Cache {
+    fn is_expired(policy: &Policy, created: Instant) -> bool {
+        match policy.ttl {
+            Some(ttl) => created.elapsed() >= Duration::from_secs(ttl),
+            None => false,
+        }
+    }
+
+    pub fn get(&mut self, policy: &Policy, key: &str) -> Option<Output> {
+        let expired = match self.entries.get(key) {
+            Some((created, data)) if !Self::is_expired(policy, *created) => {
+                return Some(data.to_owned())
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            self.entries.remove(key);
+            self.order.retain(|k| k != key);
+        }
+
+        None
+    }
+
+    pub fn insert(&mut self, policy: &Policy, key: String, data: Output) {
+        if policy.max_entries == 0 {
+            return;
+        }
+
+        if self.entries.remove(&key).is_some() {
+            self.order.retain(|k| *k != key);
+        }
+
+        // drop expired outputs first, then the oldest ones
+        while let Some(oldest) = self.order.front() {
+            let expired = self
+                .entries
+                .get(oldest)
+                .map(|(created, _)| Self::is_expired(policy, *created))
+                .unwrap_or(true);
+            if !expired && self.entries.len() < policy.max_entries {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(key.clone());
+        self.entries.insert(key, (Instant::now(), data));
+    }
+}
@@ -0,0 +1,823 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::Service;
+
+use super::handler::CommandHandler;
+
+// bundled command trees
+const IOS: &str = include_str!("cli/ios.yml");
+const ROUTEROS: &str = include_str!("cli/routeros.yml");
+const VRP: &str = include_str!("cli/vrp.yml");
+
+// failed attempts before the enable password is denied
+const MAX_PASSWORD_ATTEMPTS: usize = 3;
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    // raise the privilege level to the argument, or 15, asking for the enable password
+    // if configured
+    Enable,
+    // back to the initial privilege level
+    Disable,
+    // leave the current mode, or the session from the initial one
+    Exit,
+    // leave the current mode, if any
+    Back,
+    // back to the initial mode
+    End,
+    Logout,
+    // set the hostname to the last argument (the part after = if any)
+    Hostname,
+}
+
+// keyword or argument of a command
+#[derive(Clone, Deserialize, Debug)]
+pub struct Node {
+    // keyword, or WORD, LINE (the rest of the line), A.B.C.D and <min-max> arguments
+    command: String,
+    #[serde(default)]
+    help: String,
+    // minimum privilege level for the node to be visible
+    #[serde(default)]
+    privilege: u8,
+    // handler printing the output of the command, with the arguments in {$1}
+    #[serde(default)]
+    output: Option<String>,
+    // mode entered by the command
+    #[serde(default)]
+    mode: Option<String>,
+    // the subcommands are the ones of this mode
+    #[serde(default)]
+    menu: Option<String>,
+    #[serde(default)]
+    action: Option<Action>,
+    #[serde(default)]
+    commands: Vec<Node>,
+    #[serde(skip)]
+    handler: Option<CommandHandler>,
+}
+
+impl Node {
+    fn keyword(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+            help: String::new(),
+            privilege: 0,
+            output: None,
+            mode: None,
+            menu: None,
+            action: None,
+            commands: vec![],
+            handler: None,
+        }
+    }
+
+    fn is_argument(&self) -> bool {
+        self.command.starts_with('<')
+            || (self.command.chars().any(|c| c.is_ascii_uppercase())
+                && !self.command.chars().any(|c| c.is_ascii_lowercase()))
+    }
+
+    fn is_line(&self) -> bool {
+        self.command == "LINE"
+    }
+
+    fn accepts(&self, word: &str) -> bool {
+        if let Some(range) = self
+            .command
+            .strip_prefix('<')
+            .and_then(|s| s.strip_suffix('>'))
+        {
+            let bounds: Vec<u64> = range.split('-').filter_map(|n| n.parse().ok()).collect();
+            return match (word.parse::<u64>(), bounds.as_slice()) {
+                (Ok(n), [min, max]) => n >= *min && n <= *max,
+                _ => false,
+            };
+        }
+
+        match self.command.as_str() {
+            "A.B.C.D" => word.parse::<Ipv4Addr>().is_ok(),
+            _ => true,
+        }
+    }
+
+    // a node with subcommands can only be executed if it does something by itself
+    fn is_runnable(&self) -> bool {
+        (self.commands.is_empty() && self.menu.is_none())
+            || self.output.is_some()
+            || self.mode.is_some()
+            || self.action.is_some()
+    }
+
+    fn compile(&mut self) -> Result<(), String> {
+        if let Some(output) = &self.output {
+            self.handler = Some(CommandHandler::new(
+                r"^(?s)(.*)$".to_owned(),
+                output.to_owned(),
+            )?);
+        }
+        for node in &mut self.commands {
+            node.compile()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Mode {
+    name: String,
+    // with {hostname}, {user}, {sigil} and {arg}, the last argument of the command that
+    // entered the mode
+    prompt: String,
+    // every mode but the first one has a parent
+    #[serde(default)]
+    parent: Option<String>,
+    // the commands of the parent are available too and leave this mode, like in the
+    // IOS configuration submodes
+    #[serde(default)]
+    inherit: bool,
+    #[serde(default)]
+    commands: Vec<Node>,
+}
+
+// error messages, with {marker} (a caret under the offending word), {command} (the word)
+// and {column}
+#[derive(Clone, Deserialize, Debug)]
+pub struct Errors {
+    invalid: String,
+    // for an unknown first word in the initial mode, invalid if not set
+    #[serde(default)]
+    unknown: Option<String>,
+    ambiguous: String,
+    incomplete: String,
+    // wrong enable password
+    denied: String,
+}
+
+fn default_hostname() -> String {
+    "Router".to_owned()
+}
+
+fn default_privilege() -> u8 {
+    1
+}
+
+fn default_password_prompt() -> String {
+    "Password: ".to_owned()
+}
+
+// definition of the CLI of a network device
+#[derive(Clone, Deserialize, Debug)]
+pub struct Cli {
+    #[serde(default = "default_hostname")]
+    hostname: String,
+    // initial privilege level
+    #[serde(default = "default_privilege")]
+    privilege: u8,
+    // enable passwords, * accepts anything, not asked if empty
+    #[serde(default)]
+    enable_passwords: Vec<String>,
+    #[serde(default = "default_password_prompt")]
+    password_prompt: String,
+    // prompt sigils above privilege level 1 and for the others
+    #[serde(default)]
+    sigils: (String, String),
+    // commands starting with / are relative to the initial mode, like in RouterOS
+    #[serde(default)]
+    absolute: bool,
+    // output filters like | include
+    #[serde(default)]
+    pipes: bool,
+    errors: Errors,
+    // the first one is the initial mode
+    modes: Vec<Mode>,
+}
+
+// cli section of the service configuration, either a preset name or a preset (or a
+// definition file) with overrides
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Config {
+    Preset(String),
+    Custom {
+        #[serde(default)]
+        preset: Option<String>,
+        // definition file, relative to the service file
+        #[serde(default)]
+        file: Option<String>,
+        #[serde(default)]
+        hostname: Option<String>,
+        #[serde(default)]
+        privilege: Option<u8>,
+        #[serde(default)]
+        enable_passwords: Option<Vec<String>>,
+        // outputs of the commands of the initial mode by their full keywords, missing
+        // commands are added
+        #[serde(default)]
+        outputs: BTreeMap<String, String>,
+    },
+}
+
+// per session state of the CLI
+pub struct State {
+    // modes entered with their arguments, the initial one first
+    modes: Vec<(usize, String)>,
+    pub privilege: u8,
+    pub hostname: String,
+    // privilege level waiting for the enable password and the attempts so far
+    password: Option<(u8, usize)>,
+}
+
+impl State {
+    // true while the enable password is being asked
+    pub fn is_secret(&self) -> bool {
+        self.password.is_some()
+    }
+}
+
+// why a command couldn't be resolved, with the offset of the offending word
+#[derive(Debug)]
+pub enum Failure {
+    Invalid(usize),
+    Unknown(usize),
+    Ambiguous(usize),
+    Incomplete(usize),
+}
+
+// command resolved from a command line
+struct Resolved<'a> {
+    node: &'a Node,
+    // depth of the mode the command belongs to, lower for inherited commands
+    depth: usize,
+    args: Vec<String>,
+    // the command with its keywords in full
+    words: Vec<String>,
+}
+
+// what the execution of a command line produced
+pub struct Executed<'a> {
+    // the command with its keywords in full
+    pub text: String,
+    // handler of the output and its arguments
+    pub handler: Option<&'a CommandHandler>,
+    pub args: String,
+    // the session should be closed
+    pub exit: bool,
+}
+
+// words of a command line with their offsets
+fn words(line: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push((s, &line[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, &line[s..]));
+    }
+    words
+}
+
+// find the node a word refers to among the visible ones: exact keywords first, then
+// unique abbreviations and arguments
+fn find<'a>(nodes: &'a [Node], word: &str, privilege: u8) -> Result<Option<&'a Node>, ()> {
+    let visible = || nodes.iter().filter(|node| node.privilege <= privilege);
+    let word = word.to_lowercase();
+
+    let keywords: Vec<&Node> = visible().filter(|node| !node.is_argument()).collect();
+    if let Some(node) = keywords
+        .iter()
+        .find(|node| node.command.to_lowercase() == word)
+    {
+        return Ok(Some(node));
+    }
+
+    let abbreviated: Vec<&Node> = keywords
+        .into_iter()
+        .filter(|node| node.command.to_lowercase().starts_with(&word))
+        .collect();
+    match abbreviated.len() {
+        0 => Ok(visible().find(|node| node.is_argument() && node.accepts(&word))),
+        1 => Ok(Some(abbreviated[0])),
+        _ => Err(()),
+    }
+}
+
+// the node of a keyword, added if missing
+fn child<'a>(nodes: &'a mut Vec<Node>, word: &str) -> &'a mut Node {
+    match nodes.iter().position(|node| node.command == word) {
+        Some(index) => &mut nodes[index],
+        None => {
+            nodes.push(Node::keyword(word));
+            nodes.last_mut().unwrap()
+        }
+    }
+}
+
+fn preset(name: &str) -> Result<Cli, String> {
+    let definition = match name {
+        "ios" => IOS,
+        "routeros" => ROUTEROS,
+        "vrp" => VRP,
+        _ => return Err(format!("unknown cli preset '{}'", name)),
+    };
+    serde_yaml::from_str(definition).map_err(|e| format!("invalid cli preset '{}': {}", name, e))
+}
+
+impl Cli {
+    pub fn from_config(config: Config, svc: &Service) -> Result<Self, String> {
+        let mut cli = match config {
+            Config::Preset(name) => preset(&name)?,
+            Config::Custom {
+                preset: base,
+                file,
+                hostname,
+                privilege,
+                enable_passwords,
+                outputs,
+            } => {
+                let mut cli = match (base, file) {
+                    (_, Some(file)) => {
+                        let path = svc.relative(&file);
+                        let data = fs::read_to_string(&path)
+                            .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+                        serde_yaml::from_str(&data)
+                            .map_err(|e| format!("invalid cli definition {:?}: {}", path, e))?
+                    }
+                    (Some(base), None) => preset(&base)?,
+                    (None, None) => preset("ios")?,
+                };
+
+                if let Some(hostname) = hostname {
+                    cli.hostname = hostname;
+                }
+                if let Some(privilege) = privilege {
+                    cli.privilege = privilege;
+                }
+                if let Some(passwords) = enable_passwords {
+                    cli.enable_passwords = passwords;
+                }
+                for (command, output) in outputs {
+                    cli.set_output(&command, output)?;
+                }
+
+                cli
+            }
+        };
+
+        cli.validate()?;
+        for mode in &mut cli.modes {
+            for node in &mut mode.commands {
+                node.compile()?;
+            }
+        }
+
+        Ok(cli)
+    }
+
+    // set the output of a command of the initial mode, adding it if needed
+    fn set_output(&mut self, command: &str, output: String) -> Result<(), String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let (last, path) = words
+            .split_last()
+            .ok_or_else(|| "empty command in cli outputs".to_owned())?;
+        let mut nodes = match self.modes.first_mut() {
+            Some(mode) => &mut mode.commands,
+            None => return Err("cli definition without modes".to_owned()),
+        };
+        for word in path {
+            nodes = &mut child(nodes, word).commands;
+        }
+        child(nodes, last).output = Some(output);
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.modes.is_empty() {
+            return Err("cli definition without modes".to_owned());
+        }
+
+        for (index, mode) in self.modes.iter().enumerate() {
+            match &mode.parent {
+                Some(parent) if self.mode(parent).is_none() => {
+                    return Err(format!("unknown parent mode '{}'", parent))
+                }
+                None if index > 0 => return Err(format!("mode '{}' has no parent", mode.name)),
+                _ => {}
+            }
+            if self.chain(index).len() > self.modes.len() {
+                return Err(format!("mode '{}' is its own parent", mode.name));
+            }
+
+            let mut nodes: Vec<&Node> = mode.commands.iter().collect();
+            while let Some(node) = nodes.pop() {
+                for name in node.mode.iter().chain(node.menu.iter()) {
+                    if self.mode(name).is_none() {
+                        return Err(format!("unknown mode '{}' in '{}'", name, node.command));
+                    }
+                }
+                nodes.extend(node.commands.iter());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mode(&self, name: &str) -> Option<usize> {
+        self.modes.iter().position(|mode| mode.name == name)
+    }
+
+    // the mode and its parents, the initial one first
+    fn chain(&self, mut index: usize) -> Vec<usize> {
+        let mut chain = vec![index];
+        while let Some(parent) = self.modes[index]
+            .parent
+            .as_ref()
+            .and_then(|name| self.mode(name))
+        {
+            // a cycle, reported by validate
+            if chain.len() > self.modes.len() {
+                break;
+            }
+            chain.push(parent);
+            index = parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    pub fn state(&self) -> State {
+        State {
+            modes: vec![(0, String::new())],
+            privilege: self.privilege,
+            hostname: self.hostname.to_owned(),
+            password: None,
+        }
+    }
+
+    pub fn has_pipes(&self) -> bool {
+        self.pipes
+    }
+
+    pub fn prompt(&self, state: &State, user: &str) -> String {
+        if state.password.is_some() {
+            return self.password_prompt.to_owned();
+        }
+
+        let (index, arg) = state.modes.last().unwrap();
+        let (privileged, unprivileged) = &self.sigils;
+        self.modes[*index]
+            .prompt
+            .replace("{hostname}", &state.hostname)
+            .replace("{user}", user)
+            .replace("{arg}", arg)
+            .replace(
+                "{sigil}",
+                if state.privilege > 1 {
+                    privileged
+                } else {
+                    unprivileged
+                },
+            )
+    }
+
+    fn subcommands<'a>(&'a self, node: &'a Node) -> &'a [Node] {
+        match &node.menu {
+            Some(menu) if node.commands.is_empty() => self
+                .mode(menu)
+                .map(|index| self.modes[index].commands.as_slice())
+                .unwrap_or_default(),
+            _ => &node.commands,
+        }
+    }
+
+    // modes the first word is looked up in, by stack depth
+    fn levels(&self, state: &State, absolute: bool) -> Vec<usize> {
+        if absolute {
+            return vec![0];
+        }
+        let mut levels = vec![];
+        for depth in (0..state.modes.len()).rev() {
+            levels.push(depth);
+            if !self.modes[state.modes[depth].0].inherit {
+                break;
+            }
+        }
+        levels
+    }
+
+    // follow the words of a command line down the tree of commands
+    fn walk<'a>(
+        &'a self,
+        state: &State,
+        line: &str,
+        words: &[(usize, &str)],
+    ) -> Result<Resolved<'a>, Failure> {
+        let (offset, first) = words[0];
+        let (first, absolute) = match first.strip_prefix('/') {
+            Some(stripped) if self.absolute && !stripped.is_empty() => (stripped, true),
+            _ => (first, false),
+        };
+
+        let mut resolved = None;
+        for depth in self.levels(state, absolute) {
+            let commands = &self.modes[state.modes[depth].0].commands;
+            match find(commands, first, state.privilege) {
+                Ok(Some(node)) => {
+                    resolved = Some(Resolved {
+                        node,
+                        // absolute commands don't leave the current mode
+                        depth: if absolute {
+                            state.modes.len() - 1
+                        } else {
+                            depth
+                        },
+                        args: vec![],
+                        words: vec![],
+                    });
+                    break;
+                }
+                Ok(None) => {}
+                Err(()) => return Err(Failure::Ambiguous(offset)),
+            }
+        }
+        let mut resolved = match resolved {
+            Some(resolved) => resolved,
+            None if state.modes.len() == 1 && self.errors.unknown.is_some() => {
+                return Err(Failure::Unknown(offset))
+            }
+            None => return Err(Failure::Invalid(offset)),
+        };
+
+        let mut rest = &words[1..];
+        let mut word = (offset, first);
+        loop {
+            let node = resolved.node;
+            if node.is_line() {
+                let value = line[word.0..].trim_end().to_owned();
+                resolved.words.push(value.to_owned());
+                resolved.args.push(value);
+                return Ok(resolved);
+            }
+            if node.is_argument() {
+                resolved.args.push(word.1.to_owned());
+                resolved.words.push(word.1.to_owned());
+            } else {
+                resolved.words.push(node.command.to_owned());
+            }
+
+            word = match rest.split_first() {
+                Some((next, tail)) => {
+                    rest = tail;
+                    *next
+                }
+                None => return Ok(resolved),
+            };
+            resolved.node = match find(self.subcommands(node), word.1, state.privilege) {
+                Ok(Some(node)) => node,
+                Ok(None) => return Err(Failure::Invalid(word.0)),
+                Err(()) => return Err(Failure::Ambiguous(word.0)),
+            };
+        }
+    }
+
+    // switch to a mode, keeping the modes of the stack it's nested in
+    fn enter(&self, state: &mut State, mode: usize, arg: String) {
+        let chain = self.chain(mode);
+        let last = chain.len() - 1;
+        state.modes = chain
+            .into_iter()
+            .enumerate()
+            .map(|(depth, index)| {
+                let arg = match state.modes.get(depth) {
+                    _ if depth == last => arg.to_owned(),
+                    Some((entered, arg)) if *entered == index => arg.to_owned(),
+                    _ => String::new(),
+                };
+                (index, arg)
+            })
+            .collect();
+    }
+
+    // execute a command line, changing the state of the session as needed
+    pub fn execute<'a>(&'a self, state: &mut State, line: &str) -> Result<Executed<'a>, Failure> {
+        let mut executed = Executed {
+            text: line.to_owned(),
+            handler: None,
+            args: String::new(),
+            exit: false,
+        };
+
+        let words = words(line);
+        if words.is_empty() {
+            return Ok(executed);
+        }
+        if self.absolute && line == "/" {
+            state.modes.truncate(1);
+            return Ok(executed);
+        }
+
+        let resolved = self.walk(state, line, &words)?;
+        let node = resolved.node;
+        if !node.is_runnable() {
+            return Err(Failure::Incomplete(line.len()));
+        }
+
+        // inherited commands leave the current mode
+        state.modes.truncate(resolved.depth + 1);
+
+        executed.text = resolved.words.join(" ");
+        executed.args = resolved.args.join(" ");
+        executed.handler = node.handler.as_ref();
+
+        if let Some(mode) = node.mode.as_ref().and_then(|name| self.mode(name)) {
+            let arg = resolved.args.last().cloned().unwrap_or_default();
+            self.enter(state, mode, arg);
+        }
+
+        match node.action {
+            Some(Action::Enable) => {
+                let level = resolved
+                    .args
+                    .last()
+                    .and_then(|arg| arg.parse().ok())
+                    .unwrap_or(15);
+                if self.enable_passwords.is_empty() || level <= state.privilege {
+                    state.privilege = level;
+                } else {
+                    state.password = Some((level, 0));
+                }
+            }
+            Some(Action::Disable) => state.privilege = self.privilege,
+            Some(Action::Exit) if state.modes.len() == 1 => executed.exit = true,
+            Some(Action::Exit) | Some(Action::Back) if state.modes.len() > 1 => {
+                state.modes.pop();
+            }
+            Some(Action::End) => state.modes.truncate(1),
+            Some(Action::Logout) => executed.exit = true,
+            Some(Action::Hostname) => {
+                if let Some(arg) = resolved.args.last() {
+                    let name = arg.rsplit('=').next().unwrap_or(arg);
+                    state.hostname = name.trim_matches('"').to_owned();
+                }
+            }
+            _ => {}
+        }
+
+        Ok(executed)
+    }
+
+    // check the enable password being asked, returning the error if denied
+    pub fn password(&self, state: &mut State, password: &str) -> Result<bool, String> {
+        let (level, attempts) = match state.password.take() {
+            Some(pending) => pending,
+            None => return Ok(false),
+        };
+
+        if self
+            .enable_passwords
+            .iter()
+            .any(|p| p == "*" || p == password)
+        {
+            state.privilege = level;
+            return Ok(true);
+        }
+
+        if attempts + 1 < MAX_PASSWORD_ATTEMPTS {
+            state.password = Some((level, attempts + 1));
+            Ok(false)
+        } else {
+            Err(self.errors.denied.to_owned())
+        }
+    }
+
+    // context help for a command line ending with ?
+    pub fn help(&self, state: &State, line: &str) -> Result<String, Failure> {
+        let text = line.strip_suffix('?').unwrap_or(line);
+        let mut words = words(text);
+        let partial = match text.chars().last() {
+            Some(c) if !c.is_whitespace() => words.pop(),
+            _ => None,
+        };
+
+        let (nodes, runnable): (Vec<&Node>, bool) = if words.is_empty() {
+            let nodes = self
+                .levels(state, false)
+                .into_iter()
+                .flat_map(|depth| self.modes[state.modes[depth].0].commands.iter())
+                .collect();
+            (nodes, false)
+        } else {
+            let resolved = self.walk(state, text, &words)?;
+            (
+                self.subcommands(resolved.node).iter().collect(),
+                resolved.node.is_runnable(),
+            )
+        };
+        let visible = nodes
+            .into_iter()
+            .filter(|node| node.privilege <= state.privilege);
+
+        if let Some((offset, partial)) = partial {
+            let partial = partial.to_lowercase();
+            let matching: Vec<&str> = visible
+                .filter(|node| {
+                    !node.is_argument() && node.command.to_lowercase().starts_with(&partial)
+                })
+                .map(|node| node.command.as_str())
+                .collect();
+            if matching.is_empty() {
+                return Err(Failure::Invalid(offset));
+            }
+            return Ok(matching.join("  "));
+        }
+
+        let mut entries: Vec<(&str, &str)> = visible
+            .map(|node| (node.command.as_str(), node.help.as_str()))
+            .collect();
+        if runnable {
+            entries.push(("<cr>", ""));
+        }
+        let width = entries.iter().map(|(c, _)| c.len()).max().unwrap_or(0);
+
+        Ok(entries
+            .into_iter()
+            .map(|(command, help)| format!("  {:<w$}  {}", command, help, w = width))
+            .map(|entry| entry.trim_end().to_owned())
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    // error message for a command line, column is where the line starts on the terminal
+    pub fn error(&self, failure: Failure, line: &str, column: usize) -> String {
+        let (template, offset) = match failure {
+            Failure::Invalid(offset) => (&self.errors.invalid, offset),
+            Failure::Unknown(offset) => (self.errors.unknown.as_ref().unwrap(), offset),
+            Failure::Ambiguous(offset) => (&self.errors.ambiguous, offset),
+            Failure::Incomplete(offset) => (&self.errors.incomplete, offset),
+        };
+        let position = line[..offset.min(line.len())].chars().count();
+        let word = line[offset.min(line.len())..]
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+
+        template
+            .replace("{marker}", &format!("{}^", " ".repeat(column + position)))
+            .replace("{column}", &(position + 1).to_string())
+            .replace("{command}", if word.is_empty() { line } else { word })
+    }
+
+    // apply an output filter like "include REGEX", if valid
+    pub fn filter(&self, output: &str, pipe: &str) -> Option<String> {
+        let pipe = pipe.trim();
+        let (name, pattern) = match pipe.split_once(char::is_whitespace) {
+            Some((name, pattern)) => (name, pattern.trim()),
+            None => (pipe, ""),
+        };
+        let filter = ["begin", "count", "exclude", "include", "section"]
+            .iter()
+            .find(|filter| !name.is_empty() && filter.starts_with(&name.to_lowercase()))?;
+        let regex = Regex::new(pattern).ok()?;
+
+        let lines = output.lines();
+        let filtered: Vec<&str> = match *filter {
+            "begin" => lines.skip_while(|line| !regex.is_match(line)).collect(),
+            "exclude" => lines.filter(|line| !regex.is_match(line)).collect(),
+            "include" => lines.filter(|line| regex.is_match(line)).collect(),
+            "count" => {
+                return Some(format!(
+                    "Number of lines which match regexp = {}",
+                    lines.filter(|line| regex.is_match(line)).count()
+                ))
+            }
+            // matching lines and the indented ones following them
+            _ => {
+                let mut section = false;
+                lines
+                    .filter(|line| {
+                        if !line.starts_with(' ') {
+                            section = regex.is_match(line);
+                        }
+                        section
+                    })
+                    .collect()
+            }
+        };
+
+        Some(filtered.join("\n"))
+    }
+}
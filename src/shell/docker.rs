@@ -1,137 +1,620 @@
 use std::collections::HashMap;
-use std::io::prelude::*;
-use std::os::unix::net::UnixStream;
+use std::env;
+use std::path::Path;
 use std::time::Duration;
 
+use async_trait::async_trait;
 use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    time::timeout,
+};
+
+use super::{
+    backend::{Backend, Container},
+    handler::Output,
+};
 
 const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+const TIMEOUT: Duration = Duration::from_secs(15);
+// max size of a response of the engine, including the output of a command
+const MAX_RESPONSE: u64 = 4 * 1024 * 1024;
+
+// label set on the containers created by medusa
+const LABEL: &str = "medusa";
+
+// decode a body sent with chunked transfer encoding
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = vec![];
+
+    loop {
+        let eol = data
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or("truncated chunk size")?;
+        let line = String::from_utf8_lossy(&data[..eol]);
+        // ignore chunk extensions
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|e| format!("invalid chunk size '{}': {}", size, e))?;
+        data = &data[eol + 2..];
+
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            return Err("truncated chunk".to_owned());
+        }
+
+        body.extend_from_slice(&data[..size]);
+        // skip the chunk trailing \r\n
+        data = data.get(size + 2..).unwrap_or(&[]);
+    }
+}
+
+// split the multiplexed stream of a non tty exec in stdout and stderr, every frame
+// has an 8 bytes header with the stream type and the big endian payload size
+fn demux(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut data = data;
+
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err("truncated frame header".to_owned());
+        }
+
+        let size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let payload = data[8..]
+            .get(..size)
+            .ok_or_else(|| format!("truncated frame of {} bytes", size))?;
+
+        match data[0] {
+            // stdin is only there in case of tty, but just in case
+            0 | 1 => stdout.extend_from_slice(payload),
+            2 => stderr.extend_from_slice(payload),
+            other => return Err(format!("unknown stream type {}", other)),
+        }
 
-fn do_request<S>(request: &str, stream: &mut S) -> Result<Vec<u8>, String>
-where
-    S: Read + Write,
-{
-    debug!("{}\n\n", request);
-    stream
-        .write_all(request.as_bytes())
-        .map_err(|e| format!("could not write to {}: {}", DOCKER_SOCKET, e))?;
+        data = &data[8..][size..];
+    }
 
-    let mut response = vec![];
-    stream
-        .read_to_end(&mut response)
-        .map_err(|e| format!("could not read from {}: {}", DOCKER_SOCKET, e))?;
+    Ok((stdout, stderr))
+}
 
-    debug!("{:?}", &response);
+// turn an error response into a message
+fn error(status: u16, body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct Error {
+        message: String,
+    }
 
-    Ok(response)
+    match serde_json::from_slice::<Error>(body) {
+        Ok(error) => format!("{} ({})", error.message, status),
+        Err(_) => format!("status {}: {}", status, String::from_utf8_lossy(body)),
+    }
 }
 
-fn create_exec(container_id: &str, command: &str) -> Result<String, String> {
-    debug!(
-        "creating exec operation for container {}: {}",
-        container_id, command
-    );
-
-    let mut stream = UnixStream::connect(DOCKER_SOCKET)
-        .map_err(|e| format!("could not connect to {}: {}", DOCKER_SOCKET, e))?;
-
-    stream
-        .set_read_timeout(Some(Duration::from_secs(15)))
-        .map_err(|e| format!("could not set read timeout for {}: {}", DOCKER_SOCKET, e))?;
-
-    stream
-        .set_write_timeout(Some(Duration::from_secs(15)))
-        .map_err(|e| format!("could not set write timeout for {}: {}", DOCKER_SOCKET, e))?;
-
-    let content = format!(
-        "{{
-		\"AttachStdout\": true,
-		\"AttachStderr\": true,
-		\"Tty\": false,
-		\"Cmd\": [ \"sh\", \"-c\", {}]
-	  }}",
-        serde_json::to_string(command).unwrap()
-    );
-
-    let request = format!("POST /containers/{}/exec HTTP/1.0\r\n", container_id)
-        + "Content-Type: application/json\r\n"
-        + &format!("Content-Length: {}\r\n", content.len())
-        + &format!("\r\n{}", content);
-
-    let response = do_request(&request, &mut stream)?;
-    let response = String::from_utf8_lossy(&response).to_string();
-
-    // split headers from response body
-    let response = format!("{{{}", response.split_once('{').unwrap().1);
-    // parse as generic hashmap
-    let response: HashMap<String, String> = serde_json::from_str(&response).unwrap();
-    // get exec id
-    if let Some(exec_id) = response.get("Id") {
-        debug!("exec_id = '{}'", &exec_id);
-        return Ok(exec_id.to_owned());
-    }
-
-    Err(format!("{:?}", response))
+// client of the docker engine api, also exposed by podman
+pub struct Engine {
+    socket: String,
 }
 
-fn do_exec(exec_id: &str) -> Result<Vec<u8>, String> {
-    debug!("dispatching exec operation {}", exec_id);
+impl Engine {
+    pub fn docker() -> Self {
+        Self {
+            socket: DOCKER_SOCKET.to_owned(),
+        }
+    }
+
+    pub fn podman() -> Self {
+        // prefer the rootless socket of the current user, if any
+        let socket = env::var("XDG_RUNTIME_DIR")
+            .map(|dir| format!("{}/podman/podman.sock", dir))
+            .ok()
+            .filter(|socket| Path::new(socket).exists())
+            .unwrap_or_else(|| PODMAN_SOCKET.to_owned());
+
+        Self { socket }
+    }
+
+    async fn do_request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<Vec<u8>, String> {
+        let content = body.unwrap_or_default();
+        let request = format!("{} {} HTTP/1.1\r\n", method, path)
+            + "Host: docker\r\n"
+            + "Connection: close\r\n"
+            + "Content-Type: application/json\r\n"
+            + format!("Content-Length: {}\r\n", content.len()).as_str()
+            + format!("\r\n{}", content).as_str();
+
+        debug!("{}\n\n", request);
+
+        let mut stream = UnixStream::connect(&self.socket)
+            .await
+            .map_err(|e| format!("could not connect to {}: {}", self.socket, e))?;
+
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| format!("could not write to {}: {}", self.socket, e))?;
 
-    let mut stream = UnixStream::connect(DOCKER_SOCKET)
-        .map_err(|e| format!("could not connect to {}: {}", DOCKER_SOCKET, e))?;
+        let mut response = vec![];
+        stream
+            .take(MAX_RESPONSE + 1)
+            .read_to_end(&mut response)
+            .await
+            .map_err(|e| format!("could not read from {}: {}", self.socket, e))?;
+        if response.len() as u64 > MAX_RESPONSE {
+            return Err(format!(
+                "response from {} is bigger than {} bytes",
+                self.socket, MAX_RESPONSE
+            ));
+        }
 
-    stream
-        .set_read_timeout(Some(Duration::from_secs(15)))
-        .map_err(|e| format!("could not set read timeout for {}: {}", DOCKER_SOCKET, e))?;
+        debug!("{:?}", &response);
+
+        Ok(response)
+    }
+
+    // send a request to the docker engine api, returning the status code and the body
+    async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<(u16, Vec<u8>), String> {
+        let response = timeout(TIMEOUT, self.do_request(method, path, body))
+            .await
+            .map_err(|_| format!("{} {} timed out", method, path))??;
+
+        // split headers from response body
+        let pattern = b"\r\n\r\n";
+        let idx = response
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+            .ok_or_else(|| format!("invalid response from {}", self.socket))?;
+
+        let headers = String::from_utf8_lossy(&response[..idx]).to_lowercase();
+        let status = headers
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("invalid status line from {}", self.socket))?;
+
+        let body = &response[idx + pattern.len()..];
+        let chunked = headers
+            .lines()
+            .any(|header| header.starts_with("transfer-encoding:") && header.contains("chunked"));
+        let body = if chunked {
+            dechunk(body)?
+        } else {
+            body.to_vec()
+        };
+
+        Ok((status, body))
+    }
+
+    async fn create_exec(
+        &self,
+        container_id: &str,
+        content: serde_json::Value,
+    ) -> Result<String, String> {
+        debug!(
+            "creating exec operation for container {}: {}",
+            container_id, content["Cmd"]
+        );
+
+        let (status, body) = self
+            .request(
+                "POST",
+                &format!("/containers/{}/exec", container_id),
+                Some(content.to_string()),
+            )
+            .await?;
+        if status != 201 {
+            return Err(error(status, &body));
+        }
+
+        // parse as generic hashmap
+        let response: HashMap<String, serde_json::Value> = serde_json::from_slice(&body)
+            .map_err(|e| format!("could not parse exec response: {}", e))?;
+        // get exec id
+        match response.get("Id").and_then(|id| id.as_str()) {
+            Some(exec_id) => {
+                debug!("exec_id = '{}'", exec_id);
+                Ok(exec_id.to_owned())
+            }
+            None => Err(format!("{:?}", response)),
+        }
+    }
+
+    async fn do_exec(&self, exec_id: &str) -> Result<Output, String> {
+        debug!("dispatching exec operation {}", exec_id);
+
+        let content = json!({
+            "Detach": false,
+            "Tty": false,
+        });
+
+        let (status, body) = self
+            .request(
+                "POST",
+                &format!("/exec/{}/start", exec_id),
+                Some(content.to_string()),
+            )
+            .await?;
+
+        debug!("cmd response = {:?}", String::from_utf8_lossy(&body));
+
+        if status != 200 {
+            return Err(error(status, &body));
+        }
+
+        let (stdout, stderr) = demux(&body)?;
+
+        Ok(Output {
+            stdout,
+            stderr,
+            status: self.exit_code(exec_id).await.unwrap_or(0),
+            ..Output::default()
+        })
+    }
+
+    // get the exit code of a completed exec operation
+    async fn exit_code(&self, exec_id: &str) -> Result<i32, String> {
+        let (status, body) = self
+            .request("GET", &format!("/exec/{}/json", exec_id), None)
+            .await?;
+        if status != 200 {
+            return Err(error(status, &body));
+        }
+
+        let response: serde_json::Value = serde_json::from_slice(&body)
+            .map_err(|e| format!("could not parse exec inspect response: {}", e))?;
+        response["ExitCode"]
+            .as_i64()
+            .map(|code| code as i32)
+            .ok_or_else(|| format!("no exit code for exec {}", exec_id))
+    }
 
-    stream
-        .set_write_timeout(Some(Duration::from_secs(15)))
-        .map_err(|e| format!("could not set write timeout for {}: {}", DOCKER_SOCKET, e))?;
+    // start an interactive exec with a terminal, returning its id and the stream connected
+    // to the terminal
+    pub async fn attach(
+        &self,
+        container_id: &str,
+        command: &[String],
+        width: u32,
+        height: u32,
+    ) -> Result<(String, BufReader<UnixStream>), String> {
+        let content = json!({
+            "AttachStdin": true,
+            "AttachStdout": true,
+            "AttachStderr": true,
+            "Tty": true,
+            "Env": ["TERM=xterm"],
+            "Cmd": command,
+        });
+        let exec_id = self.create_exec(container_id, content).await?;
 
-    let content = "{
-			\"Detach\": false,
-			\"Tty\": false
-		  }";
-    let request = format!("POST /exec/{}/start HTTP/1.0\r\n", exec_id)
-        + "Content-Type: application/json\r\n"
-        + &format!("Content-Length: {}\r\n", content.len())
-        + &format!("\r\n{}", content);
+        debug!("attaching to exec operation {}", &exec_id);
 
-    let response = do_request(&request, &mut stream)?;
+        let content = json!({
+            "Detach": false,
+            "Tty": true,
+            "ConsoleSize": [height, width],
+        })
+        .to_string();
+        // the connection is hijacked and becomes the terminal once upgraded
+        let request = format!("POST /exec/{}/start HTTP/1.1\r\n", exec_id)
+            + "Host: docker\r\n"
+            + "Connection: Upgrade\r\n"
+            + "Upgrade: tcp\r\n"
+            + "Content-Type: application/json\r\n"
+            + format!("Content-Length: {}\r\n", content.len()).as_str()
+            + format!("\r\n{}", content).as_str();
 
-    debug!("cmd response = {:?}", String::from_utf8_lossy(&response));
+        let stream = timeout(TIMEOUT, async {
+            let mut stream = UnixStream::connect(&self.socket)
+                .await
+                .map_err(|e| format!("could not connect to {}: {}", self.socket, e))?;
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .map_err(|e| format!("could not write to {}: {}", self.socket, e))?;
 
-    // split headers and response body
-    let pattern = "\r\n\r\n".as_bytes();
-    let idx = response
-        .windows(pattern.len())
-        .position(|window| window == pattern)
-        .unwrap()
-        + pattern.len();
+            // consume the headers only, anything after them is terminal output
+            let mut stream = BufReader::new(stream);
+            let mut status = String::new();
+            stream
+                .read_line(&mut status)
+                .await
+                .map_err(|e| format!("could not read from {}: {}", self.socket, e))?;
+            loop {
+                let mut header = String::new();
+                let n = stream
+                    .read_line(&mut header)
+                    .await
+                    .map_err(|e| format!("could not read from {}: {}", self.socket, e))?;
+                if n == 0 || header.trim().is_empty() {
+                    break;
+                }
+            }
 
-    Ok(response[idx..].to_vec())
+            match status.split_whitespace().nth(1) {
+                Some("101") | Some("200") => Ok(stream),
+                _ => Err(format!("could not attach to exec: {}", status.trim())),
+            }
+        })
+        .await
+        .map_err(|_| format!("attaching to exec {} timed out", &exec_id))??;
+
+        Ok((exec_id, stream))
+    }
+
+    // resize the terminal of an interactive exec
+    pub async fn resize(&self, exec_id: &str, width: u32, height: u32) -> Result<(), String> {
+        let (status, body) = self
+            .request(
+                "POST",
+                &format!("/exec/{}/resize?h={}&w={}", exec_id, height, width),
+                None,
+            )
+            .await?;
+        if status != 200 && status != 201 {
+            return Err(error(status, &body));
+        }
+
+        Ok(())
+    }
+
+    // remove the containers created by medusa and left behind, returning how many were
+    // removed, if the engine is not running there's nothing to clean up
+    pub async fn cleanup(&self) -> Result<usize, String> {
+        if !Path::new(&self.socket).exists() {
+            return Ok(0);
+        }
+
+        // filters={"label":["medusa=ephemeral"]}
+        let (status, body) = self
+            .request(
+                "GET",
+                &format!(
+                    "/containers/json?all=true&filters=%7B%22label%22%3A%5B%22{}%3Dephemeral%22%5D%7D",
+                    LABEL
+                ),
+                None,
+            )
+            .await?;
+        if status != 200 {
+            return Err(error(status, &body));
+        }
+
+        let response: Vec<serde_json::Value> = serde_json::from_slice(&body)
+            .map_err(|e| format!("could not parse containers list response: {}", e))?;
+        let mut removed = 0;
+        for id in response.iter().filter_map(|container| container["Id"].as_str()) {
+            self.remove(id).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl Backend for Engine {
+    async fn exec(&self, container_id: &str, command: &str) -> Result<Output, String> {
+        // HACK: since wget and curl don't have any timeout by default, force it.
+        let command = if command.contains("wget ") {
+            debug!("patching wget command with timeout");
+            command.replace("wget ", "wget --timeout 10 ")
+        } else if command.contains("curl ") {
+            debug!("patching curl command with timeout");
+            command.replace("curl ", "curl --connect-timeout 10 ")
+        } else {
+            command.to_owned()
+        };
+
+        debug!(
+            "running command '{}' inside container '{}'",
+            &command, container_id
+        );
+
+        // create exec operation
+        let content = json!({
+            "AttachStdout": true,
+            "AttachStderr": true,
+            "Tty": false,
+            "Cmd": ["sh", "-c", command],
+        });
+        let exec_id = self.create_exec(container_id, content).await?;
+
+        // start exec operation by id
+        self.do_exec(&exec_id).await
+    }
+
+    // create and start a container, returning its id
+    async fn create(&self, container: &Container) -> Result<String, String> {
+        debug!("creating container from image {}", &container.image);
+
+        let mut host = json!({
+            "NetworkMode": container.network,
+            "SecurityOpt": ["no-new-privileges"],
+        });
+        if let Some(memory) = container.memory {
+            // no swap on top of the memory limit
+            host["Memory"] = json!(memory * 1024 * 1024);
+            host["MemorySwap"] = json!(memory * 1024 * 1024);
+        }
+        if let Some(cpus) = container.cpus {
+            host["NanoCpus"] = json!((cpus * 1e9) as u64);
+        }
+        if let Some(pids) = container.pids {
+            host["PidsLimit"] = json!(pids);
+        }
+
+        let mut content = json!({
+            "Image": container.image,
+            "Cmd": container.command,
+            "Labels": { LABEL: "ephemeral" },
+            "NetworkDisabled": container.network == "none",
+            "HostConfig": host,
+        });
+        if let Some(hostname) = &container.hostname {
+            content["Hostname"] = json!(hostname);
+        }
+
+        let (status, body) = self
+            .request("POST", "/containers/create", Some(content.to_string()))
+            .await?;
+        if status != 201 {
+            return Err(error(status, &body));
+        }
+
+        let response: HashMap<String, serde_json::Value> = serde_json::from_slice(&body)
+            .map_err(|e| format!("could not parse create response: {}", e))?;
+        let id = match response.get("Id").and_then(|id| id.as_str()) {
+            Some(id) => id.to_owned(),
+            None => return Err(format!("{:?}", response)),
+        };
+
+        let (status, body) = self
+            .request("POST", &format!("/containers/{}/start", id), None)
+            .await?;
+        // 304 means already started
+        if status != 204 && status != 304 {
+            // don't leave it behind
+            let _ = self.remove(&id).await;
+            return Err(error(status, &body));
+        }
+
+        debug!("container {} started", &id);
+
+        Ok(id)
+    }
+
+    // kill and delete a container
+    async fn remove(&self, container_id: &str) -> Result<(), String> {
+        debug!("removing container {}", container_id);
+
+        let (status, body) = self
+            .request(
+                "DELETE",
+                &format!("/containers/{}?force=true&v=true", container_id),
+                None,
+            )
+            .await?;
+        if status != 204 && status != 404 {
+            return Err(error(status, &body));
+        }
+
+        Ok(())
+    }
 }
 
-pub fn exec(container_id: &str, command: &str) -> Result<Vec<u8>, String> {
-    // HACK: since wget and curl don't have any timeout by default, force it.
-    let command = if command.contains("wget ") {
-        debug!("patching wget command with timeout");
-        command.replace("wget ", "wget --timeout 10 ")
-    } else if command.contains("curl ") {
-        debug!("patching curl command with timeout");
-        command.replace("curl ", "curl --connect-timeout 10 ")
-    } else {
-        command.to_owned()
-    };
-
-    debug!(
-        "running command '{}' inside container '{}'",
-        &command, container_id
-    );
-
-    // create exec operation
-    let exec_id = create_exec(container_id, &command)?;
-
-    // start exec operation by id
-    do_exec(&exec_id)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(stream: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![stream, 0, 0, 0];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        assert_eq!(dechunk(b"0\r\n\r\n").unwrap(), b"");
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n").unwrap(),
+            b"hello, world"
+        );
+        // extensions, uppercase sizes and trailers
+        assert_eq!(
+            dechunk(b"A;name=value\r\n0123456789\r\n0\r\nExpires: never\r\n\r\n").unwrap(),
+            b"0123456789"
+        );
+        // the chunks can contain line breaks
+        assert_eq!(dechunk(b"4\r\n\r\n\r\n\r\n0\r\n").unwrap(), b"\r\n\r\n");
+    }
+
+    #[test]
+    fn malformed_chunked_bodies() {
+        assert_eq!(dechunk(b""), Err("truncated chunk size".to_owned()));
+        assert_eq!(dechunk(b"5"), Err("truncated chunk size".to_owned()));
+        // missing last chunk
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n"),
+            Err("truncated chunk size".to_owned())
+        );
+        assert_eq!(dechunk(b"5\r\nhel"), Err("truncated chunk".to_owned()));
+        assert_eq!(dechunk(b"5\r\nhello"), Err("truncated chunk size".to_owned()));
+
+        for size in &["", "x", "-1", "0x5", "5 5", "ffffffffffffffffffff"] {
+            let body = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+            assert!(
+                dechunk(body.as_bytes()).unwrap_err().starts_with("invalid chunk size"),
+                "{:?}",
+                size
+            );
+        }
+
+        // bigger than anything that could have been received
+        assert_eq!(
+            dechunk(b"ffffffffffffffff\r\nhello\r\n0\r\n\r\n"),
+            Err("truncated chunk".to_owned())
+        );
+    }
+
+    #[test]
+    fn multiplexed_streams() {
+        assert_eq!(demux(b"").unwrap(), (vec![], vec![]));
+
+        let mut data = frame(1, b"uid=0(root)");
+        data.extend(frame(2, b"warning\n"));
+        data.extend(frame(1, b" gid=0(root)\n"));
+        data.extend(frame(0, b""));
+        assert_eq!(
+            demux(&data).unwrap(),
+            (b"uid=0(root) gid=0(root)\n".to_vec(), b"warning\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn malformed_multiplexed_streams() {
+        assert_eq!(
+            demux(&[1, 0, 0, 0]),
+            Err("truncated frame header".to_owned())
+        );
+
+        let mut data = frame(1, b"hello");
+        data.extend_from_slice(&[2, 0, 0]);
+        assert_eq!(demux(&data), Err("truncated frame header".to_owned()));
+
+        let mut data = frame(1, b"hello");
+        data.pop();
+        assert_eq!(demux(&data), Err("truncated frame of 5 bytes".to_owned()));
+
+        let mut data = frame(1, b"hello");
+        data[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            demux(&data),
+            Err(format!("truncated frame of {} bytes", u32::MAX))
+        );
+
+        assert_eq!(
+            demux(&frame(3, b"hello")),
+            Err("unknown stream type 3".to_owned())
+        );
+    }
 }
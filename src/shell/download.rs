@@ -0,0 +1,286 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use reqwest::Url;
+use serde::Serialize;
+
+use super::vfs;
+
+// a payload download requested by the client
+#[derive(Clone, Debug, Serialize)]
+pub struct Download {
+    pub tool: String,
+    pub url: String,
+    pub host: String,
+    pub port: u16,
+    // remote file name
+    pub filename: String,
+    // local path the payload would be saved to, None if written to stdout
+    #[serde(skip)]
+    pub output: Option<String>,
+    #[serde(skip)]
+    quiet: bool,
+}
+
+impl Download {
+    fn new(tool: &str, url: &str, default_port: u16) -> Option<Self> {
+        let url = if url.contains("://") {
+            url.to_owned()
+        } else {
+            format!("http://{}", url)
+        };
+        let parsed = Url::parse(&url).ok()?;
+        let host = parsed.host_str()?.to_owned();
+        let port = parsed.port_or_known_default().unwrap_or(default_port);
+        let filename = parsed
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("index.html")
+            .to_owned();
+
+        Some(Self {
+            tool: tool.to_owned(),
+            url,
+            host,
+            port,
+            filename,
+            output: None,
+            quiet: false,
+        })
+    }
+
+    // fake but stable payload size
+    pub fn size(&self) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.url.hash(&mut hasher);
+        8192 + (hasher.finish() % 57344) as usize
+    }
+
+    // plausible output of a successful download, as (stdout, stderr)
+    pub fn output(&self) -> (String, String) {
+        if self.quiet || self.output.is_none() {
+            return (String::new(), String::new());
+        }
+
+        let size = self.size();
+        let name = vfs::basename(self.output.as_ref().unwrap()).to_owned();
+        let stderr = match self.tool.as_str() {
+            "wget" => format!(
+                "Connecting to {host}:{port} ({host}:{port})\nsaving to '{name}'\n{name:<20} 100% |********************************| {size:>6}  0:00:00 ETA\n'{name}' saved\n",
+                host = self.host,
+                port = self.port,
+                name = name,
+                size = size
+            ),
+            "curl" => format!(
+                "  % Total    % Received % Xferd  Average Speed   Time    Time     Time  Current\n                                 Dload  Upload   Total   Spent    Left  Speed\n100 {size:>5}  100 {size:>5}    0     0  {speed:>5}      0 --:--:-- --:--:-- --:--:-- {speed:>5}\n",
+                size = size,
+                speed = size * 3
+            ),
+            _ => String::new(),
+        };
+
+        (String::new(), stderr)
+    }
+}
+
+// split single letter options, some of which take a value, from operands
+fn getopt<'a>(
+    args: &'a [String],
+    with_value: &str,
+    long_with_value: &[&str],
+) -> (Vec<(String, &'a str)>, Vec<&'a str>) {
+    let mut options = vec![];
+    let mut operands = vec![];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if let Some(long) = arg.strip_prefix("--") {
+            match long.split_once('=') {
+                Some((name, value)) => options.push((name.to_owned(), value)),
+                None if long_with_value.contains(&long) => options.push((
+                    long.to_owned(),
+                    args.next().map(|v| v.as_str()).unwrap_or(""),
+                )),
+                None => options.push((long.to_owned(), "")),
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, flag) in arg[1..].char_indices() {
+                if with_value.contains(flag) {
+                    let value = &arg[1 + i + flag.len_utf8()..];
+                    let value = if value.is_empty() {
+                        args.next().map(|v| v.as_str()).unwrap_or("")
+                    } else {
+                        value
+                    };
+                    options.push((flag.to_string(), value));
+                    break;
+                }
+                options.push((flag.to_string(), ""));
+            }
+        } else {
+            operands.push(arg.as_str());
+        }
+    }
+
+    (options, operands)
+}
+
+fn wget(args: &[String]) -> Option<Download> {
+    let (options, operands) = getopt(
+        args,
+        "OPTUYteo",
+        &[
+            "output-document",
+            "directory-prefix",
+            "user-agent",
+            "header",
+            "post-data",
+            "timeout",
+            "tries",
+        ],
+    );
+    let mut download = Download::new("wget", operands.first()?, 80)?;
+    let mut folder = None;
+    let mut output = Some(download.filename.to_owned());
+
+    for (name, value) in options {
+        match name.as_str() {
+            "q" | "quiet" => download.quiet = true,
+            "O" | "output-document" if value == "-" => output = None,
+            "O" | "output-document" => output = Some(value.to_owned()),
+            "P" | "directory-prefix" => folder = Some(value),
+            _ => {}
+        }
+    }
+
+    download.output = match folder {
+        Some(folder) => output.map(|output| format!("{}/{}", folder, output)),
+        None => output,
+    };
+    Some(download)
+}
+
+fn curl(args: &[String]) -> Option<Download> {
+    let (options, operands) = getopt(
+        args,
+        "oAHdxumXebcrTwKCEyYz",
+        &[
+            "output",
+            "user-agent",
+            "header",
+            "data",
+            "proxy",
+            "user",
+            "max-time",
+            "request",
+        ],
+    );
+    let mut download = Download::new("curl", operands.first()?, 80)?;
+
+    for (name, value) in options {
+        match name.as_str() {
+            "s" | "silent" => download.quiet = true,
+            "o" | "output" if value != "-" => download.output = Some(value.to_owned()),
+            "O" | "remote-name" => download.output = Some(download.filename.to_owned()),
+            _ => {}
+        }
+    }
+
+    Some(download)
+}
+
+fn tftp(args: &[String]) -> Option<Download> {
+    let (options, operands) = getopt(args, "lrbc", &[]);
+    let mut remote = None;
+    let mut local = None;
+
+    for (name, value) in &options {
+        match name.as_str() {
+            "r" => remote = Some(*value),
+            "l" => local = Some(*value),
+            // tftp-hpa: tftp HOST -c get FILE [LOCAL]
+            "c" if value.eq_ignore_ascii_case("get") => {
+                remote = operands.get(1).copied();
+                local = operands.get(2).copied();
+            }
+            _ => {}
+        }
+    }
+
+    // windows: tftp -i HOST GET FILE [LOCAL]
+    if remote.is_none() && operands.len() >= 3 && operands[1].eq_ignore_ascii_case("get") {
+        remote = Some(operands[2]);
+        local = operands.get(3).copied();
+    }
+
+    let remote = remote.or(local)?;
+    let host = match operands.get(1) {
+        Some(port) if port.parse::<u16>().is_ok() => format!("{}:{}", operands[0], port),
+        _ => operands.first()?.to_string(),
+    };
+
+    let mut download = Download::new(
+        "tftp",
+        &format!("tftp://{}/{}", host, remote.trim_start_matches('/')),
+        69,
+    )?;
+    download.output = Some(local.unwrap_or(&download.filename).to_owned());
+    Some(download)
+}
+
+fn ftpget(args: &[String]) -> Option<Download> {
+    let (options, operands) = getopt(args, "upP", &["username", "password", "port"]);
+    let mut credentials = String::new();
+    let mut port = 21;
+
+    for (name, value) in &options {
+        match name.as_str() {
+            "u" | "username" => credentials = format!("{}@", value),
+            "P" | "port" => port = value.parse().unwrap_or(21),
+            _ => {}
+        }
+    }
+    if let Some((_, password)) = options.iter().find(|(n, _)| n == "p" || n == "password") {
+        credentials = format!("{}:{}@", credentials.trim_end_matches('@'), password);
+    }
+
+    // ftpget HOST LOCAL_FILE [REMOTE_FILE]
+    let host = operands.first()?;
+    let local = operands.get(1)?;
+    let remote = operands.get(2).unwrap_or(local);
+
+    let mut download = Download::new(
+        "ftpget",
+        &format!(
+            "ftp://{}{}:{}/{}",
+            credentials,
+            host,
+            port,
+            remote.trim_start_matches('/')
+        ),
+        21,
+    )?;
+    download.output = Some(local.to_string());
+    Some(download)
+}
+
+// recognise a download command and extract what's being downloaded
+pub fn parse(argv: &[String]) -> Option<Download> {
+    let (mut name, mut args) = argv.split_first()?;
+    // busybox applets
+    while vfs::basename(name) == "busybox" {
+        let (applet, rest) = args.split_first()?;
+        name = applet;
+        args = rest;
+    }
+
+    match vfs::basename(name) {
+        "wget" => wget(args),
+        "curl" => curl(args),
+        "tftp" => tftp(args),
+        "ftpget" => ftpget(args),
+        _ => None,
+    }
+}
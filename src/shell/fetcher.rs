@@ -0,0 +1,291 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use reqwest::{redirect, Url};
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+use crate::record::Record;
+
+use super::download::Download;
+
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 10;
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+fn default_max_size() -> usize {
+    DEFAULT_MAX_SIZE
+}
+
+// fetcher section of the service configuration
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Config {
+    // append the downloads to a JSON lines file for a separate process to collect
+    Queue {
+        path: String,
+    },
+    // fetch the payloads through a local http proxy
+    Proxy {
+        proxy: String,
+        // folder the payloads are saved to, if any
+        #[serde(default)]
+        output: Option<String>,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+        // bytes, bigger payloads are not fetched
+        #[serde(default = "default_max_size")]
+        max_size: usize,
+    },
+}
+
+// hands the URLs requested by clients to whatever collects the payloads
+pub trait Fetcher: Send + Sync {
+    fn fetch(&self, log: &Record, download: &Download);
+}
+
+#[derive(Serialize)]
+struct QueueEntry<'a> {
+    time: String,
+    service: &'a str,
+    address: &'a str,
+    #[serde(flatten)]
+    download: &'a Download,
+}
+
+struct Queue {
+    path: PathBuf,
+    // serialize writes from concurrent sessions
+    lock: Mutex<()>,
+}
+
+impl Fetcher for Queue {
+    fn fetch(&self, log: &Record, download: &Download) {
+        let entry = QueueEntry {
+            time: Utc::now().to_rfc3339(),
+            service: &log.service,
+            address: &log.address,
+            download,
+        };
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("could not serialize download: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let _lock = self.lock.lock().unwrap();
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = res {
+            error!("could not write to download queue {:?}: {}", self.path, e);
+        }
+    }
+}
+
+struct Proxy {
+    client: reqwest::Client,
+    output: Option<PathBuf>,
+    max_size: usize,
+}
+
+// clients choose the URLs, so they must not be able to reach the sensor itself or
+// its internal network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                || ip.is_unspecified()
+                || octets[0] == 0
+                // shared address space
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+                // ipv4 mapped
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public(IpAddr::from([a, b, c, d]));
+            }
+            !(ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unspecified()
+                // unique local
+                || (segments[0] & 0xfe00) == 0xfc00
+                // link local
+                || (segments[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+// address of a host that is an ip, either v4 or [v6]
+fn literal_address(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+// resolve the host of the URL and check that all of its addresses are public
+async fn check_destination(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("invalid url: {}", e))?;
+    let host = url.host_str().ok_or("url without host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addresses: Vec<IpAddr> = match literal_address(host) {
+        Some(ip) => vec![ip],
+        None => lookup_host((host, port))
+            .await
+            .map_err(|e| format!("could not resolve {}: {}", host, e))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+
+    if addresses.is_empty() {
+        return Err(format!("could not resolve {}", host));
+    }
+    match addresses.into_iter().find(|ip| !is_public(*ip)) {
+        Some(ip) => Err(format!("{} resolves to non public address {}", host, ip)),
+        None => Ok(()),
+    }
+}
+
+// download the body of the URL, up to max_size bytes
+async fn get(client: &reqwest::Client, url: &str, max_size: usize) -> Result<Vec<u8>, String> {
+    check_destination(url).await?;
+
+    let mut response = client.get(url).send().await.map_err(|e| e.to_string())?;
+    if let Some(size) = response.content_length() {
+        if size > max_size as u64 {
+            return Err(format!("{} bytes is more than the maximum size", size));
+        }
+    }
+
+    let mut data = vec![];
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if data.len() + chunk.len() > max_size {
+            return Err(format!("more than the maximum size of {} bytes", max_size));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+impl Fetcher for Proxy {
+    fn fetch(&self, _log: &Record, download: &Download) {
+        if !download.url.starts_with("http://") && !download.url.starts_with("https://") {
+            debug!("can't fetch {} via proxy", download.url);
+            return;
+        }
+
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("can't fetch {}: {}", download.url, e);
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        let output = self.output.clone();
+        let max_size = self.max_size;
+        let download = download.clone();
+
+        // don't block the session while downloading
+        runtime.spawn(async move {
+            let data = match get(&client, &download.url, max_size).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("could not fetch {}: {}", download.url, e);
+                    return;
+                }
+            };
+
+            info!("fetched {} ({} bytes)", download.url, data.len());
+
+            if let Some(folder) = output {
+                let folder = folder.join(&download.host);
+                let path = folder.join(format!(
+                    "{}-{}",
+                    Utc::now().format("%Y%m%d%H%M%S%.f"),
+                    download.filename
+                ));
+                if let Err(e) = fs::create_dir_all(&folder).and_then(|_| fs::write(&path, &data)) {
+                    error!("could not save {:?}: {}", path, e);
+                }
+            }
+        });
+    }
+}
+
+pub fn from_config(config: Config) -> Result<Box<dyn Fetcher>, String> {
+    match config {
+        Config::Queue { path } => Ok(Box::new(Queue {
+            path: PathBuf::from(path),
+            lock: Mutex::new(()),
+        })),
+        Config::Proxy {
+            proxy,
+            output,
+            timeout,
+            max_size,
+        } => {
+            let client = reqwest::Client::builder()
+                .proxy(
+                    reqwest::Proxy::all(&proxy)
+                        .map_err(|e| format!("invalid fetcher proxy '{}': {}", proxy, e))?,
+                )
+                .timeout(Duration::from_secs(timeout))
+                // the destination of a redirect can't be resolved here, so only the
+                // literal addresses are checked
+                .redirect(redirect::Policy::custom(|attempt| {
+                    let allowed = match attempt.url().host_str() {
+                        Some(host) => match literal_address(host) {
+                            Some(ip) => is_public(ip),
+                            None => !host.eq_ignore_ascii_case("localhost"),
+                        },
+                        None => false,
+                    };
+                    if !allowed {
+                        let message = format!("redirect to {} is not allowed", attempt.url());
+                        attempt.error(message)
+                    } else if attempt.previous().len() >= MAX_REDIRECTS {
+                        attempt.error("too many redirects")
+                    } else {
+                        attempt.follow()
+                    }
+                }))
+                .danger_accept_invalid_certs(true)
+                .build()
+                .map_err(|e| format!("could not create fetcher client: {}", e))?;
+
+            Ok(Box::new(Proxy {
+                client,
+                output: output.map(PathBuf::from),
+                max_size,
+            }))
+        }
+    }
+}
@@ -1,26 +1,166 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use lazy_static::lazy_static;
 use log::{debug, error};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use super::docker;
+use crate::config::Service;
+
+use super::backend::{self, Backend, Container};
+use super::cache::{Cache, Policy, Scope};
+use super::pacing::{Chunk, Pacing};
+use super::script::{self, Files, Limits};
+use super::template;
 
 pub const EXIT_HANDLER_TOKEN: &str = "@exit";
 
 lazy_static! {
-    static ref DOCKER_HANDLER_PARSER: Regex = Regex::new(r"^@docker\s+([^\s]+)\s+(.+)$").unwrap();
+    static ref JAIL_HANDLER_PARSER: Regex =
+        Regex::new(r"^@(docker|podman|sandbox)\s+([^\s]+)\s+(.+)$").unwrap();
+    static ref SCRIPT_HANDLER_PARSER: Regex = Regex::new(r"^@script\s+(.+)$").unwrap();
+}
+
+// output of a command, either from a handler or a builtin
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: i32,
+    // how the output should be sent to the client, all at once if not set
+    pub pacing: Option<Pacing>,
+}
+
+impl Output {
+    pub(super) fn text(text: &str) -> Self {
+        Self {
+            stdout: text.as_bytes().to_owned(),
+            ..Self::default()
+        }
+    }
+
+    pub(super) fn print(&mut self, line: &str) {
+        self.stdout.extend_from_slice(line.as_bytes());
+        self.stdout.push(b'\n');
+    }
+
+    pub fn error(&mut self, line: String) {
+        self.stderr.extend_from_slice(line.as_bytes());
+        self.stderr.push(b'\n');
+        self.status = 1;
+    }
+
+    // what a client without redirections would receive
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut data = self.stdout;
+        data.extend(self.stderr);
+        data
+    }
+
+    // same as into_bytes, split according to the pacing
+    pub fn into_chunks(mut self) -> Vec<Chunk> {
+        let pacing = self.pacing.take();
+        let data = self.into_bytes();
+        match pacing {
+            Some(pacing) => pacing.chunks(&data),
+            None => vec![Chunk::now(data)],
+        }
+    }
+}
+
+// per client session state of the handlers
+#[derive(Default)]
+pub struct Context {
+    // outputs of the handlers with a session cache scope
+    cache: Cache,
+    // jails created for this session by backend and name
+    jails: HashMap<(String, String), (&'static dyn Backend, String)>,
+    // variables available to the handler templates
+    vars: HashMap<String, String>,
+    // state kept by the scripts between commands
+    state: HashMap<PathBuf, rhai::Map>,
+}
+
+impl Context {
+    pub fn for_address(service_name: &str, address: SocketAddr) -> Self {
+        let mut context = Self::default();
+        context.set("service", service_name);
+        context.set("client_ip", &address.ip().to_string());
+        context.set("client_port", &address.port().to_string());
+        context
+    }
+
+    // set a variable for the handler templates
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_owned(), value.to_owned());
+    }
+
+    // get the id of the session jail with the given name, creating it if needed
+    async fn jail(
+        &mut self,
+        kind: &str,
+        backend: &'static dyn Backend,
+        name: &str,
+        config: &Container,
+    ) -> Result<String, String> {
+        let key = (kind.to_owned(), name.to_owned());
+        if let Some((_, id)) = self.jails.get(&key) {
+            return Ok(id.to_owned());
+        }
+
+        let id = backend.create(config).await?;
+        self.jails.insert(key, (backend, id.to_owned()));
+        Ok(id)
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.jails.is_empty() {
+            return;
+        }
+
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("can't remove {} jails: {}", self.jails.len(), e);
+                return;
+            }
+        };
+
+        for (_, (backend, id)) in self.jails.drain() {
+            runtime.spawn(async move {
+                if let Err(e) = backend.remove(&id).await {
+                    error!("could not remove jail {}: {}", id, e);
+                }
+            });
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct CommandHandler {
     parser: String,
     handler: String,
+    #[serde(default, skip_serializing_if = "Policy::is_default")]
+    cache: Policy,
+    // if set, jailed commands run in a new container for every session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    container: Option<Container>,
+    // limits of @script handlers
+    #[serde(default, skip_serializing_if = "Limits::is_default")]
+    script: Limits,
+    // delays and streaming of the output
+    #[serde(default, skip_serializing_if = "Pacing::is_default")]
+    timing: Pacing,
     #[serde(skip)]
     compiled: Option<Regex>,
+    // outputs shared by all sessions, if the cache scope is global
     #[serde(skip)]
-    cache: HashMap<String, Vec<u8>>,
+    global: Arc<Mutex<Cache>>,
 }
 
 impl CommandHandler {
@@ -31,28 +171,71 @@ impl CommandHandler {
         Ok(Self {
             parser,
             handler,
+            cache: Policy::default(),
+            container: None,
+            script: Limits::default(),
+            timing: Pacing::default(),
             compiled,
-            cache: HashMap::new(),
+            global: Arc::new(Mutex::new(Cache::default())),
         })
     }
 
-    fn handle_with_captures(&self, captures: &regex::Captures) -> String {
-        // substitute {{$N}} tokens with matches
-        let mut handler = self.handler.to_owned();
-        let num = captures.len();
-        for n in 1..num {
-            let token = format!("{{${}}}", n).to_string();
-            if handler.contains(&token) {
-                handler = handler.replace(&token, &captures[n]);
-            }
+    fn handle_with_captures(
+        &self,
+        captures: &regex::Captures,
+        folder: &Path,
+        context: &mut Context,
+    ) -> Result<String, String> {
+        template::Scope {
+            captures,
+            vars: &mut context.vars,
+            folder,
+        }
+        .render(&self.handler, 0)
+    }
+
+    fn cached(&self, key: &str, context: &mut Context) -> Option<Output> {
+        match self.cache.scope {
+            Scope::None => None,
+            Scope::Session => context.cache.get(&self.cache, key),
+            Scope::Global => self.global.lock().unwrap().get(&self.cache, key),
+        }
+    }
+
+    fn store(&self, key: String, data: Output, context: &mut Context) {
+        match self.cache.scope {
+            Scope::None => {}
+            Scope::Session => context.cache.insert(&self.cache, key, data),
+            Scope::Global => self.global.lock().unwrap().insert(&self.cache, key, data),
         }
-        handler
     }
 
-    // TODO: this should return a Result<Option<Vec<u8>>, Error>
-    pub fn parse(&mut self, command: &str) -> Option<Vec<u8>> {
+    pub fn compile(&mut self) -> Result<(), String> {
+        if self.compiled.is_none() {
+            self.compiled = Some(
+                Regex::new(&self.parser)
+                    .map_err(|e| format!("can't compile regex '{}': {}", &self.parser, e))?,
+            );
+        }
+        Ok(())
+    }
+
+    // check the container options against the backend the handler uses
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(container), Some(exec)) =
+            (&self.container, JAIL_HANDLER_PARSER.captures(&self.handler))
+        {
+            container
+                .validate(&exec[1])
+                .map_err(|e| format!("handler '{}': {}", &self.handler, e))?;
+        }
+        Ok(())
+    }
+
+    // check if this handler is the one for the command
+    pub fn matches(&mut self, command: &str) -> bool {
         if command == EXIT_HANDLER_TOKEN {
-            return Some(command.as_bytes().to_owned());
+            return true;
         }
 
         if self.compiled.is_none() {
@@ -62,38 +245,176 @@ impl CommandHandler {
             );
         }
 
-        // check command for matches
-        if let Some(captures) = self.compiled.as_ref().unwrap().captures(command) {
-            let handler = self.handle_with_captures(&captures);
+        self.compiled.as_ref().unwrap().is_match(command)
+    }
+
+    // TODO: this should return a Result<Output, Error>
+    pub async fn handle(
+        &self,
+        command: &str,
+        folder: &Path,
+        context: &mut Context,
+        files: Option<Files<'_>>,
+    ) -> Output {
+        if command == EXIT_HANDLER_TOKEN {
+            return Output::text(command);
+        }
+
+        let (parser, captures) = match self
+            .compiled
+            .as_ref()
+            .and_then(|re| re.captures(command).map(|captures| (re, captures)))
+        {
+            Some(matched) => matched,
+            None => return Output::default(),
+        };
 
-            // check cache first
-            if let Some(out) = self.cache.get(&handler) {
-                debug!("'{}' from cache: {}", &handler, out.len());
-                return Some(out.to_owned());
+        let handler = match self.handle_with_captures(&captures, folder, context) {
+            Ok(handler) => handler,
+            Err(e) => {
+                error!("error rendering handler '{}': {}", &self.handler, e);
+                return Output::default();
             }
+        };
 
-            // docker exec?
-            if let Some(exec) = DOCKER_HANDLER_PARSER.captures(&handler) {
-                let (container_id, command) = (&exec[1], &exec[2]);
-                match docker::exec(container_id, command) {
-                    Ok(data) => {
-                        debug!("docker_exec('{}') -> {:?}", command, data);
-                        self.cache.insert(handler, data.clone());
-                        return Some(data);
-                    }
+        // scripts can keep state, so their output is never cached
+        if let Some(exec) = SCRIPT_HANDLER_PARSER.captures(&handler) {
+            let path = folder.join(exec[1].trim());
+            let scope = script::scope(command, parser, &captures, &context.vars);
+            let state = context.state.entry(path.clone()).or_default();
+
+            return match script::run(&path, &self.script, scope, state, files).await {
+                Ok(output) => {
+                    debug!("script({:?}, '{}') -> {:?}", &path, command, output);
+                    output
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    Output::default()
+                }
+            };
+        }
+
+        // check cache first
+        if let Some(out) = self.cached(&handler, context) {
+            debug!(
+                "'{}' from cache: {}",
+                &handler,
+                out.stdout.len() + out.stderr.len()
+            );
+            return out;
+        }
+
+        // docker, podman or sandbox exec?
+        if let Some(exec) = JAIL_HANDLER_PARSER.captures(&handler) {
+            let (kind, name, command) = (&exec[1], &exec[2], &exec[3]);
+            let backend = match backend::get(kind) {
+                Some(backend) => backend,
+                None => return Output::default(),
+            };
+            let container_id = match &self.container {
+                Some(config) => match context.jail(kind, backend, name, config).await {
+                    Ok(id) => id,
                     Err(e) => {
                         error!(
-                            "error running '{}' inside container '{}': {}",
-                            command, container_id, e
+                            "could not create {} jail from {}: {}",
+                            kind, &config.image, e
                         );
-                        return Some("".as_bytes().to_owned());
+                        return Output::default();
                     }
+                },
+                None => name.to_owned(),
+            };
+
+            match backend.exec(&container_id, command).await {
+                Ok(output) => {
+                    debug!("{}_exec('{}') -> {:?}", kind, command, output);
+                    self.store(handler, output.clone(), context);
+                    return output;
+                }
+                Err(e) => {
+                    error!(
+                        "error running '{}' inside container '{}': {}",
+                        command, container_id, e
+                    );
+                    return Output::default();
                 }
             }
+        }
 
-            return Some(handler.as_bytes().to_owned());
+        Output::text(&handler)
+    }
+}
+
+// folder the files referenced by the handlers are relative to
+pub(super) fn folder(service: &Service) -> PathBuf {
+    service
+        .path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+pub(super) async fn run(
+    handler: &CommandHandler,
+    command: &str,
+    folder: &Path,
+    context: &mut Context,
+    files: Option<Files<'_>>,
+) -> Output {
+    let mut output = handler.handle(command, folder, context, files).await;
+    if !handler.timing.is_default() {
+        output.pacing = Some(handler.timing.clone());
+    }
+    output
+}
+
+// run the first handler of the service matching the command, if any
+pub async fn dispatch(
+    service: &Mutex<Service>,
+    command: &str,
+    context: &mut Context,
+    files: Option<Files<'_>>,
+) -> Option<Output> {
+    // don't keep the service locked while the handler runs
+    let (handler, folder) = {
+        let mut service = service.lock().unwrap();
+        let folder = folder(&service);
+        let handler = service.commands.iter_mut().find_map(|handler| {
+            if handler.matches(command) {
+                Some(handler.clone())
+            } else {
+                None
+            }
+        })?;
+        (handler, folder)
+    };
+
+    Some(run(&handler, command, &folder, context, files).await)
+}
+
+// run the matching handlers in order until one of them succeeds with some output
+pub async fn fallback(
+    service: &Mutex<Service>,
+    handlers: &[CommandHandler],
+    command: &str,
+    context: &mut Context,
+    mut files: Option<Files<'_>>,
+) -> Option<Output> {
+    let folder = folder(&service.lock().unwrap());
+
+    for handler in handlers {
+        let mut handler = handler.clone();
+        if !handler.matches(command) {
+            continue;
+        }
+
+        let files = files.as_mut().map(Files::reborrow);
+        let output = run(&handler, command, &folder, context, files).await;
+        if output.status == 0 && !(output.stdout.is_empty() && output.stderr.is_empty()) {
+            return Some(output);
         }
-        // this is not the handler you're looking for ...
-        None
     }
+
+    None
 }
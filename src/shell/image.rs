@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use log::debug;
+use serde::Deserialize;
+use tar::{Archive, EntryType};
+
+use super::vfs::{Filesystem, Node};
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct Uname {
+    pub sysname: String,
+    pub nodename: String,
+    pub release: String,
+    pub version: String,
+    pub machine: String,
+}
+
+impl Default for Uname {
+    fn default() -> Self {
+        Self {
+            sysname: "Linux".to_owned(),
+            nodename: "localhost".to_owned(),
+            release: "4.14.180".to_owned(),
+            version: "#1 SMP PREEMPT Mon Sep 5 14:12:09 UTC 2022".to_owned(),
+            machine: "armv7l".to_owned(),
+        }
+    }
+}
+
+// device image definition as found in the YAML file
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct Definition {
+    // optional root filesystem, relative to the image file
+    tarball: Option<String>,
+    uname: Uname,
+    directories: Vec<String>,
+    files: BTreeMap<String, String>,
+    symlinks: BTreeMap<String, String>,
+}
+
+// the initial state of the filesystem and system information every session starts from
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub filesystem: Filesystem,
+    pub uname: Uname,
+}
+
+// used when the image doesn't provide a tarball
+fn skeleton(uname: &Uname) -> Filesystem {
+    let mut fs = Filesystem::new();
+
+    for dir in &[
+        "/bin",
+        "/dev",
+        "/etc",
+        "/home",
+        "/lib",
+        "/mnt",
+        "/proc",
+        "/sbin",
+        "/sys",
+        "/usr/bin",
+        "/usr/sbin",
+        "/var/log",
+        "/var/run",
+    ] {
+        fs.insert(dir, Node::directory(0o755));
+    }
+    fs.insert("/tmp", Node::directory(0o1777));
+    fs.insert("/root", Node::directory(0o700));
+    fs.insert(
+        "/etc/passwd",
+        Node::file(b"root:x:0:0:root:/root:/bin/sh\n".to_vec(), 0o644),
+    );
+    fs.insert("/etc/group", Node::file(b"root:x:0:\n".to_vec(), 0o644));
+    fs.insert(
+        "/etc/hostname",
+        Node::file(format!("{}\n", uname.nodename).into_bytes(), 0o644),
+    );
+
+    fs
+}
+
+fn load_tarball(path: &Path) -> Result<Filesystem, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {:?}: {}", path, e))?;
+    let name = path.to_string_lossy();
+    let reader: Box<dyn Read> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut fs = Filesystem::new();
+    let mut archive = Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("could not read {:?}: {}", path, e))?;
+        let header = entry.header();
+        let name = entry
+            .path()
+            .map_err(|e| format!("invalid entry in {:?}: {}", path, e))?
+            .to_string_lossy()
+            .into_owned();
+        let mode = header.mode().unwrap_or(0o644);
+        let uid = header.uid().unwrap_or(0) as u32;
+        let gid = header.gid().unwrap_or(0) as u32;
+        let mtime = header.mtime().unwrap_or(0);
+
+        let node = match header.entry_type() {
+            EntryType::Directory => Node::directory(mode),
+            EntryType::Symlink => match entry.link_name() {
+                Ok(Some(target)) => Node::symlink(&target.to_string_lossy()),
+                _ => continue,
+            },
+            EntryType::Regular | EntryType::Continuous => {
+                let mut data = vec![];
+                entry
+                    .read_to_end(&mut data)
+                    .map_err(|e| format!("could not read {} from {:?}: {}", name, path, e))?;
+                Node::file(data, mode)
+            }
+            // hard links, devices, fifos, etc
+            other => {
+                debug!("skipping {} ({:?}) from {:?}", name, other, path);
+                continue;
+            }
+        };
+
+        fs.insert(&name, node.owned_by(uid, gid).modified_at(mtime));
+    }
+
+    Ok(fs)
+}
+
+fn load_definition(path: &Path) -> Result<Image, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    let def: Definition = serde_yaml::from_str(&data)
+        .map_err(|e| format!("could not parse image {:?}: {}", path, e))?;
+
+    let mut filesystem = match &def.tarball {
+        Some(tarball) => load_tarball(&path.parent().unwrap_or(path).join(tarball))?,
+        None => skeleton(&def.uname),
+    };
+
+    for dir in &def.directories {
+        filesystem.insert(dir, Node::directory(0o755));
+    }
+    for (file, contents) in def.files {
+        filesystem.insert(&file, Node::file(contents.into_bytes(), 0o644));
+    }
+    for (link, target) in &def.symlinks {
+        filesystem.insert(link, Node::symlink(target));
+    }
+
+    Ok(Image {
+        filesystem,
+        uname: def.uname,
+    })
+}
+
+pub fn load(path: &Path) -> Result<Image, String> {
+    let name = path.to_string_lossy();
+    let image = if name.ends_with(".yml") || name.ends_with(".yaml") {
+        load_definition(path)?
+    } else {
+        Image {
+            filesystem: load_tarball(path)?,
+            uname: Uname::default(),
+        }
+    };
+
+    debug!("loaded device image {:?}", path);
+
+    Ok(image)
+}
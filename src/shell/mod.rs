@@ -1,3 +1,105 @@
+use crate::config::Service;
+
+mod backend;
+mod builtins;
+mod cli;
 mod docker;
+mod download;
+mod fetcher;
+mod image;
+mod parser;
+mod personality;
+mod sandbox;
+mod script;
+mod template;
+mod vfs;
 
+pub mod cache;
 pub mod handler;
+pub mod pacing;
+pub mod session;
+pub mod tty;
+
+pub use backend::cleanup;
+
+// bytes a session can write to the emulated filesystem, unless configured
+const DEFAULT_FILESYSTEM_SIZE: u64 = 16 * 1024 * 1024;
+
+// per service shell emulation settings, shared by all the sessions
+pub struct Shell {
+    // initial state of the emulated device, builtins are disabled without one
+    image: Option<image::Image>,
+    // user the sessions are logged in as
+    user: String,
+    // bytes every session can write to its copy of the image
+    filesystem_size: usize,
+    // recognise download commands and fake their output
+    downloads: bool,
+    // where the URLs of the downloads are sent to
+    fetcher: Option<Box<dyn fetcher::Fetcher>>,
+    // attach the session to a terminal in a container instead
+    tty: Option<tty::Config>,
+    // error messages, prompt and fallback handlers
+    personality: personality::Personality,
+    // command modes of a network device instead of a unix shell
+    cli: Option<cli::Cli>,
+}
+
+impl Shell {
+    pub fn from_service(svc: &Service) -> Result<Self, String> {
+        let image = match svc.string("filesystem", "").as_str() {
+            "" => None,
+            file => Some(image::load(&svc.relative(file))?),
+        };
+        let user = svc.string("user", "root");
+        let filesystem_size = svc.unsigned("filesystem_size", DEFAULT_FILESYSTEM_SIZE) as usize;
+        let downloads = svc.bool("downloads", true);
+        let fetcher = match svc.config.get("fetcher") {
+            Some(value) => {
+                let config = serde_yaml::from_value(value.clone())
+                    .map_err(|e| format!("invalid fetcher configuration: {}", e))?;
+                Some(fetcher::from_config(config)?)
+            }
+            None => None,
+        };
+
+        let tty = match svc.config.get("tty") {
+            Some(value) => {
+                let config: tty::Config = serde_yaml::from_value(value.clone())
+                    .map_err(|e| format!("invalid tty configuration: {}", e))?;
+                config.validate()?;
+                Some(config)
+            }
+            None => None,
+        };
+
+        let personality = match svc.config.get("personality") {
+            Some(value) => {
+                let config = serde_yaml::from_value(value.clone())
+                    .map_err(|e| format!("invalid personality configuration: {}", e))?;
+                personality::Personality::from_config(config)?
+            }
+            None => personality::Personality::default(),
+        };
+
+        let cli = match svc.config.get("cli") {
+            Some(value) => {
+                let config = serde_yaml::from_value(value.clone())
+                    .map_err(|e| format!("invalid cli configuration: {}", e))?;
+                Some(cli::Cli::from_config(config, svc)?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            image,
+            user,
+            filesystem_size,
+            downloads,
+            fetcher,
+            tty,
+            personality,
+            cli,
+        })
+    }
+}
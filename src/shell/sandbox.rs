@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use rand::Rng;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStderr, ChildStdout, Command},
+    sync::Mutex as AsyncMutex,
+    time::timeout,
+};
+
+use super::{
+    backend::{Backend, Container},
+    handler::Output,
+};
+
+const BWRAP: &str = "bwrap";
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const TIMEOUT: Duration = Duration::from_secs(15);
+// megabytes of the /tmp tmpfs, unless configured
+const TMP_SIZE: u64 = 64;
+// bytes of stdout and stderr kept for every command
+const MAX_OUTPUT: usize = 1024 * 1024;
+// where the commands fifo is mounted inside a jail
+const COMMANDS: &str = "/dev/.medusa-commands";
+
+// runs the commands written to the fifo one at a time, each followed by the marker
+// preceding it and its exit status, so that the files in /tmp last as long as the jail
+const KEEPER: &str = r#"exec 3< /dev/.medusa-commands
+while IFS= read -r marker <&3 && IFS= read -r command <&3; do
+    command=$(printf '%b' "$command")
+    sh -c "$command" < /dev/null 3<&-
+    status=$?
+    printf '\n%s %d\n' "$marker" "$status"
+    printf '\n%s\n' "$marker" >&2
+done"#;
+
+// shell running the commands of a jail
+struct Keeper {
+    child: Child,
+    commands: tokio::fs::File,
+    stdout: ChildStdout,
+    stderr: ChildStderr,
+}
+
+// jail created for a session
+#[derive(Clone)]
+struct Jail {
+    container: Container,
+    // folder of the commands fifo
+    workdir: PathBuf,
+    // started again if it dies or a command times out, losing /tmp
+    keeper: Arc<AsyncMutex<Option<Keeper>>>,
+}
+
+// runs commands as local processes in their own namespaces via bubblewrap, on top of
+// a read-only root filesystem
+#[derive(Default)]
+pub struct Sandbox {
+    jails: Mutex<HashMap<String, Jail>>,
+    next: AtomicUsize,
+}
+
+// read up to MAX_OUTPUT bytes, the process is left with a broken pipe if it writes more
+async fn read_capped<R: AsyncRead + Unpin>(reader: R) -> Vec<u8> {
+    let mut data = vec![];
+    if let Err(e) = reader.take(MAX_OUTPUT as u64).read_to_end(&mut data).await {
+        debug!("could not read sandbox output: {}", e);
+    }
+    data
+}
+
+// read until the line with the marker, keeping up to MAX_OUTPUT bytes before it and
+// returning what follows the marker on its line
+async fn read_until_marker<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    marker: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut data = vec![];
+    let mut found = None;
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("could not read from sandbox: {}", e))?;
+        if n == 0 {
+            return Err("sandbox exited".to_owned());
+        }
+
+        // the marker can be split between reads
+        let from = data.len().saturating_sub(marker.len());
+        data.extend_from_slice(&buf[..n]);
+
+        if found.is_none() {
+            found = find(&data[from..], marker).map(|pos| pos + from);
+        }
+
+        match found {
+            Some(pos) => {
+                let rest = &data[pos + marker.len()..];
+                if let Some(end) = rest.iter().position(|b| *b == b'\n') {
+                    let tail = rest[..end].to_vec();
+                    data.truncate(pos.min(MAX_OUTPUT));
+                    return Ok((data, tail));
+                }
+            }
+            // drop what's over the limit, except what could be the beginning of the marker
+            None if data.len() > MAX_OUTPUT + marker.len() => {
+                let end = data.len() - marker.len();
+                data.drain(MAX_OUTPUT..end);
+            }
+            None => {}
+        }
+    }
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|w| w == pattern)
+}
+
+// bytes of the command as octal escapes for printf, so that it fits in a line
+fn encode(command: &str) -> String {
+    command
+        .bytes()
+        .filter(|b| *b != 0)
+        .map(|b| format!("\\0{:03o}", b))
+        .collect()
+}
+
+impl Sandbox {
+    // bubblewrap with the root filesystem and a size limited /tmp, returning what has
+    // to be its stdin
+    fn bwrap(rootfs: &str, container: Option<&Container>) -> Result<(Command, Stdio), String> {
+        if !Path::new(rootfs).is_dir() {
+            return Err(format!("{} is not a folder", rootfs));
+        }
+
+        let tmp_size = container.and_then(|c| c.tmp_size).unwrap_or(TMP_SIZE);
+
+        let mut cmd = Command::new(BWRAP);
+        cmd.args(["--ro-bind", rootfs, "/"])
+            .args(["--dev", "/dev", "--proc", "/proc"])
+            .args(["--unshare-all", "--die-with-parent", "--new-session"])
+            .arg("--size")
+            .arg((tmp_size * 1024 * 1024).to_string())
+            .args(["--tmpfs", "/tmp"]);
+
+        let mut stdin = Stdio::null();
+        if let Some(container) = container {
+            if container.network != "none" {
+                cmd.arg("--share-net");
+            }
+            if let Some(hostname) = &container.hostname {
+                cmd.args(["--hostname", hostname]);
+            }
+            if let Some(seccomp) = &container.seccomp {
+                // bwrap reads the filter from the given file descriptor
+                let filter = File::open(seccomp)
+                    .map_err(|e| format!("could not open {}: {}", seccomp, e))?;
+                stdin = Stdio::from(filter);
+                cmd.args(["--seccomp", "0"]);
+            }
+        }
+
+        cmd.args(["--chdir", "/"])
+            .env_clear()
+            .env("PATH", PATH)
+            .env("HOME", "/root")
+            .kill_on_drop(true);
+
+        Ok((cmd, stdin))
+    }
+
+    // run a command in a sandbox of its own
+    async fn run(rootfs: &str, command: &str) -> Result<Output, String> {
+        let (mut cmd, stdin) = Self::bwrap(rootfs, None)?;
+        let mut child = cmd
+            .args(["sh", "-c", command])
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", BWRAP, e))?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        timeout(TIMEOUT, async move {
+            let (stdout, stderr) = tokio::join!(read_capped(stdout), read_capped(stderr));
+            let status = child
+                .wait()
+                .await
+                .map_err(|e| format!("could not run {}: {}", BWRAP, e))?;
+            let status = status
+                .code()
+                .or_else(|| status.signal().map(|signal| 128 + signal))
+                .unwrap_or(1);
+
+            Ok(Output {
+                stdout,
+                stderr,
+                status,
+                ..Output::default()
+            })
+        })
+        .await
+        .map_err(|_| format!("'{}' timed out", command))?
+    }
+
+    fn start(jail: &Jail) -> Result<Keeper, String> {
+        let container = &jail.container;
+        let (mut cmd, stdin) = Self::bwrap(&container.image, Some(container))?;
+
+        // opened for writing and reading, so that neither end blocks
+        let fifo = jail.workdir.join("commands");
+        let commands = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fifo)
+            .map_err(|e| format!("could not open {:?}: {}", fifo, e))?;
+
+        let mut script = KEEPER.to_owned();
+        if let Some(memory) = container.memory {
+            script = format!("ulimit -v {} 2>/dev/null\n{}", memory * 1024, script);
+        }
+
+        let mut child = cmd
+            .arg("--ro-bind")
+            .arg(&fifo)
+            .arg(COMMANDS)
+            .args(["sh", "-c", &script])
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", BWRAP, e))?;
+
+        Ok(Keeper {
+            stdout: child.stdout.take().unwrap(),
+            stderr: child.stderr.take().unwrap(),
+            commands: tokio::fs::File::from_std(commands),
+            child,
+        })
+    }
+
+    async fn exec_in(id: &str, jail: &Jail, command: &str) -> Result<Output, String> {
+        let mut keeper = jail.keeper.lock().await;
+        if keeper.is_none() {
+            warn!("restarting sandbox {}, its /tmp is lost", id);
+            *keeper = Some(Self::start(jail)?);
+        }
+        let running = keeper.as_mut().unwrap();
+
+        // can't be guessed by the command to fake its end
+        let marker: String = format!("medusa-{:032x}", rand::thread_rng().gen::<u128>());
+        let request = format!("{}\n{}\n", marker, encode(command));
+        let stdout_marker = format!("\n{} ", marker);
+        let stderr_marker = format!("\n{}", marker);
+
+        let result = timeout(TIMEOUT, async {
+            running
+                .commands
+                .write_all(request.as_bytes())
+                .await
+                .map_err(|e| format!("could not write to sandbox: {}", e))?;
+            running
+                .commands
+                .flush()
+                .await
+                .map_err(|e| format!("could not write to sandbox: {}", e))?;
+
+            let (stdout, stderr) = tokio::join!(
+                read_until_marker(&mut running.stdout, stdout_marker.as_bytes()),
+                read_until_marker(&mut running.stderr, stderr_marker.as_bytes())
+            );
+            let (stdout, status) = stdout?;
+            let (stderr, _) = stderr?;
+
+            Ok::<_, String>(Output {
+                stdout,
+                stderr,
+                status: String::from_utf8_lossy(&status).trim().parse().unwrap_or(1),
+                ..Output::default()
+            })
+        })
+        .await
+        .unwrap_or_else(|_| Err(format!("'{}' timed out", command)));
+
+        // the jail can't be trusted to be in sync anymore
+        if result.is_err() {
+            if let Some(mut dead) = keeper.take() {
+                let _ = dead.child.start_kill();
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl Backend for Sandbox {
+    async fn exec(&self, jail: &str, command: &str) -> Result<Output, String> {
+        debug!("running command '{}' inside sandbox '{}'", command, jail);
+
+        // either a session jail or the root filesystem to use
+        let session = self.jails.lock().unwrap().get(jail).cloned();
+        match &session {
+            Some(session) => Self::exec_in(jail, session, command).await,
+            None => Self::run(jail, command).await,
+        }
+    }
+
+    async fn create(&self, container: &Container) -> Result<String, String> {
+        if !Path::new(&container.image).is_dir() {
+            return Err(format!("{} is not a folder", &container.image));
+        }
+
+        let id = format!(
+            "medusa-sandbox-{}-{}",
+            std::process::id(),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        );
+        let workdir = env::temp_dir().join(&id);
+        fs::create_dir_all(&workdir)
+            .map_err(|e| format!("could not create {:?}: {}", workdir, e))?;
+
+        let fifo = workdir.join("commands");
+        let status = Command::new("mkfifo")
+            .arg("-m")
+            .arg("600")
+            .arg(&fifo)
+            .status()
+            .await
+            .map_err(|e| format!("could not run mkfifo: {}", e))?;
+        if !status.success() {
+            let _ = fs::remove_dir_all(&workdir);
+            return Err(format!("could not create {:?}", fifo));
+        }
+
+        let jail = Jail {
+            container: container.clone(),
+            workdir,
+            keeper: Arc::new(AsyncMutex::new(None)),
+        };
+        let keeper = match Self::start(&jail) {
+            Ok(keeper) => keeper,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&jail.workdir);
+                return Err(e);
+            }
+        };
+        *jail.keeper.lock().await = Some(keeper);
+
+        debug!("created sandbox {} from {}", &id, &container.image);
+
+        self.jails.lock().unwrap().insert(id.to_owned(), jail);
+
+        Ok(id)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        let jail = self.jails.lock().unwrap().remove(id);
+        if let Some(jail) = jail {
+            debug!("removing sandbox {}", id);
+            if let Some(mut keeper) = jail.keeper.lock().await.take() {
+                let _ = keeper.child.kill().await;
+            }
+            fs::remove_dir_all(&jail.workdir)
+                .map_err(|e| format!("could not remove {:?}: {}", jail.workdir, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use lazy_static::lazy_static;
+use log::debug;
+use regex::{Captures, Regex};
+use rhai::{module_resolvers::DummyModuleResolver, Array, Dynamic, Engine, Map, Scope, AST};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    handler::Output,
+    vfs::{self, Filesystem},
+};
+
+lazy_static! {
+    // compiled scripts by path, along with the modification time of the file
+    static ref SCRIPTS: Mutex<HashMap<PathBuf, (SystemTime, Arc<AST>)>> = Mutex::new(HashMap::new());
+}
+
+fn default_timeout() -> u64 {
+    250
+}
+
+fn default_max_operations() -> u64 {
+    1_000_000
+}
+
+fn default_max_string_size() -> usize {
+    1024 * 1024
+}
+
+fn default_max_collection_size() -> usize {
+    10_000
+}
+
+// resources a script can use
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub struct Limits {
+    // milliseconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+    // in bytes
+    #[serde(default = "default_max_string_size")]
+    pub max_string_size: usize,
+    // max number of items of arrays and maps
+    #[serde(default = "default_max_collection_size")]
+    pub max_collection_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: default_timeout(),
+            max_operations: default_max_operations(),
+            max_string_size: default_max_string_size(),
+            max_collection_size: default_max_collection_size(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// emulated filesystem of a shell session, made available to the scripts
+pub struct Files<'a> {
+    pub fs: &'a mut Filesystem,
+    pub cwd: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl<'a> Files<'a> {
+    pub fn reborrow(&mut self) -> Files<'_> {
+        Files {
+            fs: &mut *self.fs,
+            cwd: self.cwd.to_owned(),
+            uid: self.uid,
+            gid: self.gid,
+        }
+    }
+}
+
+// compile a script, or get it from the cache if the file didn't change
+fn load(path: &Path) -> Result<Arc<AST>, String> {
+    let modified = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map_err(|e| format!("could not open {:?}: {}", path, e))?;
+
+    let mut scripts = SCRIPTS.lock().unwrap();
+    if let Some((time, ast)) = scripts.get(path) {
+        if *time == modified {
+            return Ok(ast.clone());
+        }
+    }
+
+    debug!("compiling {:?}", path);
+
+    let source =
+        fs::read_to_string(path).map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    let ast = Arc::new(
+        sandboxed()
+            .compile(&source)
+            .map_err(|e| format!("could not compile {:?}: {}", path, e))?,
+    );
+    scripts.insert(path.to_owned(), (modified, ast.clone()));
+    Ok(ast)
+}
+
+// register the functions to access the emulated filesystem
+fn register_files(
+    engine: &mut Engine,
+    fs: Arc<Mutex<Filesystem>>,
+    cwd: String,
+    uid: u32,
+    gid: u32,
+) {
+    let (files, dir) = (fs.clone(), cwd.clone());
+    engine.register_fn("exists", move |path: &str| {
+        files.lock().unwrap().exists(&vfs::join(&dir, path))
+    });
+
+    // unit if the file can't be read
+    let (files, dir) = (fs.clone(), cwd.clone());
+    engine.register_fn("read", move |path: &str| -> Dynamic {
+        match files.lock().unwrap().read(&vfs::join(&dir, path)) {
+            Ok(data) => String::from_utf8_lossy(&data).to_string().into(),
+            Err(_) => Dynamic::UNIT,
+        }
+    });
+
+    let (files, dir) = (fs.clone(), cwd.clone());
+    engine.register_fn("ls", move |path: &str| -> Array {
+        match files.lock().unwrap().list(&vfs::join(&dir, path)) {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|(name, _)| name.to_owned().into())
+                .collect(),
+            Err(_) => Array::new(),
+        }
+    });
+
+    // write and append return false on error
+    let (files, dir) = (fs.clone(), cwd.clone());
+    engine.register_fn("write", move |path: &str, data: &str| {
+        files
+            .lock()
+            .unwrap()
+            .write(&vfs::join(&dir, path), data.as_bytes(), false, uid, gid)
+            .is_ok()
+    });
+
+    let (files, dir) = (fs, cwd);
+    engine.register_fn("append", move |path: &str, data: &str| {
+        files
+            .lock()
+            .unwrap()
+            .write(&vfs::join(&dir, path), data.as_bytes(), true, uid, gid)
+            .is_ok()
+    });
+}
+
+// no access to anything but what's registered here
+fn sandboxed() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval");
+    engine
+}
+
+fn engine(limits: &Limits, stdout: Arc<Mutex<String>>) -> Engine {
+    let mut engine = sandboxed();
+    engine
+        .set_max_operations(limits.max_operations)
+        .set_max_string_size(limits.max_string_size)
+        .set_max_array_size(limits.max_collection_size)
+        .set_max_map_size(limits.max_collection_size);
+
+    let deadline = Instant::now() + Duration::from_millis(limits.timeout);
+    engine.on_progress(move |_| {
+        if Instant::now() > deadline {
+            Some("timeout".into())
+        } else {
+            None
+        }
+    });
+
+    engine.on_print(move |s| {
+        let mut stdout = stdout.lock().unwrap();
+        stdout.push_str(s);
+        stdout.push('\n');
+    });
+    engine.on_debug(|s, _, pos| debug!("script {}: {}", pos, s));
+
+    engine.register_fn("md5", |s: &str| format!("{:x}", md5::compute(s)));
+
+    engine
+}
+
+// what the script evaluated to, either a string or a map with stdout, stderr and status
+fn output(result: Dynamic, mut out: Output) -> Output {
+    if result.is_unit() {
+        return out;
+    }
+
+    if !result.is_map() {
+        out.stdout.extend(result.to_string().into_bytes());
+        return out;
+    }
+
+    for (key, value) in result.cast::<Map>() {
+        match key.as_str() {
+            "stdout" => out.stdout.extend(value.to_string().into_bytes()),
+            "stderr" => out.stderr.extend(value.to_string().into_bytes()),
+            "status" => out.status = value.as_int().unwrap_or(1) as i32,
+            _ => {}
+        }
+    }
+
+    out
+}
+
+// variables available to the script of a handler
+pub fn scope(
+    command: &str,
+    parser: &Regex,
+    captures: &Captures,
+    vars: &HashMap<String, String>,
+) -> Scope<'static> {
+    let mut scope = Scope::new();
+    scope.push("command", command.to_owned());
+    scope.push(
+        "captures",
+        captures
+            .iter()
+            .map(|m| m.map(|m| m.as_str()).unwrap_or("").to_owned().into())
+            .collect::<Array>(),
+    );
+    let mut named = Map::new();
+    for name in parser.capture_names().flatten() {
+        let value = captures.name(name).map(|m| m.as_str()).unwrap_or("");
+        named.insert(name.into(), value.to_owned().into());
+    }
+    scope.push("named", named);
+    scope.push_constant(
+        "vars",
+        vars.iter()
+            .map(|(k, v)| (k.as_str().into(), v.to_owned().into()))
+            .collect::<Map>(),
+    );
+    scope
+}
+
+// run a script in a blocking thread, its per session state is updated in place
+pub async fn run(
+    path: &Path,
+    limits: &Limits,
+    mut scope: Scope<'static>,
+    state: &mut Map,
+    files: Option<Files<'_>>,
+) -> Result<Output, String> {
+    let ast = load(path)?;
+
+    scope.push("state", mem::take(state));
+
+    let stdout = Arc::new(Mutex::new(String::new()));
+    let mut engine = engine(limits, stdout.clone());
+
+    // the filesystem is moved to the script thread and back
+    let (fs, fs_ref) = match files {
+        Some(files) => {
+            let fs = Arc::new(Mutex::new(mem::replace(files.fs, Filesystem::new())));
+            register_files(&mut engine, fs.clone(), files.cwd, files.uid, files.gid);
+            (Some(fs), Some(files.fs))
+        }
+        None => (None, None),
+    };
+
+    let result = tokio::task::spawn_blocking(move || {
+        let result = engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast);
+        (result, scope)
+    })
+    .await;
+
+    if let (Some(fs), Some(fs_ref)) = (fs, fs_ref) {
+        // always give the filesystem back to the session, even if the script panicked
+        // or something still holds a reference to it
+        let mut fs = fs.lock().unwrap_or_else(|e| e.into_inner());
+        *fs_ref = mem::replace(&mut *fs, Filesystem::new());
+    }
+
+    let (result, scope) = result.map_err(|e| format!("script {:?} crashed: {}", path, e))?;
+
+    if let Some(updated) = scope.get_value::<Map>("state") {
+        *state = updated;
+    }
+
+    let result = result.map_err(|e| format!("error running {:?}: {}", path, e))?;
+    let stdout = mem::take(&mut *stdout.lock().unwrap());
+
+    Ok(output(result, Output::text(&stdout)))
+}
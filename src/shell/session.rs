@@ -0,0 +1,787 @@
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use crate::{config::Service, record::Record};
+
+use super::{
+    builtins,
+    cli::{Cli, Failure, State},
+    download,
+    handler::{self, Context, Output, EXIT_HANDLER_TOKEN},
+    image::Uname,
+    pacing::{Chunk, Pacing},
+    parser::{self, Command, Condition, Part, Pipeline, RedirectKind, Word},
+    script::Files,
+    tty,
+    vfs::{self, Filesystem},
+    Shell,
+};
+
+// max nesting of command substitutions
+const MAX_DEPTH: usize = 8;
+
+// result of the execution of a command line
+#[derive(Default)]
+pub struct Response {
+    // what should be displayed on the client terminal
+    pub output: Vec<u8>,
+    // the same output, split as the handlers are configured to stream it
+    pub chunks: Vec<Chunk>,
+    // the exit handler matched, the session should be closed
+    pub exit: bool,
+    // the command line keeps running until interrupted
+    pub running: bool,
+}
+
+impl Response {
+    // true if the output can't be sent all at once
+    pub fn is_paced(&self) -> bool {
+        self.running || self.chunks.iter().any(|chunk| !chunk.delay.is_zero())
+    }
+}
+
+// where the output of a command goes after the redirections
+#[derive(Clone)]
+enum Stream {
+    Stdout,
+    Stderr,
+    File(String),
+    Null,
+}
+
+pub(super) struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+// find the entry with the given name (or id) in a passwd or group formatted file
+fn lookup(fs: &Filesystem, file: &str, name: Option<&str>, id: Option<u32>) -> Option<Vec<String>> {
+    let data = fs.read(file).ok()?;
+    String::from_utf8_lossy(&data)
+        .lines()
+        .map(|line| {
+            line.split(':')
+                .map(|s| s.to_owned())
+                .collect::<Vec<String>>()
+        })
+        .find(|fields| {
+            fields.len() >= 3
+                && (Some(fields[0].as_str()) == name
+                    || (id.is_some() && fields[2].parse().ok() == id))
+        })
+}
+
+impl User {
+    pub(super) fn new(fs: &Filesystem, name: &str) -> Self {
+        match lookup(fs, "/etc/passwd", Some(name), None) {
+            Some(fields) => Self {
+                name: name.to_owned(),
+                uid: fields[2].parse().unwrap_or(0),
+                gid: fields.get(3).and_then(|gid| gid.parse().ok()).unwrap_or(0),
+                home: fields.get(5).cloned().unwrap_or_else(|| "/".to_owned()),
+            },
+            None => Self {
+                name: name.to_owned(),
+                uid: 0,
+                gid: 0,
+                home: if name == "root" {
+                    "/root".to_owned()
+                } else {
+                    format!("/home/{}", name)
+                },
+            },
+        }
+    }
+}
+
+// state of a single shell session, every session works on its own copy of the filesystem
+pub struct Session {
+    service: Arc<Mutex<Service>>,
+    shell: Arc<Shell>,
+    // state of the handlers for this session
+    context: Context,
+    enabled: bool,
+    pub(super) fs: Filesystem,
+    pub(super) uname: Uname,
+    pub(super) user: User,
+    pub(super) cwd: String,
+    // command line being executed
+    line: String,
+    // output for the client terminal of the command line being executed
+    terminal: Vec<u8>,
+    // ranges of the terminal output to be sent with a given pacing
+    paced: Vec<(usize, usize, Pacing)>,
+    exit: bool,
+    running: bool,
+    depth: usize,
+    // width of the last prompt
+    column: usize,
+    // modes and privilege level, if a network device CLI is emulated
+    cli: Option<State>,
+}
+
+impl Session {
+    pub fn new(service: Arc<Mutex<Service>>, shell: Arc<Shell>, mut context: Context) -> Self {
+        let (enabled, mut fs, uname) = match &shell.image {
+            Some(image) => (true, image.filesystem.clone(), image.uname.clone()),
+            None => (false, Filesystem::new(), Uname::default()),
+        };
+        fs.set_budget(shell.filesystem_size);
+        let user = User::new(&fs, &shell.user);
+        let cwd = if fs.exists(&user.home) {
+            user.home.to_owned()
+        } else {
+            "/".to_owned()
+        };
+        let cli = shell.cli.as_ref().map(Cli::state);
+        match &cli {
+            Some(state) => {
+                context.set("hostname", &state.hostname);
+                context.set("privilege", &state.privilege.to_string());
+            }
+            None => context.set("hostname", &uname.nodename),
+        }
+
+        Self {
+            service,
+            shell,
+            context,
+            enabled,
+            fs,
+            uname,
+            user,
+            cwd,
+            line: String::new(),
+            terminal: vec![],
+            paced: vec![],
+            exit: false,
+            running: false,
+            depth: 0,
+            column: 0,
+            cli,
+        }
+    }
+
+    // name the client logged in with, for the handler templates
+    pub fn login(&mut self, name: &str) {
+        self.context.set("login", name);
+    }
+
+    // prefix of the error messages
+    pub(super) fn name(&self) -> &str {
+        &self.shell.personality.name
+    }
+
+    // parser errors are reported as sh
+    fn syntax_error(&self, error: String) -> String {
+        match error.strip_prefix("sh:") {
+            Some(message) => format!("{}:{}", self.name(), message),
+            None => error,
+        }
+    }
+
+    // prompt as configured by the personality or the service
+    pub fn prompt(&mut self, default: &str) -> String {
+        if let (Some(cli), Some(state)) = (&self.shell.cli, &self.cli) {
+            let prompt = cli.prompt(state, &self.user.name);
+            self.column = prompt.rsplit('\n').next().unwrap_or("").chars().count();
+            return prompt;
+        }
+
+        let template = self.shell.personality.prompt.as_deref().unwrap_or(default);
+        let (root, user) = &self.shell.personality.sigils;
+
+        let cwd = if self.cwd == self.user.home {
+            "~".to_owned()
+        } else if self.cwd.starts_with(&format!("{}/", self.user.home)) && self.user.home != "/" {
+            format!("~{}", &self.cwd[self.user.home.len()..])
+        } else {
+            self.cwd.to_owned()
+        };
+
+        let prompt = template
+            .replace("{user}", &self.user.name)
+            .replace("{hostname}", &self.uname.nodename)
+            .replace("{dir}", vfs::basename(&cwd))
+            .replace("{cwd}", &cwd)
+            .replace("{sigil}", if self.user.uid == 0 { root } else { user });
+
+        self.column = prompt.rsplit('\n').next().unwrap_or("").chars().count();
+        prompt
+    }
+
+    // true if what the client types should not be echoed
+    pub fn is_secret(&self) -> bool {
+        self.cli.as_ref().map(State::is_secret).unwrap_or(false)
+    }
+
+    // context help of the network device CLI for a partial command line, if emulated
+    pub fn help(&self, line: &str) -> Option<Vec<u8>> {
+        let (cli, state) = match (&self.shell.cli, &self.cli) {
+            (Some(cli), Some(state)) => (cli, state),
+            _ => return None,
+        };
+        let help = match cli.help(state, line) {
+            Ok(help) => help,
+            Err(failure) => cli.error(failure, line, self.column),
+        };
+        Some(help.replace('\n', "\r\n").into_bytes())
+    }
+
+    // terminal passthrough configuration, if enabled
+    pub fn tty(&self) -> Option<&tty::Config> {
+        self.shell.tty.as_ref()
+    }
+
+    pub(super) fn passwd(&self, name: &str) -> Option<Vec<String>> {
+        lookup(&self.fs, "/etc/passwd", Some(name), None)
+    }
+
+    pub(super) fn user_name(&self, uid: u32) -> String {
+        match lookup(&self.fs, "/etc/passwd", None, Some(uid)) {
+            Some(fields) => fields[0].to_owned(),
+            None => uid.to_string(),
+        }
+    }
+
+    pub(super) fn group_name(&self, gid: u32) -> String {
+        match lookup(&self.fs, "/etc/group", None, Some(gid)) {
+            Some(fields) => fields[0].to_owned(),
+            None => gid.to_string(),
+        }
+    }
+
+    // run a command substitution and return its output, boxed since it's recursive
+    fn substitute<'a>(
+        &'a mut self,
+        command: &'a str,
+        log: &'a mut Record,
+    ) -> Pin<Box<dyn Future<Output = String> + Send + 'a>> {
+        Box::pin(async move {
+            if self.depth >= MAX_DEPTH {
+                return String::new();
+            }
+
+            self.depth += 1;
+            let output = match parser::parse(command) {
+                Ok(list) => self.run_list(&list, log, true).await,
+                Err(e) => {
+                    let e = self.syntax_error(e);
+                    self.terminal.extend(e.into_bytes());
+                    self.terminal.push(b'\n');
+                    vec![]
+                }
+            };
+            self.depth -= 1;
+
+            String::from_utf8_lossy(&output)
+                .trim_end_matches('\n')
+                .to_owned()
+        })
+    }
+
+    // expand the command substitutions of a word, splitting the unquoted ones in fields
+    async fn expand(&mut self, word: &Word, log: &mut Record) -> Vec<String> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut has_field = false;
+
+        for part in &word.parts {
+            match part {
+                Part::Literal(literal) => {
+                    field.push_str(literal);
+                    has_field = true;
+                }
+                Part::Substitution { command, quoted } => {
+                    let output = self.substitute(command, log).await;
+                    if *quoted {
+                        field.push_str(&output);
+                        has_field = true;
+                        continue;
+                    }
+
+                    let mut pieces = output.split_whitespace();
+                    if let Some(first) = pieces.next() {
+                        field.push_str(first);
+                        has_field = true;
+                    }
+                    for piece in pieces {
+                        fields.push(mem::take(&mut field));
+                        field.push_str(piece);
+                    }
+                }
+            }
+        }
+
+        if has_field {
+            fields.push(field);
+        }
+
+        fields
+    }
+
+    // open the target of an output redirection, truncating it if needed
+    fn open(&mut self, target: &str, append: bool) -> Result<Stream, String> {
+        let path = vfs::join(&self.cwd, target);
+        if path == "/dev/null" {
+            return Ok(Stream::Null);
+        }
+
+        match self
+            .fs
+            .write(&path, b"", append, self.user.uid, self.user.gid)
+        {
+            Ok(_) => Ok(Stream::File(path)),
+            // without a device image there's no filesystem to be consistent with
+            Err(_) if !self.enabled => Ok(Stream::Null),
+            Err(e) => Err(format!("{}: can't create {}: {}", self.name(), target, e)),
+        }
+    }
+
+    // apply the redirections of a command, returning where its stdout and stderr go
+    async fn redirect(
+        &mut self,
+        command: &Command,
+        stdin: &mut Vec<u8>,
+        log: &mut Record,
+    ) -> Result<[Stream; 2], String> {
+        let mut streams = [Stream::Stdout, Stream::Stderr];
+
+        for redirect in &command.redirects {
+            let target = self.expand(&redirect.target, log).await.join(" ");
+            let index = match redirect.fd {
+                0 if redirect.kind == RedirectKind::Read => {
+                    *stdin = match self.fs.read(&vfs::join(&self.cwd, &target)) {
+                        Ok(data) => data.to_vec(),
+                        Err(_) if target == "/dev/null" || !self.enabled => vec![],
+                        Err(e) => {
+                            return Err(format!("{}: can't open {}: {}", self.name(), target, e))
+                        }
+                    };
+                    continue;
+                }
+                1 => 0,
+                2 => 1,
+                _ => continue,
+            };
+
+            streams[index] = match redirect.kind {
+                RedirectKind::Duplicate => match target.as_str() {
+                    "1" => streams[0].clone(),
+                    "2" => streams[1].clone(),
+                    _ => return Err(format!("{}: {}: bad file descriptor", self.name(), target)),
+                },
+                RedirectKind::Append => self.open(&target, true)?,
+                _ => self.open(&target, false)?,
+            };
+        }
+
+        Ok(streams)
+    }
+
+    // log a download command and pretend it succeeded
+    fn download(&mut self, download: download::Download, log: &mut Record) -> Output {
+        log.download(
+            download.tool.to_owned(),
+            download.url.to_owned(),
+            download.host.to_owned(),
+            download.filename.to_owned(),
+        );
+
+        if let Some(fetcher) = &self.shell.fetcher {
+            fetcher.fetch(log, &download);
+        }
+
+        if let Some(output) = &download.output {
+            let path = vfs::join(&self.cwd, output);
+            let data = vec![0; download.size()];
+            if let Err(e) = self
+                .fs
+                .write(&path, &data, false, self.user.uid, self.user.gid)
+            {
+                let mut out = Output::default();
+                if self.enabled {
+                    out.error(format!("{}: can't open '{}': {}", download.tool, output, e));
+                }
+                return out;
+            }
+        }
+
+        let (stdout, stderr) = download.output();
+        Output {
+            stdout: stdout.into_bytes(),
+            stderr: stderr.into_bytes(),
+            ..Output::default()
+        }
+    }
+
+    // run a command with the builtins, if enabled, or the service handlers
+    async fn dispatch(
+        &mut self,
+        argv: &[String],
+        text: &str,
+        stdin: &[u8],
+        log: &mut Record,
+    ) -> Output {
+        if self.shell.downloads {
+            if let Some(download) = download::parse(argv) {
+                return self.download(download, log);
+            }
+        }
+
+        if self.enabled {
+            if let Some(output) = builtins::run(self, argv, stdin) {
+                return output;
+            }
+        }
+
+        self.context.set("user", &self.user.name);
+        self.context.set("cwd", &self.cwd);
+        let files = Files {
+            fs: &mut self.fs,
+            cwd: self.cwd.to_owned(),
+            uid: self.user.uid,
+            gid: self.user.gid,
+        };
+        if let Some(output) =
+            handler::dispatch(&self.service, text, &mut self.context, Some(files)).await
+        {
+            if output.stdout == EXIT_HANDLER_TOKEN.as_bytes() {
+                self.exit = true;
+                return Output::default();
+            }
+            return Self::whole_lines(output);
+        }
+
+        let shell = self.shell.clone();
+        let personality = &shell.personality;
+        if !personality.fallback.is_empty() {
+            let files = Files {
+                fs: &mut self.fs,
+                cwd: self.cwd.to_owned(),
+                uid: self.user.uid,
+                gid: self.user.gid,
+            };
+            if let Some(output) = handler::fallback(
+                &self.service,
+                &personality.fallback,
+                text,
+                &mut self.context,
+                Some(files),
+            )
+            .await
+            {
+                return Self::whole_lines(output);
+            }
+        }
+
+        let mut output = Output::default();
+        output.error(personality.not_found(&argv[0], self.column));
+        output.status = personality.not_found_status;
+        output
+    }
+
+    // handlers output a whole line
+    fn whole_lines(mut output: Output) -> Output {
+        for stream in [&mut output.stdout, &mut output.stderr] {
+            if !stream.is_empty() && !stream.ends_with(b"\n") {
+                stream.push(b'\n');
+            }
+        }
+        output
+    }
+
+    async fn run_command(&mut self, command: &Command, stdin: &[u8], log: &mut Record) -> Output {
+        let mut argv = vec![];
+        let mut words = vec![];
+        for word in &command.words {
+            let fields = self.expand(word, log).await;
+            // handlers get the command as typed, unless it has been expanded
+            words.push(if word.has_substitutions() {
+                fields.join(" ")
+            } else {
+                word.raw.to_owned()
+            });
+            argv.extend(fields);
+        }
+        let text = words.join(" ");
+
+        let mut stdin = stdin.to_vec();
+        let streams = match self.redirect(command, &mut stdin, log).await {
+            Ok(streams) => streams,
+            Err(e) => {
+                let mut output = Output::default();
+                output.error(e);
+                self.terminal.extend(mem::take(&mut output.stderr));
+                return output;
+            }
+        };
+
+        // only redirections
+        if argv.is_empty() {
+            return Output::default();
+        }
+
+        if text != self.line {
+            log.exec(text.to_owned());
+        }
+
+        let output = self.dispatch(&argv, &text, &stdin, log).await;
+        let mut result = Output {
+            status: output.status,
+            ..Output::default()
+        };
+        // pacing only matters if the output goes to the terminal
+        if let Stream::Stdout = streams[0] {
+            result.pacing = output.pacing;
+        }
+
+        for (data, stream) in [(output.stdout, &streams[0]), (output.stderr, &streams[1])] {
+            match stream {
+                Stream::Stdout => result.stdout.extend(data),
+                Stream::Stderr => self.terminal.extend(data),
+                Stream::File(path) => {
+                    // the file has been created (or truncated) by the redirection already
+                    let _ = self
+                        .fs
+                        .write(path, &data, true, self.user.uid, self.user.gid);
+                }
+                Stream::Null => {}
+            }
+        }
+
+        result
+    }
+
+    async fn run_pipeline(&mut self, pipeline: &Pipeline, log: &mut Record) -> Output {
+        let mut output = Output::default();
+        for command in &pipeline.commands {
+            let stdin = mem::take(&mut output.stdout);
+            output = self.run_command(command, &stdin, log).await;
+            if self.exit {
+                break;
+            }
+        }
+        output
+    }
+
+    // run a list of pipelines, the output is either captured and returned or sent
+    // to the terminal
+    async fn run_list(&mut self, list: &[Pipeline], log: &mut Record, capture: bool) -> Vec<u8> {
+        let mut captured = vec![];
+        let mut status = 0;
+
+        for pipeline in list {
+            let run = match pipeline.condition {
+                Condition::Always => true,
+                Condition::Success => status == 0,
+                Condition::Failure => status != 0,
+            };
+            if !run {
+                continue;
+            }
+
+            let output = self.run_pipeline(pipeline, log).await;
+            status = output.status;
+            if capture {
+                captured.extend(output.stdout);
+            } else {
+                let start = self.terminal.len();
+                self.terminal.extend(output.stdout);
+                if let Some(pacing) = output.pacing {
+                    // nothing else runs until the command is interrupted
+                    self.running = pacing.running;
+                    self.paced.push((start, self.terminal.len(), pacing));
+                }
+            }
+
+            if self.exit || self.running {
+                break;
+            }
+        }
+
+        captured
+    }
+
+    // send the output of a command to the terminal
+    fn show(&mut self, output: Output) {
+        let output = Self::whole_lines(output);
+        let start = self.terminal.len();
+        self.terminal.extend(output.stdout);
+        if let Some(pacing) = output.pacing {
+            self.running = pacing.running;
+            self.paced.push((start, self.terminal.len(), pacing));
+        }
+        self.terminal.extend(output.stderr);
+    }
+
+    // run a command line with the network device CLI
+    async fn run_cli(&mut self, cli: &Cli, line: &str, log: &mut Record) {
+        let state = self.cli.as_mut().unwrap();
+
+        if state.is_secret() {
+            match cli.password(state, line) {
+                Ok(accepted) => log.log(format!(
+                    "enable password '{}' {}",
+                    line,
+                    if accepted { "accepted" } else { "rejected" }
+                )),
+                Err(e) => {
+                    log.log(format!("enable password '{}' denied", line));
+                    self.terminal.extend(e.into_bytes());
+                }
+            }
+            self.context.set("privilege", &state.privilege.to_string());
+            return;
+        }
+
+        if line.ends_with('?') {
+            let help = match cli.help(state, line) {
+                Ok(help) => help,
+                Err(failure) => cli.error(failure, line, self.column),
+            };
+            self.terminal.extend(help.into_bytes());
+            return;
+        }
+
+        // output filters
+        let (command, pipes) = match line.find('|') {
+            Some(pos) if cli.has_pipes() => (line[..pos].trim_end(), Some(pos)),
+            _ => (line, None),
+        };
+
+        let executed = match cli.execute(state, command) {
+            Ok(executed) => executed,
+            Err(failure) => {
+                self.context.set("user", &self.user.name);
+                // the service handlers can still take care of it
+                match handler::dispatch(&self.service, line, &mut self.context, None).await {
+                    Some(output) if output.stdout == EXIT_HANDLER_TOKEN.as_bytes() => {
+                        self.exit = true
+                    }
+                    Some(output) => self.show(output),
+                    None => {
+                        let error = cli.error(failure, command, self.column);
+                        self.terminal.extend(error.into_bytes());
+                    }
+                }
+                return;
+            }
+        };
+
+        if executed.text != command {
+            log.exec(executed.text.to_owned());
+        }
+        self.context.set("hostname", &state.hostname);
+        self.context.set("privilege", &state.privilege.to_string());
+        self.context.set("user", &self.user.name);
+        self.exit = executed.exit;
+
+        let mut output = match executed.handler {
+            Some(command) => {
+                let folder = handler::folder(&self.service.lock().unwrap());
+                handler::run(command, &executed.args, &folder, &mut self.context, None).await
+            }
+            None => return,
+        };
+        if output.stdout == EXIT_HANDLER_TOKEN.as_bytes() {
+            self.exit = true;
+            return;
+        }
+
+        if let Some(pos) = pipes {
+            let mut stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            let mut offset = pos + 1;
+            for pipe in line[offset..].split('|') {
+                stdout = match cli.filter(&stdout, pipe) {
+                    Some(filtered) => filtered,
+                    None => {
+                        let start = offset + pipe.len() - pipe.trim_start().len();
+                        let error = cli.error(Failure::Invalid(start), line, self.column);
+                        self.terminal.extend(error.into_bytes());
+                        return;
+                    }
+                };
+                offset += pipe.len() + 1;
+            }
+            output.stdout = stdout.into_bytes();
+        }
+
+        self.show(output);
+    }
+
+    // execute a command line, every single command is passed to the builtins first,
+    // if a device image is configured, and then to the service handlers
+    pub async fn execute(&mut self, line: &str, log: &mut Record) -> Response {
+        self.line = line.trim().to_owned();
+        self.exit = false;
+        self.running = false;
+        self.terminal.clear();
+        self.paced.clear();
+
+        let shell = self.shell.clone();
+        if let Some(cli) = &shell.cli {
+            self.run_cli(cli, line.trim(), log).await;
+        } else {
+            match parser::parse(line) {
+                Ok(list) => {
+                    self.run_list(&list, log, false).await;
+                }
+                Err(e) => {
+                    let e = self.syntax_error(e);
+                    self.terminal.extend(e.into_bytes());
+                }
+            }
+        }
+
+        let mut data = mem::take(&mut self.terminal);
+        if data.ends_with(b"\n") {
+            data.pop();
+            if data.ends_with(b"\r") {
+                data.pop();
+            }
+        }
+
+        // split the output in chunks as configured by the handlers that produced it
+        let mut chunks = vec![];
+        let mut pos = 0;
+        for (start, end, pacing) in mem::take(&mut self.paced) {
+            let (start, end) = (start.min(data.len()), end.min(data.len()));
+            if start > pos {
+                chunks.push(Chunk::now(data[pos..start].to_vec()));
+            }
+            chunks.extend(pacing.chunks(&data[start..end]));
+            pos = end;
+        }
+        if pos < data.len() || chunks.is_empty() {
+            chunks.push(Chunk::now(data[pos..].to_vec()));
+        }
+
+        // terminals expect \r\n line endings
+        let mut output = Vec::with_capacity(data.len());
+        let mut last = 0;
+        for chunk in &mut chunks {
+            let mut converted = Vec::with_capacity(chunk.data.len());
+            for byte in &chunk.data {
+                if *byte == b'\n' && last != b'\r' {
+                    converted.push(b'\r');
+                }
+                converted.push(*byte);
+                last = *byte;
+            }
+            output.extend_from_slice(&converted);
+            chunk.data = converted;
+        }
+
+        Response {
+            output,
+            chunks,
+            exit: self.exit,
+            running: self.running,
+        }
+    }
+}
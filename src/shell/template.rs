@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local, Utc};
+use lazy_static::lazy_static;
+use rand::Rng;
+use regex::Captures;
+
+// max nesting of included files
+const MAX_DEPTH: usize = 8;
+
+lazy_static! {
+    // fake boot time of the emulated devices, shared by all the services
+    static ref BOOT: SystemTime = SystemTime::now()
+        - Duration::from_secs(rand::thread_rng().gen_range(3600..90 * 24 * 3600));
+}
+
+// argument of a template tag
+#[derive(Debug)]
+enum Arg {
+    // quoted string
+    Str(String),
+    // capture, variable or function name
+    Name(String),
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    // the original text is kept for tags that don't evaluate to anything
+    Expr {
+        args: Vec<Arg>,
+        raw: String,
+    },
+    If {
+        condition: Vec<Arg>,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+// what a template is rendered with
+pub struct Scope<'a> {
+    // captures of the handler regular expression
+    pub captures: &'a Captures<'a>,
+    // session variables
+    pub vars: &'a mut HashMap<String, String>,
+    // folder included files are relative to
+    pub folder: &'a Path,
+}
+
+// split the content of a tag into arguments, None if it's not a valid tag
+fn tokenize(tag: &str) -> Option<Vec<Arg>> {
+    let mut args = vec![];
+    let mut chars = tag.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => s.push(chars.next()?),
+                        c => s.push(c),
+                    }
+                }
+                args.push(Arg::Str(s));
+            }
+            c if c.is_alphanumeric() || "$_=!".contains(c) => {
+                let mut s = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                if !s
+                    .chars()
+                    .skip(1)
+                    .all(|c| c.is_alphanumeric() || "_=".contains(c))
+                {
+                    return None;
+                }
+                args.push(Arg::Name(s));
+            }
+            _ => return None,
+        }
+    }
+
+    if args.is_empty() {
+        None
+    } else {
+        Some(args)
+    }
+}
+
+// parse a template into a list of nodes, block tags are matched with a stack
+fn parse(template: &str) -> Result<Vec<Node>, String> {
+    // nodes of the blocks being parsed with their condition, the else branch is
+    // pushed as a block of its own
+    let mut stack: Vec<(Option<Vec<Arg>>, Vec<Node>)> = vec![(None, vec![])];
+    let mut text = String::new();
+    let mut rest = template;
+
+    macro_rules! current {
+        () => {
+            stack.last_mut().unwrap().1
+        };
+    }
+
+    while !rest.is_empty() {
+        // legacy {$N} captures
+        if let Some(tail) = rest.strip_prefix("{$") {
+            if let Some(end) = tail.find('}') {
+                let name = &tail[..end];
+                if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+                    current!().push(Node::Text(std::mem::take(&mut text)));
+                    current!().push(Node::Expr {
+                        args: vec![Arg::Name(format!("${}", name))],
+                        raw: rest[..end + 3].to_owned(),
+                    });
+                    rest = &tail[end + 1..];
+                    continue;
+                }
+            }
+        }
+
+        if let Some(tail) = rest.strip_prefix("{{") {
+            if let Some(end) = tail.find("}}") {
+                if let Some(mut args) = tokenize(&tail[..end]) {
+                    let raw = rest[..end + 4].to_owned();
+                    rest = &tail[end + 2..];
+                    current!().push(Node::Text(std::mem::take(&mut text)));
+
+                    match (&args[0], args.len()) {
+                        (Arg::Name(keyword), _) if keyword == "if" => {
+                            if args.len() < 2 {
+                                return Err(format!("missing condition in '{}'", raw));
+                            }
+                            args.remove(0);
+                            stack.push((Some(args), vec![]));
+                        }
+                        (Arg::Name(keyword), 1) if keyword == "else" => {
+                            if stack.last().unwrap().0.is_none() {
+                                return Err("else without if".to_owned());
+                            }
+                            stack.push((None, vec![]));
+                        }
+                        (Arg::Name(keyword), 1) if keyword == "end" => {
+                            if stack.len() < 2 {
+                                return Err("end without if".to_owned());
+                            }
+                            let (condition, nodes) = stack.pop().unwrap();
+                            let node = match condition {
+                                Some(condition) => Node::If {
+                                    condition,
+                                    then: nodes,
+                                    otherwise: vec![],
+                                },
+                                None => {
+                                    let (condition, then) = stack.pop().unwrap();
+                                    Node::If {
+                                        condition: condition
+                                            .ok_or_else(|| "else without if".to_owned())?,
+                                        then,
+                                        otherwise: nodes,
+                                    }
+                                }
+                            };
+                            current!().push(node);
+                        }
+                        _ => current!().push(Node::Expr { args, raw }),
+                    }
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        text.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    current!().push(Node::Text(text));
+
+    if stack.len() > 1 {
+        return Err("if without end".to_owned());
+    }
+
+    Ok(stack.pop().unwrap().1)
+}
+
+fn format_time<T: chrono::TimeZone>(time: DateTime<T>, format: &str) -> Result<String, String>
+where
+    T::Offset: std::fmt::Display,
+{
+    let mut s = String::new();
+    write!(s, "{}", time.format(format))
+        .map_err(|_| format!("invalid time format '{}'", format))?;
+    Ok(s)
+}
+
+// a random locally administered mac address
+fn mac() -> String {
+    let mut rng = rand::thread_rng();
+    let mut bytes: [u8; 6] = rng.gen();
+    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+// time since boot as displayed by uptime
+fn uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    let time = if hours > 0 {
+        format!("{:2}:{:02}", hours, minutes)
+    } else {
+        format!("{} min", minutes)
+    };
+    match days {
+        0 => time,
+        1 => format!("1 day, {}", time),
+        n => format!("{} days, {}", n, time),
+    }
+}
+
+impl<'a> Scope<'a> {
+    fn value(&self, arg: &Arg) -> Option<String> {
+        match arg {
+            Arg::Str(s) => Some(s.to_owned()),
+            Arg::Name(name) => match name.strip_prefix('$') {
+                Some(capture) => Some(
+                    match capture.parse::<usize>() {
+                        Ok(n) => self.captures.get(n),
+                        Err(_) => self.captures.name(capture),
+                    }
+                    .map(|m| m.as_str().to_owned())
+                    .unwrap_or_default(),
+                ),
+                // numbers
+                None if name.chars().all(|c| c.is_ascii_digit()) => Some(name.to_owned()),
+                None => self.vars.get(name).cloned(),
+            },
+        }
+    }
+
+    fn string(&self, args: &[Arg], n: usize, default: &str) -> Result<String, String> {
+        match args.get(n) {
+            Some(arg) => self
+                .value(arg)
+                .ok_or_else(|| format!("undefined argument {:?}", arg)),
+            None => Ok(default.to_owned()),
+        }
+    }
+
+    fn number(&self, args: &[Arg], n: usize, default: u64) -> Result<u64, String> {
+        let value = self.string(args, n, &default.to_string())?;
+        value
+            .parse()
+            .map_err(|_| format!("'{}' is not a number", value))
+    }
+
+    // evaluate a tag, None if it doesn't refer to anything known
+    fn eval(&mut self, args: &[Arg], depth: usize) -> Result<Option<String>, String> {
+        let name = match &args[0] {
+            Arg::Name(name) if !name.starts_with('$') => name.as_str(),
+            arg => return Ok(self.value(arg)),
+        };
+        let elapsed = SystemTime::now()
+            .duration_since(*BOOT)
+            .unwrap_or_default()
+            .as_secs();
+
+        let value = match name {
+            "now" => format_time(Local::now(), &self.string(args, 1, "%a %b %e %H:%M:%S %Y")?)?,
+            "utc" => format_time(
+                Utc::now(),
+                &self.string(args, 1, "%a %b %e %H:%M:%S UTC %Y")?,
+            )?,
+            "boot" => format_time(
+                DateTime::<Local>::from(*BOOT),
+                &self.string(args, 1, "%Y-%m-%d %H:%M:%S")?,
+            )?,
+            "uptime" => uptime(elapsed),
+            "uptime_seconds" => elapsed.to_string(),
+            "random" => {
+                let (min, max) = (self.number(args, 1, 0)?, self.number(args, 2, 100)?);
+                if min > max {
+                    return Err(format!("invalid random range {}..{}", min, max));
+                }
+                rand::thread_rng().gen_range(min..=max).to_string()
+            }
+            "pid" => rand::thread_rng().gen_range(300..32768).to_string(),
+            "load" => {
+                let mut rng = rand::thread_rng();
+                let load: f64 = rng.gen_range(0.0..1.0);
+                format!(
+                    "{:.2}, {:.2}, {:.2}",
+                    load,
+                    load * rng.gen_range(0.5..1.5),
+                    load * rng.gen_range(0.5..1.5)
+                )
+            }
+            // the same for the whole session
+            "mac" => self
+                .vars
+                .entry("mac".to_owned())
+                .or_insert_with(mac)
+                .to_owned(),
+            "include" => {
+                let file = self.folder.join(self.string(args, 1, "")?);
+                let template = fs::read_to_string(&file)
+                    .map_err(|e| format!("could not include {:?}: {}", file, e))?;
+                self.render(&template, depth + 1)?
+            }
+            _ => return Ok(self.value(&args[0])),
+        };
+
+        Ok(Some(value))
+    }
+
+    fn condition(&mut self, args: &[Arg], depth: usize) -> Result<bool, String> {
+        if let Some(pos) = args
+            .iter()
+            .position(|arg| matches!(arg, Arg::Name(op) if op == "==" || op == "!="))
+        {
+            let (left, right) = (&args[..pos], &args[pos + 1..]);
+            if left.is_empty() || right.is_empty() {
+                return Err("missing operand in condition".to_owned());
+            }
+            let equal = self.eval(left, depth)?.unwrap_or_default()
+                == self.eval(right, depth)?.unwrap_or_default();
+            return Ok(equal == matches!(&args[pos], Arg::Name(op) if op == "=="));
+        }
+
+        Ok(!self.eval(args, depth)?.unwrap_or_default().is_empty())
+    }
+
+    fn nodes(&mut self, nodes: &[Node], out: &mut String, depth: usize) -> Result<(), String> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Expr { args, raw } => match self.eval(args, depth)? {
+                    Some(value) => out.push_str(&value),
+                    None => out.push_str(raw),
+                },
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    if self.condition(condition, depth)? {
+                        self.nodes(then, out, depth)?;
+                    } else {
+                        self.nodes(otherwise, out, depth)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // render a template, captures and variables are never parsed as templates themselves
+    pub fn render(&mut self, template: &str, depth: usize) -> Result<String, String> {
+        if depth > MAX_DEPTH {
+            return Err("too many nested includes".to_owned());
+        }
+        let mut out = String::new();
+        self.nodes(&parse(template)?, &mut out, depth)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::Regex;
+
+    fn render_in(template: &str, folder: &Path) -> Result<String, String> {
+        let re = Regex::new(r"^(\w+)\s+(?P<target>.*)$").unwrap();
+        let captures = re.captures("ping 1.2.3.4").unwrap();
+        let mut vars = HashMap::new();
+        vars.insert("user".to_owned(), "root".to_owned());
+        vars.insert("empty".to_owned(), String::new());
+        vars.insert("tag".to_owned(), "{{ user }}".to_owned());
+
+        Scope {
+            captures: &captures,
+            vars: &mut vars,
+            folder,
+        }
+        .render(template, 0)
+    }
+
+    fn render(template: &str) -> Result<String, String> {
+        render_in(template, Path::new("."))
+    }
+
+    // folder with the given files, removed when dropped
+    struct Folder(std::path::PathBuf);
+
+    impl Folder {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "medusa-template-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&path).unwrap();
+            for (file, content) in files {
+                fs::write(path.join(file), content).unwrap();
+            }
+            Self(path)
+        }
+    }
+
+    impl Drop for Folder {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(render("").unwrap(), "");
+        assert_eq!(render("plain text").unwrap(), "plain text");
+        assert_eq!(
+            render("{$1} {$2} {{ $1 }} {{$target}} {{ $9 }}.").unwrap(),
+            "ping 1.2.3.4 ping 1.2.3.4 ."
+        );
+        assert_eq!(render("{{ user }}@{{ \"host\" }}:{{ 42 }}").unwrap(), "root@host:42");
+        assert_eq!(render("{{ \"a \\\"b\\\" c\" }}").unwrap(), "a \"b\" c");
+        // variables are not templates themselves
+        assert_eq!(render("{{ tag }}").unwrap(), "{{ user }}");
+        // the mac address is the same for the whole session
+        assert_eq!(render("{{ mac }}").unwrap().len(), 17);
+        let mac = render("{{ mac }} {{ mac }}").unwrap();
+        let (first, second) = mac.split_at(17);
+        assert_eq!(first, second.trim());
+    }
+
+    #[test]
+    fn functions() {
+        let value: u64 = render("{{ random 5 10 }}").unwrap().parse().unwrap();
+        assert!((5..=10).contains(&value));
+        assert_eq!(render("{{ random 7 7 }}").unwrap(), "7");
+        assert_eq!(render("{{ utc \"%Y\" }}").unwrap().len(), 4);
+        assert!(render("{{ uptime_seconds }}").unwrap().parse::<u64>().is_ok());
+
+        assert_eq!(uptime(59), "0 min");
+        assert_eq!(uptime(3600 + 5 * 60), " 1:05");
+        assert_eq!(uptime(86400 + 12 * 60), "1 day, 12 min");
+        assert_eq!(uptime(3 * 86400 + 23 * 3600), "3 days, 23:00");
+    }
+
+    #[test]
+    fn unknown_and_invalid_tags_are_kept() {
+        for template in &[
+            "{{ unknown }}",
+            "{{ }}",
+            "{{}}",
+            "{{ now",
+            "{{ \"unterminated }}",
+            "{{ \"escaped\\\" }}",
+            "{{ user() }}",
+            "{{ @user }}",
+            "{$}",
+            "{$x}",
+            "{$1",
+            "{{{{ }}}}",
+            "}} {{",
+        ] {
+            assert_eq!(render(template).unwrap(), *template);
+        }
+        assert_eq!(render("{{{ user }}}").unwrap(), "{root}");
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(render("{{ if user }}yes{{ end }}").unwrap(), "yes");
+        assert_eq!(render("{{ if empty }}yes{{ end }}").unwrap(), "");
+        assert_eq!(render("{{ if missing }}yes{{ else }}no{{ end }}").unwrap(), "no");
+        assert_eq!(
+            render("{{ if $1 == \"ping\" }}a{{ else }}b{{ end }}").unwrap(),
+            "a"
+        );
+        assert_eq!(render("{{ if user != \"root\" }}a{{ else }}b{{ end }}").unwrap(), "b");
+        assert_eq!(
+            render("{{ if user }}{{ if empty }}a{{ else }}b{{ end }}{{ else }}c{{ end }}!")
+                .unwrap(),
+            "b!"
+        );
+    }
+
+    #[test]
+    fn unbalanced_blocks() {
+        assert_eq!(render("{{ if user }}yes"), Err("if without end".to_owned()));
+        assert_eq!(
+            render("{{ if user }}{{ if user }}yes{{ end }}"),
+            Err("if without end".to_owned())
+        );
+        assert_eq!(
+            render("{{ if user }}a{{ else }}b"),
+            Err("if without end".to_owned())
+        );
+        assert_eq!(render("yes{{ end }}"), Err("end without if".to_owned()));
+        assert_eq!(render("{{ else }}"), Err("else without if".to_owned()));
+        assert_eq!(
+            render("{{ if user }}a{{ else }}b{{ else }}c{{ end }}"),
+            Err("else without if".to_owned())
+        );
+        assert_eq!(
+            render("{{ if }}a{{ end }}"),
+            Err("missing condition in '{{ if }}'".to_owned())
+        );
+        assert_eq!(
+            render("{{ if == user }}a{{ end }}"),
+            Err("missing operand in condition".to_owned())
+        );
+        assert_eq!(
+            render("{{ if user != }}a{{ end }}"),
+            Err("missing operand in condition".to_owned())
+        );
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert_eq!(
+            render("{{ random 10 5 }}"),
+            Err("invalid random range 10..5".to_owned())
+        );
+        assert_eq!(
+            render("{{ random user }}"),
+            Err("'root' is not a number".to_owned())
+        );
+        assert!(render("{{ random missing }}").is_err());
+        assert!(render("{{ utc missing }}").is_err());
+    }
+
+    #[test]
+    fn includes() {
+        let folder = Folder::new(
+            "includes",
+            &[
+                ("banner.txt", "Welcome {{ user }}{{ include \"motd.txt\" }}"),
+                ("motd.txt", ", {$1}!"),
+                ("loop.txt", "{{ include \"loop.txt\" }}"),
+                ("broken.txt", "{{ if user }}"),
+            ],
+        );
+
+        assert_eq!(
+            render_in("{{ include \"banner.txt\" }}", &folder.0).unwrap(),
+            "Welcome root, ping!"
+        );
+        assert_eq!(
+            render_in("{{ include \"loop.txt\" }}", &folder.0),
+            Err("too many nested includes".to_owned())
+        );
+        assert_eq!(
+            render_in("{{ include \"broken.txt\" }}", &folder.0),
+            Err("if without end".to_owned())
+        );
+        assert!(render_in("{{ include \"missing.txt\" }}", &folder.0)
+            .unwrap_err()
+            .starts_with("could not include"));
+        assert!(render_in("{{ include }}", &folder.0).is_err());
+    }
+}
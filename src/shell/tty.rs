@@ -0,0 +1,241 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error};
+use serde::Deserialize;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    net::UnixStream,
+};
+
+use crate::record::Record;
+
+use super::{
+    backend::{Backend, Container, DOCKER, PODMAN},
+    docker::Engine,
+};
+
+// max container output kept in memory before being recorded
+const MAX_OUTPUT: usize = 1024 * 1024;
+
+fn default_shell() -> Vec<String> {
+    vec!["/bin/sh".to_owned()]
+}
+
+fn default_backend() -> String {
+    "docker".to_owned()
+}
+
+// tty section of the service configuration
+#[derive(Clone, Deserialize, Debug)]
+pub struct Config {
+    // docker or podman
+    #[serde(default = "default_backend")]
+    pub backend: String,
+    // name of an existing container shared by all the sessions
+    #[serde(default)]
+    pub container: Option<String>,
+    // or a new container for every session
+    #[serde(default)]
+    pub ephemeral: Option<Container>,
+    // command attached to the terminal
+    #[serde(default = "default_shell")]
+    pub shell: Vec<String>,
+}
+
+impl Config {
+    fn engine(&self) -> Result<&'static Engine, String> {
+        match self.backend.as_str() {
+            "docker" => Ok(&*DOCKER),
+            "podman" => Ok(&*PODMAN),
+            other => Err(format!("unsupported tty backend '{}'", other)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.engine()?;
+        match (&self.container, &self.ephemeral) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err("tty requires either a container or an ephemeral section".to_owned()),
+        }
+    }
+}
+
+// position in a terminal escape sequence
+enum Escape {
+    None,
+    // after ESC
+    Start,
+    // after ESC [ or ESC O, until the final byte
+    Sequence,
+}
+
+type Stream = BufReader<UnixStream>;
+
+// client side of a terminal attached to a container
+pub struct Tty {
+    engine: &'static Engine,
+    exec_id: String,
+    writer: WriteHalf<Stream>,
+    // ephemeral container to be removed
+    container: Option<String>,
+    // keystrokes of the line being typed
+    line: Vec<u8>,
+    escape: Escape,
+    // true if the last keystroke was a carriage return
+    return_pressed: bool,
+    // container output not recorded yet
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+// container side of the terminal
+pub struct Reader {
+    reader: ReadHalf<Stream>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Reader {
+    // read the next chunk of container output, None once the terminal is closed
+    pub async fn read(&mut self) -> Option<Vec<u8>> {
+        let mut buf = [0; 4096];
+        match self.reader.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                let mut output = self.output.lock().unwrap();
+                let room = MAX_OUTPUT.saturating_sub(output.len());
+                output.extend_from_slice(&buf[..n.min(room)]);
+                Some(buf[..n].to_vec())
+            }
+            Err(e) => {
+                debug!("terminal closed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Tty {
+    // attach a new terminal to the configured container
+    pub async fn open(config: &Config, width: u32, height: u32) -> Result<(Self, Reader), String> {
+        let engine = config.engine()?;
+        let (container_id, ephemeral) = match (&config.container, &config.ephemeral) {
+            (Some(name), _) => (name.to_owned(), None),
+            (None, Some(container)) => {
+                let id = engine.create(container).await?;
+                (id.to_owned(), Some(id))
+            }
+            (None, None) => return Err("no container configured".to_owned()),
+        };
+
+        let (exec_id, stream) = match engine
+            .attach(&container_id, &config.shell, width, height)
+            .await
+        {
+            Ok(attached) => attached,
+            Err(e) => {
+                if let Some(id) = ephemeral {
+                    let _ = engine.remove(&id).await;
+                }
+                return Err(e);
+            }
+        };
+
+        let output = Arc::new(Mutex::new(vec![]));
+        let (reader, writer) = io::split(stream);
+
+        Ok((
+            Self {
+                engine,
+                exec_id,
+                writer,
+                container: ephemeral,
+                line: vec![],
+                escape: Escape::None,
+                return_pressed: false,
+                output: output.clone(),
+            },
+            Reader { reader, output },
+        ))
+    }
+
+    // record the container output received so far
+    pub fn flush(&mut self, log: &mut Record) {
+        let output = mem::take(&mut *self.output.lock().unwrap());
+        if !output.is_empty() {
+            log.output(String::from_utf8_lossy(&output).to_string());
+        }
+    }
+
+    // follow the keystrokes in order to log the commands being typed
+    fn keystrokes(&mut self, data: &[u8], log: &mut Record) {
+        for byte in data {
+            match (&self.escape, *byte) {
+                (Escape::Start, b'[') | (Escape::Start, b'O') => self.escape = Escape::Sequence,
+                (Escape::Start, _) => self.escape = Escape::None,
+                (Escape::Sequence, 0x40..=0x7e) => self.escape = Escape::None,
+                (Escape::Sequence, _) => {}
+                // ESC
+                (Escape::None, 0x1b) => self.escape = Escape::Start,
+                // \r\n
+                (Escape::None, b'\n') if self.return_pressed => {}
+                (Escape::None, b'\r') | (Escape::None, b'\n') => {
+                    // output of the previous command, followed by the new one
+                    self.flush(log);
+                    let line = String::from_utf8_lossy(&mem::take(&mut self.line))
+                        .trim()
+                        .to_owned();
+                    if !line.is_empty() {
+                        log.command(line);
+                    }
+                }
+                // backspace and delete
+                (Escape::None, 0x08) | (Escape::None, 0x7f) => {
+                    // remove a whole utf-8 character
+                    while let Some(byte) = self.line.pop() {
+                        if byte & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // ^C and ^U
+                (Escape::None, 0x03) | (Escape::None, 0x15) => self.line.clear(),
+                (Escape::None, byte) if byte >= 0x20 => self.line.push(byte),
+                _ => {}
+            }
+            self.return_pressed = *byte == b'\r';
+        }
+    }
+
+    // send client input to the terminal
+    pub async fn write(&mut self, data: &[u8], log: &mut Record) -> Result<(), String> {
+        self.keystrokes(data, log);
+        self.writer
+            .write_all(data)
+            .await
+            .map_err(|e| format!("could not write to terminal: {}", e))
+    }
+
+    pub async fn resize(&self, width: u32, height: u32) {
+        if let Err(e) = self.engine.resize(&self.exec_id, width, height).await {
+            debug!("could not resize terminal {}: {}", &self.exec_id, e);
+        }
+    }
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        if let Some(id) = self.container.take() {
+            let engine = self.engine;
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(async move {
+                        if let Err(e) = engine.remove(&id).await {
+                            error!("could not remove container {}: {}", id, e);
+                        }
+                    });
+                }
+                Err(e) => error!("can't remove container {}: {}", id, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+// errors are reported with the same text of the corresponding errno
+pub type Error = &'static str;
+
+pub const NOT_FOUND: Error = "No such file or directory";
+pub const NOT_A_DIRECTORY: Error = "Not a directory";
+pub const IS_A_DIRECTORY: Error = "Is a directory";
+pub const EXISTS: Error = "File exists";
+pub const BUSY: Error = "Device or resource busy";
+pub const TOO_MANY_LINKS: Error = "Too many levels of symbolic links";
+pub const NO_SPACE: Error = "No space left on device";
+
+const MAX_LINKS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub enum Kind {
+    // contents are shared between sessions until modified
+    File(Arc<Vec<u8>>),
+    Directory,
+    Symlink(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub kind: Kind,
+    // permission bits only, the file type is given by kind
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: DateTime<Utc>,
+}
+
+impl Node {
+    pub fn file(data: Vec<u8>, mode: u32) -> Self {
+        Self::new(Kind::File(Arc::new(data)), mode)
+    }
+
+    pub fn directory(mode: u32) -> Self {
+        Self::new(Kind::Directory, mode)
+    }
+
+    pub fn symlink(target: &str) -> Self {
+        Self::new(Kind::Symlink(target.to_owned()), 0o777)
+    }
+
+    fn new(kind: Kind, mode: u32) -> Self {
+        Self {
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            modified: Utc::now(),
+        }
+    }
+
+    pub fn owned_by(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    pub fn modified_at(mut self, timestamp: u64) -> Self {
+        if let Some(modified) = Utc.timestamp_opt(timestamp as i64, 0).single() {
+            self.modified = modified;
+        }
+        self
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Directory)
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.kind {
+            Kind::File(data) => data.len(),
+            Kind::Directory => 4096,
+            Kind::Symlink(target) => target.len(),
+        }
+    }
+
+    // ls -l style permissions string
+    pub fn permissions(&self) -> String {
+        let mut perms = String::with_capacity(10);
+        perms.push(match self.kind {
+            Kind::File(_) => '-',
+            Kind::Directory => 'd',
+            Kind::Symlink(_) => 'l',
+        });
+        for shift in [6, 3, 0] {
+            let bits = self.mode >> shift;
+            perms.push(if bits & 4 != 0 { 'r' } else { '-' });
+            perms.push(if bits & 2 != 0 { 'w' } else { '-' });
+            perms.push(if bits & 1 != 0 { 'x' } else { '-' });
+        }
+        if self.mode & 0o1000 != 0 {
+            perms.pop();
+            perms.push(if self.mode & 1 != 0 { 't' } else { 'T' });
+        }
+        perms
+    }
+}
+
+// lexically join path to cwd, resolving . and .. components
+pub fn join(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        vec![]
+    } else {
+        cwd.split('/').filter(|p| !p.is_empty()).collect()
+    };
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    format!("/{}", parts.join("/"))
+}
+
+pub fn parent(path: &str) -> String {
+    join(path, "..")
+}
+
+pub fn basename(path: &str) -> &str {
+    path.rsplit('/').find(|p| !p.is_empty()).unwrap_or("/")
+}
+
+// flat map of absolute paths to nodes, cheap enough to be cloned for every session
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    nodes: BTreeMap<String, Node>,
+    // bytes of all the files
+    used: usize,
+    // max bytes of all the files, writes fail beyond it
+    limit: usize,
+}
+
+fn file_size(node: &Node) -> usize {
+    match &node.kind {
+        Kind::File(contents) => contents.len(),
+        _ => 0,
+    }
+}
+
+impl Filesystem {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("/".to_owned(), Node::directory(0o755));
+        Self {
+            nodes,
+            used: 0,
+            limit: usize::MAX,
+        }
+    }
+
+    // allow up to size bytes to be written on top of the current files
+    pub fn set_budget(&mut self, size: usize) {
+        self.limit = self.used.saturating_add(size);
+    }
+
+    // account for a file changing from old to new bytes
+    fn reserve(&mut self, old: usize, new: usize) -> Result<(), Error> {
+        let used = self.used - old + new;
+        if new > old && used > self.limit {
+            return Err(NO_SPACE);
+        }
+        self.used = used;
+        Ok(())
+    }
+
+    // resolve symlinks in every component of an absolute path, the last component
+    // is only followed if requested and doesn't need to exist
+    pub fn resolve(&self, path: &str, follow: bool) -> Result<String, Error> {
+        let mut path = join("/", path);
+
+        for _ in 0..MAX_LINKS {
+            let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+            let mut resolved = "/".to_owned();
+            let mut redirect = None;
+
+            for (i, component) in components.iter().enumerate() {
+                let current = join(&resolved, component);
+                let last = i == components.len() - 1;
+
+                match self.nodes.get(&current) {
+                    Some(Node {
+                        kind: Kind::Symlink(target),
+                        ..
+                    }) if !last || follow => {
+                        let target = join(&resolved, target);
+                        redirect = Some(join(&target, &components[i + 1..].join("/")));
+                        break;
+                    }
+                    Some(node) if !last && !node.is_dir() => return Err(NOT_A_DIRECTORY),
+                    None if !last => return Err(NOT_FOUND),
+                    _ => {}
+                }
+
+                resolved = current;
+            }
+
+            match redirect {
+                Some(target) => path = target,
+                None => return Ok(resolved),
+            }
+        }
+
+        Err(TOO_MANY_LINKS)
+    }
+
+    // stat
+    pub fn get(&self, path: &str) -> Result<(String, &Node), Error> {
+        let path = self.resolve(path, true)?;
+        match self.nodes.get(&path) {
+            Some(node) => Ok((path, node)),
+            None => Err(NOT_FOUND),
+        }
+    }
+
+    // lstat
+    pub fn get_link(&self, path: &str) -> Result<(String, &Node), Error> {
+        let path = self.resolve(path, false)?;
+        match self.nodes.get(&path) {
+            Some(node) => Ok((path, node)),
+            None => Err(NOT_FOUND),
+        }
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.get(path).is_ok()
+    }
+
+    pub fn read(&self, path: &str) -> Result<Arc<Vec<u8>>, Error> {
+        match &self.get(path)?.1.kind {
+            Kind::File(data) => Ok(data.clone()),
+            _ => Err(IS_A_DIRECTORY),
+        }
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<(&str, &Node)>, Error> {
+        let (path, node) = self.get(path)?;
+        if !node.is_dir() {
+            return Err(NOT_A_DIRECTORY);
+        }
+
+        let prefix = if path == "/" {
+            path
+        } else {
+            format!("{}/", path)
+        };
+
+        Ok(self
+            .nodes
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter(|(p, _)| p.len() > prefix.len() && !p[prefix.len()..].contains('/'))
+            .map(|(p, node)| (&p[prefix.len()..], node))
+            .collect())
+    }
+
+    // check that the parent of a new node exists and is a directory
+    fn check_parent(&self, path: &str) -> Result<(), Error> {
+        match self.nodes.get(&parent(path)) {
+            Some(node) if node.is_dir() => Ok(()),
+            Some(_) => Err(NOT_A_DIRECTORY),
+            None => Err(NOT_FOUND),
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        append: bool,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(), Error> {
+        let path = self.resolve(path, true)?;
+        match self.nodes.get(&path) {
+            Some(
+                node @ Node {
+                    kind: Kind::File(_),
+                    ..
+                },
+            ) => {
+                let old = file_size(node);
+                let new = if append { old + data.len() } else { data.len() };
+                self.reserve(old, new)?;
+            }
+            Some(_) => return Err(IS_A_DIRECTORY),
+            None => {
+                self.check_parent(&path)?;
+                self.reserve(0, data.len())?;
+            }
+        }
+
+        match self.nodes.get_mut(&path) {
+            Some(Node {
+                kind: Kind::File(contents),
+                modified,
+                ..
+            }) => {
+                let contents = Arc::make_mut(contents);
+                if !append {
+                    contents.clear();
+                }
+                contents.extend_from_slice(data);
+                *modified = Utc::now();
+            }
+            _ => {
+                self.nodes
+                    .insert(path, Node::file(data.to_vec(), 0o644).owned_by(uid, gid));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn mkdir(&mut self, path: &str, parents: bool, uid: u32, gid: u32) -> Result<(), Error> {
+        if parents {
+            let parent = parent(&join("/", path));
+            if !self.exists(&parent) {
+                self.mkdir(&parent, true, uid, gid)?;
+            }
+        }
+
+        let path = self.resolve(path, true)?;
+        if let Some(node) = self.nodes.get(&path) {
+            return if parents && node.is_dir() {
+                Ok(())
+            } else {
+                Err(EXISTS)
+            };
+        }
+
+        self.check_parent(&path)?;
+        self.nodes
+            .insert(path, Node::directory(0o755).owned_by(uid, gid));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &str, recursive: bool) -> Result<(), Error> {
+        let (path, node) = self.get_link(path)?;
+        if path == "/" {
+            return Err(BUSY);
+        }
+
+        if node.is_dir() {
+            if !recursive {
+                return Err(IS_A_DIRECTORY);
+            }
+            let prefix = format!("{}/", path);
+            let mut freed = 0;
+            self.nodes.retain(|p, node| {
+                let keep = !p.starts_with(&prefix);
+                if !keep {
+                    freed += file_size(node);
+                }
+                keep
+            });
+            self.used -= freed;
+        }
+
+        if let Some(node) = self.nodes.remove(&path) {
+            self.used -= file_size(&node);
+        }
+        Ok(())
+    }
+
+    pub fn chmod(&mut self, path: &str, mode: u32) -> Result<(), Error> {
+        let path = self.resolve(path, true)?;
+        match self.nodes.get_mut(&path) {
+            Some(node) => {
+                node.mode = mode & 0o7777;
+                Ok(())
+            }
+            None => Err(NOT_FOUND),
+        }
+    }
+
+    // add a node from a device image, creating the missing parent directories
+    pub fn insert(&mut self, path: &str, node: Node) {
+        let path = join("/", path);
+        let parent = parent(&path);
+        if !self.nodes.contains_key(&parent) {
+            self.insert(&parent, Node::directory(0o755));
+        }
+        self.used += file_size(&node);
+        if let Some(old) = self.nodes.insert(path, node) {
+            self.used -= file_size(&old);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -36,6 +37,7 @@ pub fn ssh(port_num: u64, data: &str) -> String {
         proto: "ssh".to_owned(),
         address: format!("0.0.0.0:{}", port_num),
         commands,
+        path: PathBuf::new(),
         config,
     })
     .unwrap()
@@ -66,6 +68,7 @@ pub fn telnet(port_num: u64, data: &str) -> String {
         proto: "telnet".to_owned(),
         address: format!("0.0.0.0:{}", port_num),
         commands,
+        path: PathBuf::new(),
         config,
     })
     .unwrap()
@@ -82,6 +85,7 @@ pub fn tcp(port_num: u64, data: &str) -> String {
         proto: "tcp".to_owned(),
         address: format!("0.0.0.0:{}", port_num),
         commands,
+        path: PathBuf::new(),
         config,
     })
     .unwrap()
@@ -98,6 +102,7 @@ pub fn udp(port_num: u64, data: &str) -> String {
         proto: "udp".to_owned(),
         address: format!("0.0.0.0:{}", port_num),
         commands,
+        path: PathBuf::new(),
         config,
     })
     .unwrap()
@@ -137,11 +142,11 @@ pub fn http(
         config.insert("tls".to_string(), serde_yaml::to_value(true).unwrap());
         config.insert(
             "key".to_string(),
-            serde_yaml::to_value(crate::protocols::http::config::DEFAULT_KEY_FILE).unwrap(),
+            serde_yaml::to_value(crate::protocols::tls::DEFAULT_KEY_FILE).unwrap(),
         );
         config.insert(
             "certificate".to_string(),
-            serde_yaml::to_value(crate::protocols::http::config::DEFAULT_CERT_FILE).unwrap(),
+            serde_yaml::to_value(crate::protocols::tls::DEFAULT_CERT_FILE).unwrap(),
         );
     }
 
@@ -149,6 +154,7 @@ pub fn http(
         proto: "http".to_owned(),
         address: format!("0.0.0.0:{}", port_num),
         commands,
+        path: PathBuf::new(),
         config,
     })
     .unwrap()
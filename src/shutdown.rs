@@ -0,0 +1,124 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+
+// entry added to the records of the sessions interrupted by a shutdown
+pub const ENTRY: &str = "sensor shutdown";
+
+lazy_static! {
+    // set once a shutdown has been requested
+    static ref STATE: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+    // set once the connections still active are out of time to close themselves
+    static ref EXPIRED: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+// connections still being handled
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// keeps a connection counted as active until dropped
+struct Active;
+
+impl Active {
+    fn new() -> Self {
+        ACTIVE.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn trigger() {
+    let _ = STATE.0.send(true);
+}
+
+pub fn triggered() -> bool {
+    *STATE.1.borrow()
+}
+
+// resolves once the state is set
+async fn set(state: &watch::Receiver<bool>) {
+    let mut state = state.clone();
+    while !*state.borrow() {
+        if state.changed().await.is_err() {
+            // the sender is static, this never happens
+            return;
+        }
+    }
+}
+
+// resolves once a shutdown has been requested
+pub async fn wait() {
+    set(&STATE.1).await
+}
+
+// run a future until it completes or a shutdown is requested, in which case it's dropped
+pub async fn until<F: Future>(future: F) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = wait() => None,
+    }
+}
+
+// handle a connection, the shutdown waits for it to close itself once requested, it's
+// dropped (and its record saved) if it's still active past the deadline
+pub async fn track<F: Future>(future: F) {
+    let _active = Active::new();
+    tokio::select! {
+        _ = future => {}
+        _ = set(&EXPIRED.1) => {}
+    }
+}
+
+// wait for SIGINT or SIGTERM, returning the name of the signal
+#[cfg(unix)]
+pub async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt()).expect("could not handle SIGINT");
+    let mut terminate = signal(SignalKind::terminate()).expect("could not handle SIGTERM");
+
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> &'static str {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("could not handle ctrl-c");
+    "ctrl-c"
+}
+
+// wait for the active connections to be closed
+async fn closed() {
+    while ACTIVE.load(Ordering::SeqCst) > 0 {
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// wait for the active connections to close themselves, up to the deadline, then drop
+// the ones left, returning how many there were
+pub async fn drain(deadline: Duration) -> usize {
+    if timeout(deadline, closed()).await.is_ok() {
+        return 0;
+    }
+
+    let active = ACTIVE.load(Ordering::SeqCst);
+    let _ = EXPIRED.0.send(true);
+    // dropping them saves their records
+    let _ = timeout(Duration::from_secs(1), closed()).await;
+
+    active
+}
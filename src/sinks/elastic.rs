@@ -0,0 +1,105 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::warn;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::Event;
+
+// events indexed through the bulk api of Elasticsearch or OpenSearch
+pub struct Sink {
+    url: String,
+    index: String,
+    credentials: Option<(String, Option<String>)>,
+    client: Client,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    errors: bool,
+    #[serde(default)]
+    items: Vec<serde_json::Value>,
+}
+
+impl Sink {
+    pub fn new(
+        url: &str,
+        index: &str,
+        username: Option<String>,
+        password: Option<String>,
+        timeout: u64,
+    ) -> Result<Self, String> {
+        // an invalid format would panic when indexing
+        let mut name = String::new();
+        write!(name, "{}", Utc::now().format(index))
+            .map_err(|_| format!("invalid index name format '{}'", index))?;
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .map_err(|e| format!("could not create elasticsearch client: {}", e))?;
+
+        Ok(Self {
+            url: format!("{}/_bulk", url.trim_end_matches('/')),
+            index: index.to_owned(),
+            credentials: username.map(|username| (username, password)),
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    async fn send(&mut self, events: &[Event]) -> Result<(), String> {
+        let mut body = vec![];
+        for event in events {
+            let action =
+                json!({ "index": { "_index": event.time.format(&self.index).to_string() } });
+            for line in &[action, json!(event)] {
+                serde_json::to_writer(&mut body, line)
+                    .map_err(|e| format!("could not convert event to json: {}", e))?;
+                body.push(b'\n');
+            }
+        }
+
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/x-ndjson")
+            .body(body);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, password.as_ref());
+        }
+
+        let response: Response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("could not index events: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("could not parse bulk response: {}", e))?;
+
+        // retrying would index the successful ones twice, so rejected events are only reported
+        if response.errors {
+            let rejected: Vec<&serde_json::Value> = response
+                .items
+                .iter()
+                .filter_map(|item| item.get("index")?.get("error"))
+                .collect();
+            warn!(
+                "{} of {} events rejected by {}: {}",
+                rejected.len(),
+                events.len(),
+                self.url,
+                rejected.first().map(|e| e.to_string()).unwrap_or_default()
+            );
+        }
+
+        Ok(())
+    }
+}
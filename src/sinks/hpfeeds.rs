@@ -0,0 +1,426 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use super::Event;
+use crate::record::Data;
+
+const OP_ERROR: u8 = 0;
+const OP_INFO: u8 = 1;
+const OP_AUTH: u8 = 2;
+const OP_PUBLISH: u8 = 3;
+
+// bigger messages from the broker are considered garbage
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// channel events are published to
+#[derive(Clone, Deserialize, Debug)]
+pub struct Channel {
+    pub name: String,
+    // types of the events published, all if empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    // services the events are published for, all if empty
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+impl Channel {
+    fn accepts(&self, event: &Event) -> bool {
+        let matches = |kind: &str| self.events.iter().any(|e| e == kind);
+        let selected = match &event.data {
+            // public key authentications also carry the fingerprint of the client key
+            Data::Authentication { key: Some(_), .. } if matches("fingerprint") => true,
+            data => matches(data.kind()),
+        };
+
+        (self.events.is_empty() || selected)
+            && (self.services.is_empty() || self.services.contains(&event.service))
+    }
+}
+
+// publisher for brokers speaking the HPFeeds protocol
+pub struct Sink {
+    address: String,
+    ident: String,
+    secret: String,
+    channels: Vec<Channel>,
+    // reconnected after an error
+    stream: Option<TcpStream>,
+}
+
+// length prefixed string
+fn push_name(data: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    if name.len() > 255 {
+        return Err(format!("'{}' is too long", name));
+    }
+    data.push(name.len() as u8);
+    data.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+fn message(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = ((payload.len() + 5) as u32).to_be_bytes().to_vec();
+    data.push(opcode);
+    data.extend_from_slice(payload);
+    data
+}
+
+async fn read_message(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), String> {
+    let error = |e: std::io::Error| format!("could not read from hpfeeds broker: {}", e);
+
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await.map_err(error)?;
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if !(5..=MAX_MESSAGE_SIZE).contains(&size) {
+        return Err(format!("invalid hpfeeds message size {}", size));
+    }
+
+    let mut payload = vec![0u8; size - 5];
+    stream.read_exact(&mut payload).await.map_err(error)?;
+    Ok((header[4], payload))
+}
+
+// name of the broker and nonce to authenticate with, from the payload of an info message
+fn parse_info(payload: &[u8]) -> Option<(String, &[u8])> {
+    let (&size, rest) = payload.split_first()?;
+    let name = rest.get(..size as usize)?;
+    Some((
+        String::from_utf8_lossy(name).to_string(),
+        &rest[size as usize..],
+    ))
+}
+
+impl Sink {
+    pub fn new(
+        address: &str,
+        ident: &str,
+        secret: &str,
+        channels: &[Channel],
+    ) -> Result<Self, String> {
+        if channels.is_empty() {
+            return Err("no hpfeeds channels configured".to_owned());
+        }
+        // checked now rather than at every publish
+        let mut scratch = vec![];
+        push_name(&mut scratch, ident)?;
+        for channel in channels {
+            push_name(&mut scratch, &channel.name)?;
+        }
+
+        Ok(Self {
+            address: address.to_owned(),
+            ident: ident.to_owned(),
+            secret: secret.to_owned(),
+            channels: channels.to_vec(),
+            stream: None,
+        })
+    }
+
+    // the broker sends its name and a nonce, the client answers with its ident and
+    // sha1(nonce + secret)
+    async fn connect(&self) -> Result<TcpStream, String> {
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|e| format!("could not connect to {}: {}", self.address, e))?;
+
+        let (opcode, payload) = timeout(Duration::from_secs(10), read_message(&mut stream))
+            .await
+            .map_err(|_| format!("no info message from {}", self.address))??;
+        let (name, nonce) = match parse_info(&payload) {
+            Some(info) if opcode == OP_INFO => info,
+            _ => return Err(format!("unexpected message from {}", self.address)),
+        };
+
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(nonce);
+        hash.update(self.secret.as_bytes());
+
+        let mut auth = vec![];
+        push_name(&mut auth, &self.ident)?;
+        auth.extend_from_slice(&hash.digest().bytes());
+        stream
+            .write_all(&message(OP_AUTH, &auth))
+            .await
+            .map_err(|e| format!("could not authenticate to {}: {}", self.address, e))?;
+
+        info!("connected to hpfeeds broker {} ({})", name, self.address);
+
+        Ok(stream)
+    }
+
+    // the broker only talks to report errors, for instance a failed authentication,
+    // and then closes the connection
+    fn check(&self, stream: &TcpStream) -> Result<(), String> {
+        let mut buf = [0u8; 1024];
+        match stream.try_read(&mut buf) {
+            Ok(0) => Err(format!("connection closed by {}", self.address)),
+            Ok(n) if n > 5 && buf[4] == OP_ERROR => Err(format!(
+                "error from {}: {}",
+                self.address,
+                String::from_utf8_lossy(&buf[5..n])
+            )),
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(format!("could not read from {}: {}", self.address, e)),
+        }
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    async fn send(&mut self, events: &[Event]) -> Result<(), String> {
+        let mut data = vec![];
+        for event in events {
+            let json = serde_json::to_vec(event)
+                .map_err(|e| format!("could not convert event to json: {}", e))?;
+            for channel in self.channels.iter().filter(|c| c.accepts(event)) {
+                let mut publish = vec![];
+                push_name(&mut publish, &self.ident)?;
+                push_name(&mut publish, &channel.name)?;
+                publish.extend_from_slice(&json);
+                data.extend(message(OP_PUBLISH, &publish));
+            }
+        }
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // dropped on error, so that the next attempt reconnects
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect().await?,
+        };
+        self.check(&stream)?;
+        stream
+            .write_all(&data)
+            .await
+            .map_err(|e| format!("could not publish to {}: {}", self.address, e))?;
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+    use tokio::net::TcpListener;
+
+    use crate::sinks::Sink as _;
+
+    fn channel(name: &str, events: &[&str]) -> Channel {
+        Channel {
+            name: name.to_owned(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            services: vec![],
+        }
+    }
+
+    fn event(data: Data) -> Event {
+        Event {
+            time: Utc::now(),
+            session: "01GC5Q7N1V3XK8TQ2R9M4E6B0S".to_owned(),
+            sensor: "sensor".to_owned(),
+            hostname: "sensor".to_owned(),
+            protocol: "ssh".to_owned(),
+            service: "example-ssh".to_owned(),
+            address: "1.2.3.4".to_owned(),
+            port: 51234,
+            data,
+        }
+    }
+
+    // run a broker accepting a single connection
+    async fn broker<F, T>(serve: F) -> (String, tokio::task::JoinHandle<T::Output>)
+    where
+        F: FnOnce(TcpStream) -> T + Send + 'static,
+        T: std::future::Future + Send + 'static,
+        T::Output: Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await
+        });
+        (address, handle)
+    }
+
+    // parse a message with its opcode and the length prefixed strings at the beginning of
+    // its payload, returning what's left
+    fn split(data: &[u8], names: usize) -> (u8, Vec<String>, Vec<u8>) {
+        let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        assert_eq!(size, data.len());
+        let mut rest = &data[5..];
+        let mut strings = vec![];
+        for _ in 0..names {
+            let (name, tail) = parse_info(rest).unwrap();
+            strings.push(name);
+            rest = tail;
+        }
+        (data[4], strings, rest.to_vec())
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(message(OP_PUBLISH, b"abc"), vec![0, 0, 0, 8, 3, b'a', b'b', b'c']);
+        assert_eq!(message(OP_ERROR, b""), vec![0, 0, 0, 5, 0]);
+
+        let mut data = vec![];
+        push_name(&mut data, "ident").unwrap();
+        push_name(&mut data, "").unwrap();
+        assert_eq!(data, b"\x05ident\x00");
+
+        assert!(push_name(&mut data, &"x".repeat(255)).is_ok());
+        assert!(push_name(&mut data, &"x".repeat(256)).is_err());
+        assert!(Sink::new("127.0.0.1:10000", &"x".repeat(256), "s", &[channel("c", &[])]).is_err());
+        assert!(Sink::new("127.0.0.1:10000", "ident", "s", &[]).is_err());
+    }
+
+    #[test]
+    fn info_messages() {
+        assert_eq!(
+            parse_info(b"\x06brokernonce"),
+            Some(("broker".to_owned(), &b"nonce"[..]))
+        );
+        assert_eq!(parse_info(b"\x06broker"), Some(("broker".to_owned(), &b""[..])));
+        assert_eq!(parse_info(b"\x00nonce"), Some((String::new(), &b"nonce"[..])));
+
+        // shorter than the length of the name
+        assert_eq!(parse_info(b""), None);
+        assert_eq!(parse_info(b"\x06"), None);
+        assert_eq!(parse_info(b"\x06broke"), None);
+        assert_eq!(parse_info(b"\xffbroker"), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_and_oversized_messages() {
+        for data in [
+            vec![],
+            vec![0, 0, 0],
+            vec![0, 0, 0, 10],
+            // shorter than the header itself
+            vec![0, 0, 0, 4, OP_INFO],
+            vec![0, 0, 0, 0, OP_INFO],
+            // bigger than the max size
+            vec![0x7f, 0xff, 0xff, 0xff, OP_INFO],
+            // payload shorter than announced
+            vec![0, 0, 0, 10, OP_INFO, 1, 2, 3],
+        ] {
+            let (address, _) = broker(move |mut stream| async move {
+                stream.write_all(&data).await.unwrap();
+            })
+            .await;
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            assert!(read_message(&mut stream).await.is_err());
+        }
+
+        let (address, _) = broker(|mut stream| async move {
+            stream.write_all(&message(OP_INFO, b"\x01bnonce")).await.unwrap();
+        })
+        .await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert_eq!(
+            read_message(&mut stream).await.unwrap(),
+            (OP_INFO, b"\x01bnonce".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_info_messages() {
+        for info in [
+            message(OP_INFO, b""),
+            message(OP_INFO, b"\x10broker"),
+            message(OP_ERROR, b"\x06brokernonce"),
+        ] {
+            let (address, _) = broker(move |mut stream| async move {
+                stream.write_all(&info).await.unwrap();
+                // keep the connection open until the client gives up
+                let mut buf = vec![];
+                stream.read_to_end(&mut buf).await.ok();
+            })
+            .await;
+
+            let sink = Sink::new(&address, "ident", "secret", &[channel("c", &[])]).unwrap();
+            assert_eq!(
+                sink.connect().await.err(),
+                Some(format!("unexpected message from {}", address))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn publish() {
+        let (address, broker) = broker(|mut stream| async move {
+            stream
+                .write_all(&message(OP_INFO, b"\x06brokernonce"))
+                .await
+                .unwrap();
+            let mut data = vec![];
+            stream.read_to_end(&mut data).await.unwrap();
+            data
+        })
+        .await;
+
+        let channels = [channel("all", &[]), channel("auth", &["auth"])];
+        let mut sink = Sink::new(&address, "ident", "secret", &channels).unwrap();
+        sink.send(&[
+            event(Data::Command("uname -a".to_owned())),
+            event(Data::Authentication {
+                username: "root".to_owned(),
+                password: Some("toor".to_owned()),
+                key: None,
+            }),
+        ])
+        .await
+        .unwrap();
+        drop(sink);
+
+        let data = broker.await.unwrap();
+        let mut messages = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            messages.push(&rest[..size]);
+            rest = &rest[size..];
+        }
+        assert_eq!(messages.len(), 4);
+
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(b"nonce");
+        hash.update(b"secret");
+        let (opcode, names, digest) = split(messages[0], 1);
+        assert_eq!((opcode, names), (OP_AUTH, vec!["ident".to_owned()]));
+        assert_eq!(digest, hash.digest().bytes());
+
+        let published: Vec<(u8, Vec<String>)> = messages[1..]
+            .iter()
+            .map(|message| {
+                let (opcode, names, json) = split(message, 2);
+                let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+                assert_eq!(json["service"], "example-ssh");
+                (opcode, names)
+            })
+            .collect();
+        let names = |channel: &str| vec!["ident".to_owned(), channel.to_owned()];
+        assert_eq!(
+            published,
+            vec![
+                (OP_PUBLISH, names("all")),
+                (OP_PUBLISH, names("all")),
+                (OP_PUBLISH, names("auth")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use super::Event;
+
+// events appended to a JSON lines file
+pub struct Sink {
+    path: PathBuf,
+    // reopened after an error, in case the file has been rotated
+    file: Option<File>,
+}
+
+impl Sink {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            file: None,
+        }
+    }
+
+    async fn open(path: &Path) -> Result<File, String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("could not create {:?}: {}", parent, e))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| format!("could not open {:?}: {}", path, e))
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    async fn send(&mut self, events: &[Event]) -> Result<(), String> {
+        let mut data = vec![];
+        for event in events {
+            serde_json::to_writer(&mut data, event)
+                .map_err(|e| format!("could not convert event to json: {}", e))?;
+            data.push(b'\n');
+        }
+
+        if self.file.is_none() {
+            self.file = Some(Self::open(&self.path).await?);
+        }
+        let file = self.file.as_mut().unwrap();
+
+        let written = match file.write_all(&data).await {
+            Ok(_) => file.flush().await,
+            Err(e) => Err(e),
+        };
+        written.map_err(|e| {
+            self.file = None;
+            format!("could not write to {:?}: {}", self.path, e)
+        })
+    }
+}
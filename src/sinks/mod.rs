@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::{sleep, timeout, timeout_at, Instant},
+};
+
+use crate::record::Data;
+
+mod elastic;
+mod hpfeeds;
+mod jsonl;
+mod syslog;
+mod webhook;
+
+lazy_static! {
+    // queues of the running sinks
+    static ref QUEUES: RwLock<Vec<Queue>> = RwLock::new(vec![]);
+    static ref WORKERS: RwLock<Vec<JoinHandle<()>>> = RwLock::new(vec![]);
+}
+
+// entry of a record, along with the session it belongs to
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    // id of the session
+    pub session: String,
+    pub sensor: String,
+    pub hostname: String,
+    pub protocol: String,
+    pub service: String,
+    pub address: String,
+    pub port: u16,
+    #[serde(flatten)]
+    pub data: Data,
+}
+
+#[async_trait]
+pub trait Sink: Send {
+    // deliver a batch of events, it's retried as a whole on error
+    async fn send(&mut self, events: &[Event]) -> Result<(), String>;
+}
+
+fn default_buffer() -> usize {
+    10_000
+}
+
+fn default_batch() -> usize {
+    100
+}
+
+fn default_interval() -> u64 {
+    1000
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_retry_delay() -> u64 {
+    500
+}
+
+fn default_app_name() -> String {
+    "medusa".to_owned()
+}
+
+fn default_facility() -> u8 {
+    // local0
+    16
+}
+
+fn default_index() -> String {
+    "medusa".to_owned()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Kind {
+    Jsonl {
+        path: String,
+    },
+    // udp://host:port, tcp://host:port or unix:///path
+    Syslog {
+        address: String,
+        #[serde(default = "default_app_name")]
+        app_name: String,
+        #[serde(default = "default_facility")]
+        facility: u8,
+    },
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        // seconds
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    Hpfeeds {
+        // host:port of the broker
+        address: String,
+        ident: String,
+        secret: String,
+        channels: Vec<hpfeeds::Channel>,
+    },
+    #[serde(alias = "opensearch")]
+    Elasticsearch {
+        url: String,
+        // strftime format of the event time
+        #[serde(default = "default_index")]
+        index: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        // seconds
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+}
+
+// output sink as defined in the global configuration file
+#[derive(Clone, Deserialize, Debug)]
+pub struct Config {
+    #[serde(flatten)]
+    pub kind: Kind,
+    // events queued before new ones are dropped
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+    // max events per delivery
+    #[serde(default = "default_batch")]
+    pub batch: usize,
+    // milliseconds to wait for a batch to fill up
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    // milliseconds before the first retry, doubled at every attempt
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+}
+
+impl Config {
+    fn name(&self) -> String {
+        match &self.kind {
+            Kind::Jsonl { path } => format!("jsonl:{}", path),
+            Kind::Syslog { address, .. } => format!("syslog:{}", address),
+            Kind::Webhook { url, .. } => format!("webhook:{}", url),
+            Kind::Hpfeeds { address, .. } => format!("hpfeeds:{}", address),
+            Kind::Elasticsearch { url, .. } => format!("elasticsearch:{}", url),
+        }
+    }
+
+    fn sink(&self) -> Result<Box<dyn Sink>, String> {
+        Ok(match &self.kind {
+            Kind::Jsonl { path } => Box::new(jsonl::Sink::new(path)),
+            Kind::Syslog {
+                address,
+                app_name,
+                facility,
+            } => Box::new(syslog::Sink::new(address, app_name, *facility)?),
+            Kind::Webhook {
+                url,
+                headers,
+                timeout,
+            } => Box::new(webhook::Sink::new(url, headers, *timeout)?),
+            Kind::Hpfeeds {
+                address,
+                ident,
+                secret,
+                channels,
+            } => Box::new(hpfeeds::Sink::new(address, ident, secret, channels)?),
+            Kind::Elasticsearch {
+                url,
+                index,
+                username,
+                password,
+                timeout,
+            } => Box::new(elastic::Sink::new(
+                url,
+                index,
+                username.clone(),
+                password.clone(),
+                *timeout,
+            )?),
+        })
+    }
+}
+
+// events waiting to be delivered to a sink
+struct Queue {
+    name: String,
+    sender: mpsc::Sender<Event>,
+    // events dropped because the queue was full, reported by the worker
+    dropped: Arc<AtomicUsize>,
+}
+
+async fn deliver(name: &str, sink: &mut Box<dyn Sink>, events: &[Event], config: &Config) {
+    let mut delay = Duration::from_millis(config.retry_delay);
+    for attempt in 0..=config.retries {
+        match sink.send(events).await {
+            Ok(_) => {
+                debug!("delivered {} events to {}", events.len(), name);
+                return;
+            }
+            Err(e) if attempt < config.retries => {
+                warn!("{}: {}, retrying in {:?}", name, e, delay);
+                sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => error!("{}: {}, dropping {} events", name, e, events.len()),
+        }
+    }
+}
+
+// deliver the events of a queue in batches until it's closed
+async fn work(
+    name: String,
+    mut sink: Box<dyn Sink>,
+    mut receiver: mpsc::Receiver<Event>,
+    dropped: Arc<AtomicUsize>,
+    config: Config,
+) {
+    let mut batch = Vec::with_capacity(config.batch);
+    while let Some(event) = receiver.recv().await {
+        batch.push(event);
+
+        let deadline = Instant::now() + Duration::from_millis(config.interval);
+        while batch.len() < config.batch {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(event)) => batch.push(event),
+                _ => break,
+            }
+        }
+
+        deliver(&name, &mut sink, &batch, &config).await;
+        batch.clear();
+
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("{}: {} events dropped, the queue was full", name, dropped);
+        }
+    }
+}
+
+// start a worker for every sink
+pub fn start(configs: &[Config]) -> Result<(), String> {
+    let mut queues = QUEUES.write().unwrap();
+    let mut workers = WORKERS.write().unwrap();
+
+    for config in configs {
+        let name = config.name();
+        let sink = config.sink()?;
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        info!("sending events to {}", &name);
+
+        workers.push(tokio::spawn(work(
+            name.to_owned(),
+            sink,
+            receiver,
+            dropped.clone(),
+            config.clone(),
+        )));
+        queues.push(Queue {
+            name,
+            sender,
+            dropped,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    !QUEUES.read().unwrap().is_empty()
+}
+
+// queue an event to every sink, never blocks
+pub fn publish(event: Event) {
+    for queue in QUEUES.read().unwrap().iter() {
+        match queue.sender.try_send(event.clone()) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                queue.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => debug!("{} is closed", queue.name),
+        }
+    }
+}
+
+// close the queues and wait for the pending events to be delivered, up to the deadline
+pub async fn stop(deadline: Duration) {
+    QUEUES.write().unwrap().clear();
+
+    let workers: Vec<JoinHandle<()>> = WORKERS.write().unwrap().drain(..).collect();
+    if workers.is_empty() {
+        return;
+    }
+
+    if timeout(deadline, futures::future::join_all(workers))
+        .await
+        .is_err()
+    {
+        warn!("some events could not be delivered before exiting");
+    }
+}
@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream, UdpSocket, UnixDatagram},
+};
+
+use super::Event;
+use crate::record::Data;
+
+// private enterprise number reserved for documentation by RFC 5424, used as the
+// structured data id
+const SD_ID: &str = "medusa@32473";
+
+enum Transport {
+    Udp(String),
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Unix(UnixDatagram),
+}
+
+// RFC 5424 messages over udp, tcp (with octet counting framing) or a unix socket
+pub struct Sink {
+    transport: Transport,
+    app_name: String,
+    facility: u8,
+    // reconnected after an error
+    connection: Option<Connection>,
+}
+
+// printable ascii without spaces, "-" if empty
+fn header_field(value: &str, max: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if value.is_empty() {
+        "-".to_owned()
+    } else {
+        value
+    }
+}
+
+fn param_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+impl Sink {
+    pub fn new(address: &str, app_name: &str, facility: u8) -> Result<Self, String> {
+        let transport = if let Some(address) = address.strip_prefix("udp://") {
+            Transport::Udp(address.to_owned())
+        } else if let Some(address) = address.strip_prefix("tcp://") {
+            Transport::Tcp(address.to_owned())
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            Transport::Unix(PathBuf::from(path))
+        } else {
+            return Err(format!(
+                "invalid syslog address '{}', expected udp://, tcp:// or unix://",
+                address
+            ));
+        };
+
+        if facility > 23 {
+            return Err(format!("invalid syslog facility {}", facility));
+        }
+
+        Ok(Self {
+            transport,
+            app_name: app_name.to_owned(),
+            facility,
+            connection: None,
+        })
+    }
+
+    async fn connect(&self) -> Result<Connection, String> {
+        let error = |e: std::io::Error| format!("could not connect to syslog: {}", e);
+        Ok(match &self.transport {
+            Transport::Udp(address) => {
+                let target = lookup_host(address)
+                    .await
+                    .map_err(error)?
+                    .next()
+                    .ok_or_else(|| format!("could not resolve {}", address))?;
+                let local = if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local).await.map_err(error)?;
+                socket.connect(target).await.map_err(error)?;
+                Connection::Udp(socket)
+            }
+            Transport::Tcp(address) => {
+                Connection::Tcp(TcpStream::connect(address).await.map_err(error)?)
+            }
+            Transport::Unix(path) => {
+                let socket = UnixDatagram::unbound().map_err(error)?;
+                socket.connect(path).map_err(error)?;
+                Connection::Unix(socket)
+            }
+        })
+    }
+
+    fn format(&self, event: &Event) -> String {
+        let severity = match &event.data {
+            Data::Authentication { .. } | Data::Download { .. } => 5,
+            Data::Output(_) => 7,
+            _ => 6,
+        };
+
+        format!(
+            "<{}>1 {} {} {} {} {} [{} protocol=\"{}\" service=\"{}\" address=\"{}\" port=\"{}\"] {}",
+            self.facility as u32 * 8 + severity,
+            event.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            header_field(&event.hostname, 255),
+            header_field(&self.app_name, 48),
+            std::process::id(),
+            event.data.kind(),
+            SD_ID,
+            param_value(&event.protocol),
+            param_value(&event.service),
+            param_value(&event.address),
+            event.port,
+            event.data
+        )
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    async fn send(&mut self, events: &[Event]) -> Result<(), String> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect().await?);
+        }
+
+        for event in events {
+            let message = self.format(event);
+            let sent = match self.connection.as_mut().unwrap() {
+                Connection::Udp(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+                Connection::Tcp(stream) => {
+                    // RFC 6587 octet counting
+                    let frame = format!("{} {}", message.len(), message);
+                    stream.write_all(frame.as_bytes()).await
+                }
+                Connection::Unix(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+            };
+
+            if let Err(e) = sent {
+                self.connection = None;
+                return Err(format!("could not send to syslog: {}", e));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client,
+};
+
+use super::Event;
+
+// batches of events posted as a JSON array
+pub struct Sink {
+    url: String,
+    client: Client,
+}
+
+impl Sink {
+    pub fn new(url: &str, headers: &HashMap<String, String>, timeout: u64) -> Result<Self, String> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("invalid webhook header '{}': {}", name, e))?,
+                HeaderValue::from_str(value)
+                    .map_err(|e| format!("invalid value of webhook header '{}': {}", name, e))?,
+            );
+        }
+
+        let client = Client::builder()
+            .default_headers(map)
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .map_err(|e| format!("could not create webhook client: {}", e))?;
+
+        Ok(Self {
+            url: url.to_owned(),
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    async fn send(&mut self, events: &[Event]) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(events)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("could not post events: {}", e))
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    mpsc::{self, Receiver, SyncSender, TrySendError},
+    Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use rusqlite::{params, types::Value, Connection, Transaction};
+
+use crate::record::{Data, Entry, Reason, Record};
+
+// bumped on incompatible changes of the tables
+const SCHEMA_VERSION: i64 = 2;
+
+const SCHEMA: &str = "
+-- uid is the id of the session in the record files, duration is in seconds
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    created_at TEXT NOT NULL,
+    closed_at TEXT,
+    hostname TEXT NOT NULL,
+    protocol TEXT NOT NULL,
+    service TEXT NOT NULL,
+    address TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    entries INTEGER NOT NULL DEFAULT 0,
+    uid TEXT NOT NULL DEFAULT '',
+    sensor TEXT NOT NULL DEFAULT '',
+    destination_address TEXT NOT NULL DEFAULT '',
+    destination_port INTEGER NOT NULL DEFAULT 0,
+    duration REAL,
+    reason TEXT,
+    bytes_in INTEGER NOT NULL DEFAULT 0,
+    bytes_out INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS sessions_created_at ON sessions (created_at);
+CREATE INDEX IF NOT EXISTS sessions_uid ON sessions (uid);
+CREATE INDEX IF NOT EXISTS sessions_sensor ON sessions (sensor);
+CREATE INDEX IF NOT EXISTS sessions_address ON sessions (address);
+CREATE INDEX IF NOT EXISTS sessions_service ON sessions (service);
+
+CREATE TABLE IF NOT EXISTS tags (
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    tag TEXT NOT NULL,
+    PRIMARY KEY (session_id, tag)
+);
+CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+
+-- data is the text of logs, requests and outputs and the bytes of raw entries, the
+-- other types have a table of their own
+CREATE TABLE IF NOT EXISTS entries (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    time TEXT NOT NULL,
+    type TEXT NOT NULL,
+    data BLOB
+);
+CREATE INDEX IF NOT EXISTS entries_session_id ON entries (session_id);
+CREATE INDEX IF NOT EXISTS entries_type ON entries (type);
+
+CREATE TABLE IF NOT EXISTS credentials (
+    entry_id INTEGER PRIMARY KEY REFERENCES entries (id),
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    username TEXT NOT NULL,
+    password TEXT,
+    key TEXT
+);
+CREATE INDEX IF NOT EXISTS credentials_session_id ON credentials (session_id);
+CREATE INDEX IF NOT EXISTS credentials_username ON credentials (username);
+CREATE INDEX IF NOT EXISTS credentials_password ON credentials (password);
+
+-- exec is set for the single commands of a command line
+CREATE TABLE IF NOT EXISTS commands (
+    entry_id INTEGER PRIMARY KEY REFERENCES entries (id),
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    command TEXT NOT NULL,
+    exec INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS commands_session_id ON commands (session_id);
+CREATE INDEX IF NOT EXISTS commands_command ON commands (command);
+
+CREATE TABLE IF NOT EXISTS artifacts (
+    entry_id INTEGER PRIMARY KEY REFERENCES entries (id),
+    session_id INTEGER NOT NULL REFERENCES sessions (id),
+    tool TEXT NOT NULL,
+    url TEXT NOT NULL,
+    host TEXT NOT NULL,
+    filename TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS artifacts_session_id ON artifacts (session_id);
+CREATE INDEX IF NOT EXISTS artifacts_url ON artifacts (url);
+CREATE INDEX IF NOT EXISTS artifacts_host ON artifacts (host);
+";
+
+// columns added by version 2
+const MIGRATION_V2: &str = "
+ALTER TABLE sessions ADD COLUMN uid TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN sensor TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN destination_address TEXT NOT NULL DEFAULT '';
+ALTER TABLE sessions ADD COLUMN destination_port INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN duration REAL;
+ALTER TABLE sessions ADD COLUMN reason TEXT;
+ALTER TABLE sessions ADD COLUMN bytes_in INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN bytes_out INTEGER NOT NULL DEFAULT 0;
+";
+
+// messages queued before new ones are dropped
+const QUEUE_SIZE: usize = 100_000;
+// max messages written in a single transaction
+const BATCH_SIZE: usize = 1000;
+
+lazy_static! {
+    static ref SENDER: Mutex<Option<SyncSender<Message>>> = Mutex::new(None);
+    static ref WORKER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+// keys the sessions are referred to by until the worker inserts them
+static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+// messages dropped because the queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+enum Message {
+    Open {
+        key: u64,
+        uid: String,
+        created_at: DateTime<Utc>,
+        sensor: String,
+        hostname: String,
+        protocol: String,
+        service: String,
+        destination_address: String,
+        destination_port: u16,
+        address: String,
+        port: u16,
+    },
+    Entry {
+        key: u64,
+        entry: Entry,
+    },
+    Tag {
+        key: u64,
+        tag: String,
+    },
+    Close {
+        key: u64,
+        closed_at: DateTime<Utc>,
+        duration: f64,
+        reason: Option<Reason>,
+        entries: usize,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+}
+
+// same names as in the record files
+fn reason_name(reason: &Reason) -> String {
+    serde_json::to_value(reason)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+fn time(time: &DateTime<Utc>) -> String {
+    // fixed precision, so that times sort as strings
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn open(path: &str) -> Result<Connection, String> {
+    let conn = Connection::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    let error = |e: rusqlite::Error| format!("could not initialize {}: {}", path, e);
+
+    conn.pragma_update(None, "journal_mode", "WAL")
+        .map_err(error)?;
+    conn.pragma_update(None, "synchronous", "NORMAL")
+        .map_err(error)?;
+    conn.pragma_update(None, "foreign_keys", true)
+        .map_err(error)?;
+
+    let version: i64 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(error)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "{} has schema version {}, newer than the supported {}",
+            path, version, SCHEMA_VERSION
+        ));
+    }
+
+    if version == 1 {
+        info!("migrating {} to schema version {}", path, SCHEMA_VERSION);
+        conn.execute_batch(MIGRATION_V2).map_err(error)?;
+    }
+    conn.execute_batch(SCHEMA).map_err(error)?;
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+        .map_err(error)?;
+
+    Ok(conn)
+}
+
+fn write(
+    tx: &Transaction,
+    sessions: &mut HashMap<u64, i64>,
+    message: Message,
+) -> Result<(), rusqlite::Error> {
+    match message {
+        Message::Open {
+            key,
+            uid,
+            created_at,
+            sensor,
+            hostname,
+            protocol,
+            service,
+            destination_address,
+            destination_port,
+            address,
+            port,
+        } => {
+            tx.execute(
+                "INSERT INTO sessions (uid, created_at, sensor, hostname, protocol, service,
+                    destination_address, destination_port, address, port)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    uid,
+                    time(&created_at),
+                    sensor,
+                    hostname,
+                    protocol,
+                    service,
+                    destination_address,
+                    destination_port,
+                    address,
+                    port
+                ],
+            )?;
+            sessions.insert(key, tx.last_insert_rowid());
+        }
+        Message::Entry { key, entry } => {
+            // the session has been dropped with a full queue
+            let session = match sessions.get(&key) {
+                Some(session) => *session,
+                None => return Ok(()),
+            };
+
+            let kind = entry.data.kind();
+            let data = match &entry.data {
+                Data::Log(s) | Data::Request(s) | Data::Output(s) => Value::Text(s.to_owned()),
+                Data::Raw(bytes) => Value::Blob(bytes.to_owned()),
+                _ => Value::Null,
+            };
+            tx.execute(
+                "INSERT INTO entries (session_id, time, type, data) VALUES (?, ?, ?, ?)",
+                params![session, time(&entry.time), kind, data],
+            )?;
+            let id = tx.last_insert_rowid();
+
+            match entry.data {
+                Data::Authentication {
+                    username,
+                    password,
+                    key,
+                } => {
+                    tx.execute(
+                        "INSERT INTO credentials (entry_id, session_id, username, password, key)
+                         VALUES (?, ?, ?, ?, ?)",
+                        params![id, session, username, password, key],
+                    )?;
+                }
+                Data::Command(command) | Data::Exec(command) => {
+                    tx.execute(
+                        "INSERT INTO commands (entry_id, session_id, command, exec)
+                         VALUES (?, ?, ?, ?)",
+                        params![id, session, command, kind == "exec"],
+                    )?;
+                }
+                Data::Download {
+                    tool,
+                    url,
+                    host,
+                    filename,
+                } => {
+                    tx.execute(
+                        "INSERT INTO artifacts (entry_id, session_id, tool, url, host, filename)
+                         VALUES (?, ?, ?, ?, ?, ?)",
+                        params![id, session, tool, url, host, filename],
+                    )?;
+                }
+                _ => {}
+            }
+        }
+        Message::Tag { key, tag } => {
+            if let Some(session) = sessions.get(&key) {
+                tx.execute(
+                    "INSERT OR IGNORE INTO tags (session_id, tag) VALUES (?, ?)",
+                    params![session, tag],
+                )?;
+            }
+        }
+        Message::Close {
+            key,
+            closed_at,
+            duration,
+            reason,
+            entries,
+            bytes_in,
+            bytes_out,
+        } => {
+            if let Some(session) = sessions.remove(&key) {
+                tx.execute(
+                    "UPDATE sessions SET closed_at = ?, duration = ?, reason = ?, entries = ?,
+                        bytes_in = ?, bytes_out = ?
+                     WHERE id = ?",
+                    params![
+                        time(&closed_at),
+                        duration,
+                        reason.map(|reason| reason_name(&reason)),
+                        entries as i64,
+                        bytes_in as i64,
+                        bytes_out as i64,
+                        session
+                    ],
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// write the queued messages in batches, one transaction each, until the queue is closed
+fn work(mut conn: Connection, receiver: Receiver<Message>) {
+    let mut sessions = HashMap::new();
+
+    while let Ok(message) = receiver.recv() {
+        let tx = match conn.transaction() {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("sqlite: {}", e);
+                continue;
+            }
+        };
+
+        let mut batch = vec![message];
+        while batch.len() < BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(message) => batch.push(message),
+                Err(_) => break,
+            }
+        }
+
+        let size = batch.len();
+        for message in batch {
+            if let Err(e) = write(&tx, &mut sessions, message) {
+                error!("sqlite: {}", e);
+            }
+        }
+        match tx.commit() {
+            Ok(_) => debug!("sqlite: wrote {} messages", size),
+            Err(e) => error!("sqlite: {}", e),
+        }
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("sqlite: {} messages dropped, the queue was full", dropped);
+        }
+    }
+}
+
+// create the database if needed and start writing to it
+pub fn start(path: &str) -> Result<(), String> {
+    let conn = open(path)?;
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+
+    info!("storing sessions to {}", path);
+
+    *WORKER.lock().unwrap() = Some(thread::spawn(move || work(conn, receiver)));
+    *SENDER.lock().unwrap() = Some(sender);
+
+    Ok(())
+}
+
+// queue a message for the worker, never blocks
+fn send(message: Message) {
+    if let Some(sender) = SENDER.lock().unwrap().as_ref() {
+        if let Err(TrySendError::Full(_)) = sender.try_send(message) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// start storing a session, returns the key the next messages refer to it with
+pub fn session(record: &Record) -> Option<u64> {
+    if SENDER.lock().unwrap().is_none() {
+        return None;
+    }
+
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    send(Message::Open {
+        key,
+        uid: record.id.to_owned(),
+        created_at: record.created_at,
+        sensor: record.sensor.to_owned(),
+        hostname: record.hostname.to_owned(),
+        protocol: record.protocol.to_owned(),
+        service: record.service.to_owned(),
+        destination_address: record.destination_address.to_owned(),
+        destination_port: record.destination_port,
+        address: record.address.to_owned(),
+        port: record.port,
+    });
+    Some(key)
+}
+
+pub fn entry(key: u64, entry: &Entry) {
+    send(Message::Entry {
+        key,
+        entry: entry.clone(),
+    });
+}
+
+pub fn tag(key: u64, tag: &str) {
+    send(Message::Tag {
+        key,
+        tag: tag.to_owned(),
+    });
+}
+
+pub fn close(key: u64, record: &Record) {
+    send(Message::Close {
+        key,
+        closed_at: record.closed_at.unwrap_or_else(Utc::now),
+        duration: record.duration.unwrap_or_default(),
+        reason: record.reason,
+        entries: record.size(),
+        bytes_in: record.bytes_in,
+        bytes_out: record.bytes_out,
+    });
+}
+
+// close the queue and wait for the pending messages to be written, up to the deadline
+pub async fn stop(deadline: Duration) {
+    SENDER.lock().unwrap().take();
+
+    let worker = match WORKER.lock().unwrap().take() {
+        Some(worker) => worker,
+        None => return,
+    };
+
+    let joined = tokio::task::spawn_blocking(move || worker.join());
+    if tokio::time::timeout(deadline, joined).await.is_err() {
+        warn!("some sessions could not be stored before exiting");
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time '{}': {}", time, e))
+}
+
+// read every session of a database
+pub fn load(path: &str) -> Result<Vec<Record>, String> {
+    let conn = Connection::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    let error = |e: rusqlite::Error| format!("could not read {}: {}", path, e);
+
+    let version: i64 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(error)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "{} has schema version {}, newer than the supported {}",
+            path, version, SCHEMA_VERSION
+        ));
+    }
+
+    let mut sessions = conn
+        .prepare(
+            "SELECT id, created_at, hostname, protocol, service, address, port, uid, sensor,
+                    destination_address, destination_port, closed_at, duration, reason,
+                    bytes_in, bytes_out
+             FROM sessions ORDER BY created_at",
+        )
+        .map_err(error)?;
+    let mut entries = conn
+        .prepare(
+            "SELECT e.time, e.type, e.data, c.username, c.password, c.key, m.command,
+                    a.tool, a.url, a.host, a.filename
+             FROM entries e
+             LEFT JOIN credentials c ON c.entry_id = e.id
+             LEFT JOIN commands m ON m.entry_id = e.id
+             LEFT JOIN artifacts a ON a.entry_id = e.id
+             WHERE e.session_id = ? ORDER BY e.id",
+        )
+        .map_err(error)?;
+    let mut tags = conn
+        .prepare("SELECT tag FROM tags WHERE session_id = ? ORDER BY rowid")
+        .map_err(error)?;
+
+    let rows = sessions
+        .query_map([], |row| {
+            let mut record = Record::new(
+                Utc::now(),
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+            );
+            record.id = row.get(7)?;
+            record.sensor = row.get(8)?;
+            record.destination_address = row.get(9)?;
+            record.destination_port = row.get(10)?;
+            record.duration = row.get(12)?;
+            record.reason = row
+                .get::<_, Option<String>>(13)?
+                .and_then(|reason| serde_json::from_value(serde_json::Value::String(reason)).ok());
+            record.bytes_in = row.get::<_, i64>(14)? as u64;
+            record.bytes_out = row.get::<_, i64>(15)? as u64;
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(11)?,
+                record,
+            ))
+        })
+        .map_err(error)?;
+
+    let mut records = vec![];
+    for row in rows {
+        let (id, created_at, closed_at, mut record) = row.map_err(error)?;
+        record.created_at = parse_time(&created_at)?;
+        record.closed_at = closed_at.as_deref().map(parse_time).transpose()?;
+
+        let rows = entries
+            .query_map([id], |row| {
+                let text = |n| -> rusqlite::Result<String> {
+                    Ok(row.get::<_, Option<String>>(n)?.unwrap_or_default())
+                };
+                let data = match row.get::<_, String>(1)?.as_str() {
+                    "auth" => Data::Authentication {
+                        username: text(3)?,
+                        password: row.get(4)?,
+                        key: row.get(5)?,
+                    },
+                    "command" => Data::Command(text(6)?),
+                    "exec" => Data::Exec(text(6)?),
+                    "download" => Data::Download {
+                        tool: text(7)?,
+                        url: text(8)?,
+                        host: text(9)?,
+                        filename: text(10)?,
+                    },
+                    "raw" => Data::Raw(row.get::<_, Option<Vec<u8>>>(2)?.unwrap_or_default()),
+                    "request" => Data::Request(text(2)?),
+                    "output" => Data::Output(text(2)?),
+                    _ => Data::Log(text(2)?),
+                };
+                Ok((row.get::<_, String>(0)?, data))
+            })
+            .map_err(error)?;
+        for row in rows {
+            let (time, data) = row.map_err(error)?;
+            record.entries.push(Entry {
+                time: parse_time(&time)?,
+                data,
+            });
+        }
+
+        let rows = tags
+            .query_map([id], |row| row.get::<_, String>(0))
+            .map_err(error)?;
+        for row in rows {
+            record.tags.push(row.map_err(error)?);
+        }
+
+        records.push(record);
+    }
+
+    Ok(records)
+}